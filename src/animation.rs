@@ -1,8 +1,8 @@
 use std::f32::consts::PI;
 
-use bevy::prelude::*;
+use bevy::{prelude::*, animation::animation_player, transform::TransformSystem};
 use bevy_rapier3d::prelude::{RigidBody, Velocity, GravityScale};
-use crate::{player::{Player, CurrentPlayer}, weapons:: WeaponAsset, shoot::BULLET_VELOCITY};
+use crate::{player::{Player, CurrentPlayer}, weapons:: WeaponAsset, shoot::BULLET_VELOCITY, camera::LookAngles};
 
 pub struct AnimationPlugin;
 
@@ -13,11 +13,24 @@ impl Plugin for AnimationPlugin{
         .add_systems(Update, (
             run_player_animations,
             run_gun_animation,
-            link_animations
-        ));
+            link_animations,
+            tag_look_pose_bones,
+        ))
+        // the look pose is applied on top of the animations
+        .add_systems(PostUpdate, apply_look_pose
+            .after(animation_player)
+            .before(TransformSystem::TransformPropagate)
+        );
     }   
 }
 
+// bones of the character model that follow the pitch of the player's view
+const LOOK_POSE_BONES: [&str; 3] = ["Head", "Left arm", "Right arm"];
+
+/// rotation added to the bone by the look pose during the last frame
+#[derive(Component, Default)]
+struct LookPoseBone(Quat);
+
 #[derive(Component)]
 pub struct AnimationEntityLink(pub Entity);
 #[derive(Resource)]
@@ -129,3 +142,33 @@ fn run_gun_animation(
     }
 }
 
+/// find the head and the arms in the character models
+fn tag_look_pose_bones(
+    mut cmd: Commands,
+    bones_q: Query<(Entity, &Name), Added<Name>>,
+){
+    for (entity, name) in bones_q.iter(){
+        if LOOK_POSE_BONES.contains(&name.as_str()){
+            cmd.entity(entity).insert(LookPoseBone::default());
+        }
+    }
+}
+
+/// bend the head and the arms of the characters so that they aim where the player looks
+fn apply_look_pose(
+    mut bones_q: Query<(Entity, &mut Transform, &mut LookPoseBone)>,
+    look_q: Query<&LookAngles>,
+    parent_query: Query<&Parent>,
+){
+    for (bone, mut transform, mut pose) in bones_q.iter_mut(){
+        let Ok(look) = look_q.get(get_top_parent(bone, &parent_query)) else {continue};
+
+        // if the animation did not write the bone this frame, remove last frame's rotation first
+        if !transform.is_changed(){
+            transform.rotation *= pose.0.inverse();
+        }
+
+        pose.0 = Quat::from_rotation_x(look.pitch);
+        transform.rotation *= pose.0;
+    }
+}
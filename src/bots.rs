@@ -0,0 +1,495 @@
+//! Bots : server side players driven by an AI instead of a client.
+//! A bot has the same components as a connected player, the AI only writes its `PlayerInput`
+//! (see player.rs). It walks the maze along A* paths, patrols, investigates the sounds it hears
+//! and fights the enemies it sees. The clients receive the bots like any other player.
+use bevy::prelude::*;
+use rand::{Rng, seq::SliceRandom};
+use serde::{Serialize, Deserialize};
+use std::{cmp::Reverse, collections::{BinaryHeap, HashMap}, f32::consts::{PI, TAU}, ops::Range};
+
+use crate::{
+    config,
+    player::{Player, PlayerId, PlayerInput, PlayerName, PLAYER_SCALE},
+    team::{Team, smallest_team},
+    camera::{LookAngles, MAX_PITCH, camera_height},
+    weapons::{Weapon, FireMode},
+    world::Maze,
+    sounds::EmitSound,
+    sound_propagation::SoundPropagation,
+    game::Cvars,
+    killfeed::Notice,
+    network::mods::{Lobby, DefaultChannel, ServerMessages, spawn_player},
+    demo::GameServer,
+};
+
+//////////////////////////// EVENTS, COMPONENTS, CONSTS ///////////////////////////////
+
+/// the ids given to the bots, the ids of the clients are much bigger (see client.rs).
+/// A bot is told apart by its `Bot` component, a client with the id of a bot is rejected (see server.rs)
+const BOT_IDS: Range<u64> = 1..1000;
+const BOT_NAMES: [&str; 8] = ["Alpha", "Bravo", "Charlie", "Delta", "Echo", "Foxtrot", "Golf", "Hotel"];
+const BOTS_FILE: &str = "bots.ron";
+
+// a bot sees the players in this angle (radians) in front of it
+const VIEW_ANGLE: f32 = 110. * PI / 180.;
+// how far a bot sees in the dark, and a player with his flashlight on
+const DARK_SIGHT_RANGE: f32 = 10.;
+const FLASHLIGHT_SIGHT_RANGE: f32 = 40.;
+// the players this close are noticed even behind the bot
+const AWARENESS_RANGE: f32 = 2.5;
+// the sounds quieter than this (see sound_propagation.rs) are not heard
+const HEARING_THRESHOLD: f32 = 0.15;
+
+// turn speed (radians per second) of the worst and the best bots
+const MIN_TURN_SPEED: f32 = 3.;
+const MAX_TURN_SPEED: f32 = 12.;
+// aim error (radians) of the worst bots, the best ones aim perfectly
+const MAX_AIM_ERROR: f32 = 0.12;
+// time (in seconds) between two changes of the aim error
+const AIM_ERROR_INTERVAL: f32 = 0.5;
+// the bot shoots when its aim is this close (radians) to the target
+const FIRE_ANGLE: f32 = 0.08;
+// bursts of fire, so the recoil resets
+const BURST_DURATION: f32 = 0.4;
+const BURST_PAUSE: f32 = 0.3;
+// time (in seconds) before a fighting bot changes its strafe direction
+const STRAFE_INTERVAL: f32 = 0.8;
+// a waypoint is reached at this distance (in tiles)
+const WAYPOINT_RADIUS: f32 = 0.3;
+// the path is computed again when the bot makes no progress during this time (in seconds)
+const STUCK_DELAY: f32 = 2.;
+
+/// the bots spawned when the server starts, read from `bots.ron` in the config directory
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct BotConfig{
+    count: usize,
+    skill: f32,
+    reaction_time: f32,
+}
+
+impl Default for BotConfig{
+    fn default() -> Self {
+        let cvars = Cvars::default();
+        Self{ count: 0, skill: cvars.bot_skill, reaction_time: cvars.bot_reaction_time }
+    }
+}
+
+/// add or remove bots, from the server console (see network/console.rs)
+#[derive(Event, Debug, Clone, Copy)]
+pub enum BotCommand{
+    Add(usize),
+    /// remove the last bots added
+    Remove(usize),
+    /// remove the bot with this id
+    Kick(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BotState{
+    /// walk to random places of the maze
+    Patrol,
+    /// walk to where a sound was heard, or an enemy was last seen
+    Investigate(Vec3),
+    Fight(Entity),
+}
+
+#[derive(Component)]
+pub struct Bot{
+    state: BotState,
+    /// the tiles to walk through, the next one first
+    path: Vec<(usize, usize)>,
+    /// time the current target has been seen, the bot shoots after its reaction time
+    seen_for: f32,
+    aim_error: Vec2,
+    aim_timer: f32,
+    /// firing while positive, pausing while negative
+    burst_timer: f32,
+    strafe_left: bool,
+    strafe_timer: f32,
+    /// closest distance to the next waypoint, and the time since it got closer
+    best_distance: f32,
+    stuck_timer: f32,
+}
+
+impl Default for Bot{
+    fn default() -> Self {
+        Self{
+            state: BotState::Patrol,
+            path: vec![],
+            seen_for: 0.,
+            aim_error: Vec2::ZERO,
+            aim_timer: 0.,
+            burst_timer: BURST_DURATION,
+            strafe_left: false,
+            strafe_timer: 0.,
+            best_distance: f32::INFINITY,
+            stuck_timer: 0.,
+        }
+    }
+}
+
+//////////////////////////// ADD / REMOVE ///////////////////////////////
+
+/// the bots of the config file
+pub fn spawn_configured_bots(
+    mut cvars: ResMut<Cvars>,
+    mut bot_commands: EventWriter<BotCommand>,
+){
+    let bot_config: BotConfig = config::load(BOTS_FILE).unwrap_or_default();
+    cvars.bot_skill = bot_config.skill.clamp(0., 1.);
+    cvars.bot_reaction_time = bot_config.reaction_time.max(0.);
+    if bot_config.count > 0{
+        bot_commands.send(BotCommand::Add(bot_config.count));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn handle_bot_commands(
+    mut cmd: Commands,
+    mut bot_commands: EventReader<BotCommand>,
+    mut server: GameServer,
+    mut lobby: ResMut<Lobby>,
+    teams_q: Query<&Team>,
+    names_q: Query<&PlayerName>,
+    bots_q: Query<(), With<Bot>>,
+    assets: Res<AssetServer>,
+    maze: Res<Maze>,
+){
+    for command in bot_commands.iter(){
+        let mut removed = vec![];
+
+        match *command{
+            BotCommand::Add(count) => {
+                // the players spawned in this frame are not in the queries yet
+                let mut teams: Vec<Team> = lobby.players.values().filter_map(|&entity| teams_q.get(entity).ok()).copied().collect();
+
+                for _ in 0..count{
+                    let Some(id) = BOT_IDS.clone().find(|id| !lobby.players.contains_key(id)) else {
+                        eprintln!("ERR: no more bot ids");
+                        break;
+                    };
+                    let name = format!("[BOT] {}", BOT_NAMES[(id - 1) as usize % BOT_NAMES.len()]);
+                    let team = smallest_team(teams.iter());
+                    teams.push(team);
+
+                    let entity = spawn_player(&mut cmd, &assets, &maze, lobby.players.len(), id, name.clone(), team);
+                    cmd.entity(entity).insert(Bot::default());
+                    lobby.players.insert(id, entity);
+                    println!("Bot {} ({}) added.", id, name);
+
+                    let message = bincode::serialize(&ServerMessages::Notice(Notice::Joined { name: name.clone() })).unwrap();
+                    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                    let message = bincode::serialize(&ServerMessages::PlayerConnected { id, name, team }).unwrap();
+                    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                }
+            }
+            BotCommand::Remove(count) => {
+                let mut bots: Vec<u64> = lobby.players.iter()
+                    .filter(|(_, &entity)| bots_q.contains(entity))
+                    .map(|(&id, _)| id)
+                    .collect();
+                bots.sort();
+                removed.extend(bots.iter().rev().take(count));
+            }
+            BotCommand::Kick(id) => {
+                if lobby.players.get(&id).is_some_and(|&entity| bots_q.contains(entity)){
+                    removed.push(id);
+                }
+            }
+        }
+
+        for id in removed{
+            let Some(entity) = lobby.players.remove(&id) else {continue};
+            if let Ok(PlayerName(name)) = names_q.get(entity){
+                println!("Bot {} ({}) removed.", id, name);
+                let message = bincode::serialize(&ServerMessages::Notice(Notice::Left { name: name.clone() })).unwrap();
+                server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+            }
+            cmd.entity(entity).despawn_recursive();
+
+            let message = bincode::serialize(&ServerMessages::PlayerDisconnected { id }).unwrap();
+            server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+        }
+    }
+}
+
+//////////////////////////// PATHFINDING ///////////////////////////////
+
+/// not a bevy system. A* on the tiles of the maze (4-connected).
+/// The path does not contain the start tile, None if the goal cannot be reached
+pub fn find_path(maze: &Maze, start: (usize, usize), goal: (usize, usize)) -> Option<Vec<(usize, usize)>>{
+    // already there
+    if start == goal {return Some(vec![])}
+    let heuristic = |(row, col): (usize, usize)| row.abs_diff(goal.0) + col.abs_diff(goal.1);
+
+    let mut open = BinaryHeap::from([Reverse((heuristic(start), 0, start))]);
+    let mut came_from: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+    let mut costs = HashMap::from([(start, 0)]);
+
+    while let Some(Reverse((_, cost, tile))) = open.pop(){
+        if tile == goal{
+            let mut path = vec![goal];
+            let mut current = goal;
+            while let Some(&previous) = came_from.get(&current){
+                if previous == start {break}
+                path.push(previous);
+                current = previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+        // an outdated entry of the heap
+        if costs.get(&tile).is_some_and(|&best| best < cost) {continue}
+
+        for (d_row, d_col) in [(-1, 0), (1, 0), (0, -1), (0, 1)]{
+            let (row, col) = (tile.0 as i32 + d_row, tile.1 as i32 + d_col);
+            if maze.is_wall(row, col) {continue}
+
+            let next = (row as usize, col as usize);
+            let next_cost = cost + 1;
+            if costs.get(&next).is_some_and(|&best| best <= next_cost) {continue}
+
+            costs.insert(next, next_cost);
+            came_from.insert(next, tile);
+            open.push(Reverse((next_cost + heuristic(next), next_cost, next)));
+        }
+    }
+    None
+}
+
+//////////////////////////// AI ///////////////////////////////
+
+/// what a bot knows about another player
+struct Target{
+    entity: Entity,
+    /// where to aim
+    position: Vec3,
+    team: Team,
+    alive: bool,
+    flashlight_on: bool,
+}
+
+/// not a bevy system. The angle (radians) to turn from `from` to `to`, between -PI and PI
+fn angle_difference(from: f32, to: f32) -> f32{
+    (to - from + PI).rem_euclid(TAU) - PI
+}
+
+/// not a bevy system. The yaw and the pitch to look from `from` towards `to` (see LookAngles)
+fn look_towards(from: Vec3, to: Vec3) -> Vec2{
+    let direction = to - from;
+    let horizontal = Vec2::new(direction.x, direction.z).length();
+    Vec2::new(direction.x.atan2(direction.z), direction.y.atan2(horizontal).clamp(-MAX_PITCH, MAX_PITCH))
+}
+
+/// the bots, with what their AI reads and its input
+type BotQuery<'w, 's> = Query<'w, 's, (Entity, &'static mut Bot, &'static mut PlayerInput, &'static Transform, &'static LookAngles, &'static Player, &'static Weapon, &'static PlayerId, &'static Team)>;
+
+/// choose what each bot does, and write its input
+#[allow(clippy::too_many_arguments)]
+pub fn update_bots(
+    mut bot_q: BotQuery,
+    players_q: Query<(Entity, &Transform, &Player, &Team)>,
+    mut sounds: EventReader<EmitSound>,
+    mut propagation: SoundPropagation,
+    maze: Res<Maze>,
+    cvars: Res<Cvars>,
+    time: Res<Time>,
+){
+    let delta = time.delta_seconds();
+    let heard: Vec<EmitSound> = sounds.iter().copied().collect();
+    let targets: Vec<Target> = players_q.iter().map(|(entity, transform, player, team)| Target{
+        entity,
+        position: transform.translation + Vec3::Y * camera_height(player.is_crouching) * PLAYER_SCALE * 0.75,
+        team: *team,
+        alive: player.hp > 0.,
+        flashlight_on: player.flashlight_on,
+    }).collect();
+    let mut rng = rand::thread_rng();
+
+    for (entity, mut bot, mut input, transform, look, player, weapon, player_id, team) in bot_q.iter_mut(){
+        // the flashlight and the other toggles are kept
+        *input = PlayerInput{ toggle_flashlight: input.toggle_flashlight, ..default() };
+        if player.hp <= 0.{
+            *bot = Bot::default();
+            continue;
+        }
+
+        let eyes = transform.translation + Vec3::Y * camera_height(player.is_crouching) * PLAYER_SCALE;
+
+        ////// perception
+        let visible_enemy = targets.iter()
+            .filter(|target| target.entity != entity && target.alive)
+            .filter(|target| cvars.can_damage(Some(*team), Some(target.team)))
+            .filter(|target|{
+                let distance = eyes.distance(target.position);
+                let range = if target.flashlight_on {FLASHLIGHT_SIGHT_RANGE} else {DARK_SIGHT_RANGE};
+                let angle = angle_difference(look.yaw, look_towards(eyes, target.position).x).abs();
+                distance < AWARENESS_RANGE || (distance < range && angle < VIEW_ANGLE / 2.)
+            })
+            .filter(|target| maze.line_of_sight(eyes, target.position))
+            .min_by(|a, b| eyes.distance(a.position).total_cmp(&eyes.distance(b.position)));
+
+        match (visible_enemy, bot.state){
+            (Some(enemy), BotState::Fight(current)) if current == enemy.entity => bot.seen_for += delta,
+            (Some(enemy), _) => {
+                bot.state = BotState::Fight(enemy.entity);
+                bot.seen_for = 0.;
+                bot.path.clear();
+            }
+            // lost sight of the target, go where it was last seen
+            (None, BotState::Fight(lost)) => {
+                let last_seen = targets.iter().find(|target| target.entity == lost && target.alive);
+                bot.state = last_seen.map_or(BotState::Patrol, |target| BotState::Investigate(target.position));
+                bot.path.clear();
+            }
+            (None, _) => (),
+        }
+
+        // the sounds of the others (footsteps, gunshots ...) are investigated
+        if !matches!(bot.state, BotState::Fight(_)){
+            let loudest = heard.iter()
+                .filter(|sound| sound.emitter != Some(player_id.id))
+                .filter_map(|sound| Some((sound.position, propagation.propagate(sound.position, eyes, look.yaw)?.volume)))
+                .filter(|(_, volume)| *volume > HEARING_THRESHOLD)
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            if let Some((position, _)) = loudest{
+                let new_goal = maze.world_to_tile(position);
+                let current_goal = match bot.state{
+                    BotState::Investigate(goal) => maze.world_to_tile(goal),
+                    _ => None,
+                };
+                if new_goal != current_goal{
+                    bot.state = BotState::Investigate(position);
+                    bot.path.clear();
+                }
+            }
+        }
+
+        ////// skill
+        let skill = cvars.bot_skill;
+        bot.aim_timer -= delta;
+        if bot.aim_timer <= 0.{
+            bot.aim_timer = AIM_ERROR_INTERVAL;
+            let error = MAX_AIM_ERROR * (1. - skill);
+            bot.aim_error = Vec2::new(rng.gen_range(-1. ..=1.), rng.gen_range(-1. ..=1.)) * error;
+        }
+        let max_turn = (MIN_TURN_SPEED + (MAX_TURN_SPEED - MIN_TURN_SPEED) * skill) * delta;
+        let turn = |from: f32, to: f32| angle_difference(from, to).clamp(-max_turn, max_turn);
+
+        ////// action
+        if let (BotState::Fight(_), Some(enemy)) = (bot.state, visible_enemy){
+            let aim = look_towards(eyes, enemy.position) + bot.aim_error;
+            input.mouse = Vec2::new(turn(look.yaw, aim.x), turn(look.pitch, aim.y));
+
+            // shoot in bursts once the bot had the time to react, and its aim is close enough
+            bot.burst_timer -= delta;
+            if bot.burst_timer <= -BURST_PAUSE{
+                bot.burst_timer = BURST_DURATION;
+            }
+            let aimed = angle_difference(look.yaw, aim.x).abs() < FIRE_ANGLE
+                && (aim.y - look.pitch).abs() < FIRE_ANGLE;
+            if aimed && bot.seen_for >= cvars.bot_reaction_time && bot.burst_timer > 0.{
+                input.left_click = match weapon.fire_mode{
+                    FireMode::Auto => true,
+                    // the trigger is pulled again for each shot
+                    FireMode::SemiAuto | FireMode::Burst => !player.is_shooting,
+                };
+                input.left_click_just_pressed = input.left_click;
+            }
+
+            // strafe to be harder to hit
+            bot.strafe_timer -= delta;
+            if bot.strafe_timer <= 0.{
+                bot.strafe_timer = STRAFE_INTERVAL * rng.gen_range(0.5..1.5);
+                bot.strafe_left = rng.gen();
+            }
+            input.left = bot.strafe_left;
+            input.right = !bot.strafe_left;
+        }else{
+            // walk along the path, to a random place or to what the bot heard
+            let Some(tile) = maze.world_to_tile(transform.translation) else {continue};
+            if bot.path.is_empty(){
+                let goal = match bot.state{
+                    BotState::Investigate(position) => maze.world_to_tile(position),
+                    _ => maze.free_tiles().choose(&mut rng).copied(),
+                };
+                bot.path = goal.and_then(|goal| find_path(&maze, tile, goal)).unwrap_or_default();
+                bot.best_distance = f32::INFINITY;
+                bot.stuck_timer = 0.;
+                // arrived, or nowhere to go
+                if bot.path.is_empty(){
+                    bot.state = BotState::Patrol;
+                }
+            }
+
+            if let Some(&(row, col)) = bot.path.first(){
+                let waypoint = maze.tile_to_world(row, col);
+                let distance = Vec2::new(waypoint.x - transform.translation.x, waypoint.z - transform.translation.z).length();
+
+                if distance < WAYPOINT_RADIUS * maze.tile_size{
+                    bot.path.remove(0);
+                    bot.best_distance = f32::INFINITY;
+                    bot.stuck_timer = 0.;
+                }else if distance < bot.best_distance{
+                    bot.best_distance = distance;
+                    bot.stuck_timer = 0.;
+                }else{
+                    bot.stuck_timer += delta;
+                    if bot.stuck_timer > STUCK_DELAY{
+                        bot.path.clear();
+                    }
+                }
+
+                let direction = look_towards(transform.translation, waypoint);
+                input.mouse = Vec2::new(turn(look.yaw, direction.x), turn(look.pitch, 0.));
+                // walk forward once facing the waypoint
+                input.up = angle_difference(look.yaw, direction.x).abs() < PI / 3.;
+                input.sprint = matches!(bot.state, BotState::Investigate(_));
+            }
+        }
+
+        // reload when empty, or between two fights
+        let half_magazine = weapon.ammos / 2;
+        input.reload = player.reserve_ammos > 0 && !player.is_reloading
+            && (player.ammos == 0 || (player.ammos < half_magazine && visible_enemy.is_none()));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// 1 is a wall, 0 a path
+    fn maze(rows: &[&str]) -> Maze{
+        let map = rows.iter().map(|row| row.chars().map(|c| c.to_digit(10).unwrap() as i32).collect()).collect();
+        Maze::new("test", map, vec![])
+    }
+
+    #[test]
+    fn shortest_path_around_the_walls(){
+        let maze = maze(&[
+            "11111",
+            "10001",
+            "11101",
+            "10001",
+            "11111",
+        ]);
+        let path = find_path(&maze, (1, 1), (3, 1)).unwrap();
+        assert_eq!(path, vec![(1, 2), (1, 3), (2, 3), (3, 3), (3, 2), (3, 1)]);
+        assert_eq!(find_path(&maze, (1, 1), (1, 2)), Some(vec![(1, 2)]));
+        assert_eq!(find_path(&maze, (1, 1), (1, 1)), Some(vec![]));
+    }
+
+    #[test]
+    fn unreachable_goal(){
+        let maze = maze(&[
+            "11111",
+            "10101",
+            "11111",
+        ]);
+        assert_eq!(find_path(&maze, (1, 1), (1, 3)), None);
+        // the goal is a wall
+        assert_eq!(find_path(&maze, (1, 1), (1, 2)), None);
+    }
+}
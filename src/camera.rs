@@ -14,8 +14,35 @@ impl Plugin for CameraPlugin {
     }
 }
 
-#[derive(Component)]
-pub struct CameraVerticalMotion(pub f32);
+// height of the camera relative to the player (before the player's scale is applied)
+pub const CAMERA_HEIGHT: f32 = 0.65;
+pub const CROUCHED_CAMERA_HEIGHT: f32 = 0.45;
+// how fast the camera goes up/down when the player (un)crouches
+pub const CROUCH_TRANSITION_SPEED: f32 = 12.;
+
+// the player cannot look higher/lower than 89 degrees
+pub const MAX_PITCH: f32 = 89. * PI / 180.;
+
+/// absolute orientation of the player's view, replicated to the clients.
+/// The yaw rotates the whole player, the pitch (positive when looking up) only rotates
+/// the camera, the flashlight and the head/arms of the character.
+#[derive(Component, Default, Clone, Copy)]
+pub struct LookAngles{
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl LookAngles{
+    pub fn body_rotation(&self) -> Quat{
+        Quat::from_rotation_y(self.yaw)
+    }
+
+    /// rotation of the camera and the flashlight relative to the player
+    /// (they are turned around to look in the same direction as the character model)
+    pub fn view_rotation(&self) -> Quat{
+        Quat::from_rotation_x(-self.pitch) * Quat::from_rotation_y(PI)
+    }
+}
 
 #[derive(Component)]
 struct ServerCamera;
@@ -41,8 +68,8 @@ pub fn spawn_server_camera(mut commands: Commands) {
 
 /// not a bevy system. Get a 3D camera bundle
 pub fn get_camera() -> impl Bundle{
-    let mut camera_transform = Transform::from_xyz(0.,0.65,0.);
-    camera_transform.rotate_y(PI);
+    let camera_transform = Transform::from_xyz(0.,CAMERA_HEIGHT,0.)
+        .with_rotation(LookAngles::default().view_rotation());
 
     (Camera3dBundle{
         transform: camera_transform,
@@ -55,4 +82,6 @@ pub fn get_camera() -> impl Bundle{
     Name::new("Camera"))
 }
 
-
+pub fn camera_height(is_crouching: bool) -> f32{
+    if is_crouching {CROUCHED_CAMERA_HEIGHT} else {CAMERA_HEIGHT}
+}
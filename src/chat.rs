@@ -0,0 +1,452 @@
+//! In-game text chat.
+//! The client types in an input box (the player's input is suspended meanwhile) and sends the message
+//! to the server on the `ReliableUnordered` channel (the `ReliableOrdered` one carries the player input).
+//! The server limits the rate and the length of the messages, runs them through the `ChatFilters`
+//! and broadcasts them to everyone, or to the sender's team only.
+use bevy::{prelude::*, input::mouse::{MouseWheel, MouseScrollUnit}, utils::HashMap};
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+
+use crate::{
+    config,
+    player::{PlayerInput, PlayerName},
+    team::Team,
+    controls::{ActionInput, Action},
+    netgraph::NetStats,
+    game::{AppState, toggle_game_menu},
+    network::mods::{Lobby, Spectators, RenetClient, DefaultChannel, ServerMessages},
+    demo::GameServer,
+};
+
+//////////////////////////// PLUGIN, COMPONENTS, CONSTS ///////////////////////////////
+
+/// client side of the chat, the server side (`receive_chat_messages`) is added in network/mods.rs
+pub struct ChatPlugin;
+impl Plugin for ChatPlugin{
+
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChatInput>();
+        app.init_resource::<ChatHistory>();
+        app.add_event::<ChatBroadcast>();
+        app.add_systems(OnExit(AppState::MainMenu), spawn_chat);
+        app.add_systems(OnEnter(AppState::MainMenu), despawn_chat);
+        app.add_systems(Update,(
+            // after the pause menu, so the escape key closing the chat does not open the menu
+            chat_input.after(toggle_game_menu),
+            receive_chat,
+            scroll_chat,
+            update_chat_panel.after(chat_input).after(receive_chat).after(scroll_chat),
+        ));
+    }
+}
+
+// the longer messages are cut
+pub const MAX_CHAT_LENGTH: usize = 120;
+// a player cannot send more than this number of messages in the rate window (in seconds)
+const CHAT_RATE_LIMIT: usize = 5;
+const CHAT_RATE_WINDOW: f32 = 10.;
+const CHAT_FILTER_FILE: &str = "chat_filter.ron";
+
+// the messages received are kept in the history, only the last lines are shown
+const MAX_CHAT_HISTORY: usize = 100;
+const VISIBLE_CHAT_LINES: usize = 8;
+// when the chat is closed, the lines disappear after this time (in seconds)
+const CHAT_LINE_DURATION: f32 = 10.;
+const CHAT_FONT_SIZE: f32 = 18.;
+const SERVER_NAME_COLOR: Color = Color::rgb(1., 0.8, 0.2);
+
+/// a message sent by a client to the server
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMessage{
+    pub text: String,
+    pub team_only: bool,
+}
+
+/// a message broadcast by the server, `team` is None for the spectators and the server
+#[derive(Event, Debug, Clone, Serialize, Deserialize)]
+pub struct ChatBroadcast{
+    /// None for the messages of the server itself
+    pub sender: Option<u64>,
+    pub name: String,
+    pub team: Option<Team>,
+    pub text: String,
+    pub team_only: bool,
+    /// time of the server (in seconds) when the message was sent
+    pub timestamp: f32,
+}
+
+/// the message being typed (client side)
+#[derive(Resource, Default)]
+pub struct ChatInput{
+    pub open: bool,
+    team_only: bool,
+    text: String,
+}
+
+/// the messages received (client side)
+#[derive(Resource, Default)]
+struct ChatHistory{
+    lines: VecDeque<ChatLine>,
+    /// number of lines scrolled up from the last one
+    scroll: usize,
+}
+
+struct ChatLine{
+    message: ChatBroadcast,
+    /// time of the client when the message was received
+    received_at: f32,
+}
+
+/// checks a message before it is broadcast (server side) : returns the text to send (censored ...),
+/// or None to drop the message
+pub type ChatFilter = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// the filters run on every message, in order (server side)
+#[derive(Resource)]
+pub struct ChatFilters(pub Vec<ChatFilter>);
+
+impl Default for ChatFilters{
+    fn default() -> Self {
+        // the words listed in the config file are censored
+        let banned_words = config::load::<Vec<String>>(CHAT_FILTER_FILE).unwrap_or_default();
+        Self(vec![Box::new(move |text: &str| Some(censor(text, &banned_words)))])
+    }
+}
+
+impl ChatFilters{
+    /// not a bevy system. None if a filter dropped the message
+    pub fn apply(&self, text: &str) -> Option<String>{
+        self.0.iter().try_fold(text.to_string(), |text, filter| filter(&text))
+    }
+}
+
+/// not a bevy system. Records a message sent at `now` in the send times of a client,
+/// false if the client already sent too many messages in the rate window
+fn within_rate_limit(sent: &mut VecDeque<f32>, now: f32) -> bool{
+    while sent.front().is_some_and(|&time| time < now - CHAT_RATE_WINDOW){
+        sent.pop_front();
+    }
+    if sent.len() >= CHAT_RATE_LIMIT {return false}
+    // every message counts, even the ones dropped by the filters
+    sent.push_back(now);
+    true
+}
+
+/// not a bevy system. Replace the banned words (case insensitive) with stars
+fn censor(text: &str, banned_words: &[String]) -> String{
+    text.split(' ')
+        .map(|word| {
+            let banned = banned_words.iter().any(|banned| banned.eq_ignore_ascii_case(word.trim_matches(|c: char| !c.is_alphanumeric())));
+            if banned {"*".repeat(word.chars().count())} else {word.to_string()}
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[derive(Component)]
+struct ChatPanel;
+
+#[derive(Component)]
+struct ChatLines;
+
+#[derive(Component)]
+struct ChatInputText;
+
+/// run condition, the player's input is suspended while typing
+pub fn chat_closed(chat: Res<ChatInput>) -> bool{
+    !chat.open
+}
+
+//////////////////////////// SERVER ///////////////////////////////
+
+/// receive the messages of the clients, check them and broadcast them
+pub fn receive_chat_messages(
+    mut server: GameServer,
+    lobby: Res<Lobby>,
+    spectators: Res<Spectators>,
+    names_q: Query<(&PlayerName, &Team)>,
+    filters: Res<ChatFilters>,
+    time: Res<Time>,
+    // when the last messages of each client were sent
+    mut sent_times: Local<HashMap<u64, VecDeque<f32>>>,
+){
+    let now = time.elapsed_seconds();
+
+    for client_id in server.clients_id().into_iter(){
+        while let Some(message) = server.receive_message(client_id, DefaultChannel::ReliableUnordered){
+            let Ok(message) = bincode::deserialize::<ChatMessage>(&message) else {
+                eprintln!("ERR: invalid chat message from {}", client_id);
+                continue;
+            };
+
+            if !within_rate_limit(sent_times.entry(client_id).or_default(), now){
+                let warning = server_chat_message("you are sending messages too fast".to_string(), now);
+                server.send_message(client_id, DefaultChannel::ReliableOrdered, warning);
+                continue;
+            }
+
+            // length limit and filters
+            let text: String = message.text.trim().chars().take(MAX_CHAT_LENGTH).collect();
+            if text.is_empty() {continue}
+            let Some(text) = filters.apply(&text) else {continue};
+
+            let (name, team) = match lobby.players.get(&client_id).and_then(|&entity| names_q.get(entity).ok()){
+                Some((PlayerName(name), team)) => (name.clone(), Some(*team)),
+                None => (spectators.names.get(&client_id).cloned().unwrap_or_else(|| client_id.to_string()), None),
+            };
+            println!("[chat] {}: {}", name, text);
+
+            let broadcast = bincode::serialize(&ServerMessages::Chat(ChatBroadcast{
+                sender: Some(client_id), name, team, text, team_only: message.team_only, timestamp: now
+            })).unwrap();
+
+            if !message.team_only{
+                server.broadcast_message(DefaultChannel::ReliableOrdered, broadcast);
+                continue;
+            }
+
+            // team messages go to the teammates, or to the other spectators
+            let recipients: Vec<u64> = match team{
+                Some(team) => lobby.players.iter()
+                    .filter(|(_, &entity)| names_q.get(entity).is_ok_and(|(_, player_team)| *player_team == team))
+                    .map(|(&id, _)| id)
+                    .collect(),
+                None => spectators.names.keys().copied().collect(),
+            };
+            for recipient in recipients{
+                server.send_message(recipient, DefaultChannel::ReliableOrdered, broadcast.clone());
+            }
+        }
+    }
+
+    // forget the disconnected clients
+    sent_times.retain(|client_id, _| server.clients_id().contains(client_id));
+}
+
+/// not a bevy system. A message from the server itself
+pub fn server_chat_message(text: String, timestamp: f32) -> Vec<u8>{
+    bincode::serialize(&ServerMessages::Chat(ChatBroadcast{
+        sender: None, name: "server".to_string(), team: None, text, team_only: false, timestamp
+    })).unwrap()
+}
+
+//////////////////////////// CLIENT ///////////////////////////////
+
+fn spawn_chat(mut cmd: Commands){
+    cmd.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.),
+                bottom: Val::Percent(15.),
+                width: Val::Px(450.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.),
+                ..default()
+            },
+            ..default()
+        },
+        ChatPanel,
+        Name::new("Chat")
+    )).with_children(|panel|{
+        panel.spawn((TextBundle::default(), ChatLines));
+        panel.spawn((
+            TextBundle::from_section("", TextStyle{ font_size: CHAT_FONT_SIZE, ..default() }),
+            ChatInputText,
+        ));
+    });
+}
+
+fn despawn_chat(
+    mut cmd: Commands,
+    panel_q: Query<Entity, With<ChatPanel>>,
+    mut chat: ResMut<ChatInput>,
+    mut history: ResMut<ChatHistory>,
+){
+    for entity in panel_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+    *chat = ChatInput::default();
+    *history = ChatHistory::default();
+}
+
+/// open the chat, type the message and send it
+#[allow(clippy::too_many_arguments)]
+fn chat_input(
+    actions: ActionInput,
+    keys: Res<Input<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>,
+    mut chat: ResMut<ChatInput>,
+    mut player_input: ResMut<PlayerInput>,
+    client: Option<ResMut<RenetClient>>,
+    mut stats: ResMut<NetStats>,
+    state: Res<State<AppState>>,
+){
+    let Some(mut client) = client else {return};
+
+    if !chat.open{
+        characters.clear();
+        if !matches!(state.get(), AppState::InGame | AppState::DeathScreen | AppState::Spectating) {return}
+
+        let team_only = actions.just_pressed(Action::TeamChat);
+        if !actions.just_pressed(Action::Chat) && !team_only {return}
+
+        *chat = ChatInput{ open: true, team_only, text: String::new() };
+        // the player stops moving and shooting while typing, the toggles are kept
+        *player_input = PlayerInput{
+            toggle_flashlight: player_input.toggle_flashlight,
+            show_map: player_input.show_map,
+            ..default()
+        };
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Escape){
+        chat.open = false;
+        return;
+    }
+    if keys.just_pressed(KeyCode::Back){
+        chat.text.pop();
+    }
+    for character in characters.iter(){
+        if character.char.is_control() || chat.text.chars().count() >= MAX_CHAT_LENGTH {continue}
+        chat.text.push(character.char);
+    }
+
+    if keys.just_pressed(KeyCode::Return){
+        chat.open = false;
+        if chat.text.trim().is_empty() {return}
+
+        let message = ChatMessage{ text: chat.text.clone(), team_only: chat.team_only };
+        let message = bincode::serialize(&message).unwrap();
+        stats.record_sent(DefaultChannel::ReliableUnordered, message.len());
+        client.send_message(DefaultChannel::ReliableUnordered, message);
+    }
+}
+
+fn receive_chat(
+    mut messages: EventReader<ChatBroadcast>,
+    mut history: ResMut<ChatHistory>,
+    time: Res<Time>,
+){
+    for message in messages.iter(){
+        history.lines.push_back(ChatLine{ message: message.clone(), received_at: time.elapsed_seconds() });
+        if history.lines.len() > MAX_CHAT_HISTORY{
+            history.lines.pop_front();
+        }
+        // a new message brings the chat back to the bottom
+        history.scroll = 0;
+    }
+}
+
+/// the mouse wheel scrolls the history while the chat is open
+fn scroll_chat(
+    mut wheel: EventReader<MouseWheel>,
+    chat: Res<ChatInput>,
+    mut history: ResMut<ChatHistory>,
+){
+    let lines: f32 = wheel.iter().map(|event| match event.unit{
+        MouseScrollUnit::Line => event.y,
+        MouseScrollUnit::Pixel => event.y / CHAT_FONT_SIZE,
+    }).sum();
+    if !chat.open || lines == 0. {return}
+
+    let max_scroll = history.lines.len().saturating_sub(VISIBLE_CHAT_LINES);
+    let scroll = (history.scroll as f32 + lines).round().clamp(0., max_scroll as f32);
+    if scroll as usize != history.scroll{
+        history.scroll = scroll as usize;
+    }
+}
+
+/// not a bevy system. "mm:ss" of the server's clock
+fn format_timestamp(timestamp: f32) -> String{
+    let seconds = timestamp as u32;
+    format!("{:02}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn update_chat_panel(
+    mut lines_q: Query<&mut Text, (With<ChatLines>, Without<ChatInputText>)>,
+    mut input_q: Query<&mut Text, With<ChatInputText>>,
+    chat: Res<ChatInput>,
+    history: Res<ChatHistory>,
+    time: Res<Time>,
+    // the first line shown and the number of lines, the text is only rebuilt when they change
+    mut displayed: Local<(usize, usize)>,
+){
+    let Ok(mut lines_text) = lines_q.get_single_mut() else {return};
+    let Ok(mut input_text) = input_q.get_single_mut() else {return};
+
+    if chat.is_changed(){
+        input_text.sections[0].value = match (chat.open, chat.team_only){
+            (false, _) => String::new(),
+            (true, true) => format!("[team] > {}_", chat.text),
+            (true, false) => format!("[all] > {}_", chat.text),
+        };
+    }
+
+    // the last lines (scrolled up), the old ones are hidden when the chat is closed
+    let end = history.lines.len() - history.scroll;
+    let mut start = end.saturating_sub(VISIBLE_CHAT_LINES);
+    if !chat.open{
+        let now = time.elapsed_seconds();
+        while start < end && now - history.lines[start].received_at > CHAT_LINE_DURATION{
+            start += 1;
+        }
+    }
+
+    if *displayed == (start, end - start) && !history.is_changed() {return}
+    *displayed = (start, end - start);
+
+    let style = |color: Color| TextStyle{ font_size: CHAT_FONT_SIZE, color, ..default() };
+    lines_text.sections = history.lines.range(start..end)
+        .flat_map(|line| {
+            let message = &line.message;
+            let name_color = match (message.sender, message.team){
+                (None, _) => SERVER_NAME_COLOR,
+                (Some(_), Some(team)) => team.color(),
+                (Some(_), None) => Color::WHITE,
+            };
+            let channel = if message.team_only {" [team]"} else {""};
+            [
+                TextSection::new(format!("[{}]{} ", format_timestamp(message.timestamp), channel), style(Color::GRAY)),
+                TextSection::new(format!("{}: ", message.name), style(name_color)),
+                TextSection::new(format!("{}\n", message.text), style(Color::WHITE)),
+            ]
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn censor_banned_words(){
+        let banned = vec!["noob".to_string(), "camper".to_string()];
+        assert_eq!(censor("you NOOB, stop camping", &banned), "you ***** stop camping");
+        assert_eq!(censor("camper!", &banned), "*******");
+        assert_eq!(censor("no banned word here", &banned), "no banned word here");
+    }
+
+    #[test]
+    fn filters_run_in_order(){
+        let filters = ChatFilters(vec![
+            Box::new(|text: &str| Some(text.to_uppercase())),
+            Box::new(|text: &str| if text.contains("SPAM") {None} else {Some(format!("{}!", text))}),
+        ]);
+        assert_eq!(filters.apply("hello"), Some("HELLO!".to_string()));
+        assert_eq!(filters.apply("buy spam"), None);
+    }
+
+    #[test]
+    fn rate_limit(){
+        let mut sent = VecDeque::new();
+        for i in 0..CHAT_RATE_LIMIT{
+            assert!(within_rate_limit(&mut sent, i as f32 * 0.1));
+        }
+        assert!(!within_rate_limit(&mut sent, 1.));
+        // the first messages leave the rate window
+        assert!(within_rate_limit(&mut sent, CHAT_RATE_WINDOW + 0.05));
+        assert!(!within_rate_limit(&mut sent, CHAT_RATE_WINDOW + 0.06));
+    }
+}
@@ -0,0 +1,48 @@
+//! Read/write the user's config files (key bindings, settings ...).
+//! The files are written in RON in the user's config directory :
+//! - linux: `$XDG_CONFIG_HOME/multiplayer-fps` or `~/.config/multiplayer-fps`
+//! - windows: `%APPDATA%/multiplayer-fps`
+use std::{env, fs, path::PathBuf};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+const APP_DIRECTORY: &str = "multiplayer-fps";
+
+pub fn config_dir() -> PathBuf{
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|| PathBuf::from("."));
+
+    base.join(APP_DIRECTORY)
+}
+
+/// returns None if the file does not exist or cannot be parsed
+pub fn load<T: DeserializeOwned>(file_name: &str) -> Option<T>{
+    let path = config_dir().join(file_name);
+    let content = fs::read_to_string(&path).ok()?;
+
+    match ron::from_str(&content){
+        Ok(value) => Some(value),
+        Err(e) => {
+            eprintln!("ERR: cannot parse {}, using the default values: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+pub fn save<T: Serialize>(file_name: &str, value: &T){
+    let dir = config_dir();
+    let content = match ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default()){
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("ERR: cannot serialize {}: {}", file_name, e);
+            return;
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(&dir).and_then(|_| fs::write(dir.join(file_name), content)){
+        eprintln!("ERR: cannot save {}: {}", file_name, e);
+    }
+}
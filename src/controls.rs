@@ -0,0 +1,362 @@
+//! Action mapping : the game never reads raw keys, it asks if an `Action` is pressed.
+//! The bindings can be changed in the "Controls" tab of the settings (main menu or pause menu)
+//! and are saved in the user's config file (see config.rs).
+use bevy::{prelude::*, ecs::system::SystemParam};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use crate::{config, menu::{row, button_bundle, menu_text, BUTTON_COLOR, HOVERED_BUTTON_COLOR}};
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin{
+    fn build(&self, app: &mut App) {
+        app
+        .insert_resource(Controls::load())
+        .init_resource::<Rebinding>()
+
+        .add_systems(Update, (
+            // must run before the buttons, or the click on a binding would be caught as the new binding
+            rebind_listener.before(controls_menu_buttons),
+            controls_menu_buttons,
+            update_controls_menu.after(controls_menu_buttons),
+        ));
+    }
+}
+
+//////////////////////////// CONSTS, RESOURCES ///////////////////////////////
+
+const CONTROLS_FILE: &str = "controls.ron";
+
+/// angle (in radians) of one mouse dot at sensitivity 1
+pub const MOUSE_RADIANS_PER_DOT: f32 = 0.0022;
+
+const WAITING_BUTTON_COLOR: Color = Color::rgb(0.6, 0.4, 0.);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action{
+    Forward,
+    Backward,
+    StrafeLeft,
+    StrafeRight,
+    Walk,
+    Sprint,
+    Jump,
+    Crouch,
+    Fire,
+    Reload,
+    ToggleFlashlight,
+    Mute,
+    ToggleMap,
+    Spectate,
+    Chat,
+    TeamChat,
+    NetGraph,
+    // the demos (see demo.rs)
+    ReplayPause,
+    ReplaySeekBack,
+    ReplaySeekForward,
+    ReplayFaster,
+    ReplaySlower,
+    ReplayRestart,
+}
+
+impl Action{
+    pub const ALL: [Action; 23] = [
+        Action::Forward,
+        Action::Backward,
+        Action::StrafeLeft,
+        Action::StrafeRight,
+        Action::Walk,
+        Action::Sprint,
+        Action::Jump,
+        Action::Crouch,
+        Action::Fire,
+        Action::Reload,
+        Action::ToggleFlashlight,
+        Action::Mute,
+        Action::ToggleMap,
+        Action::Spectate,
+        Action::Chat,
+        Action::TeamChat,
+        Action::NetGraph,
+        Action::ReplayPause,
+        Action::ReplaySeekBack,
+        Action::ReplaySeekForward,
+        Action::ReplayFaster,
+        Action::ReplaySlower,
+        Action::ReplayRestart,
+    ];
+
+    fn label(&self) -> &'static str{
+        match self{
+            Action::Forward => "Forward",
+            Action::Backward => "Backward",
+            Action::StrafeLeft => "Strafe left",
+            Action::StrafeRight => "Strafe right",
+            Action::Walk => "Walk",
+            Action::Sprint => "Sprint",
+            Action::Jump => "Jump",
+            Action::Crouch => "Crouch",
+            Action::Fire => "Fire",
+            Action::Reload => "Reload",
+            Action::ToggleFlashlight => "Flashlight",
+            Action::Mute => "Mute",
+            Action::ToggleMap => "Map",
+            Action::Spectate => "Spectate / camera mode",
+            Action::Chat => "Chat",
+            Action::TeamChat => "Team chat",
+            Action::NetGraph => "Net graph",
+            Action::ReplayPause => "Replay pause",
+            Action::ReplaySeekBack => "Replay back",
+            Action::ReplaySeekForward => "Replay forward",
+            Action::ReplayFaster => "Replay faster",
+            Action::ReplaySlower => "Replay slower",
+            Action::ReplayRestart => "Replay restart",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Binding{
+    Key(KeyCode),
+    Mouse(MouseButton),
+}
+
+impl Binding{
+    fn label(&self) -> String{
+        match self{
+            Binding::Key(key) => format!("{:?}", key),
+            Binding::Mouse(button) => format!("Mouse {:?}", button),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum KeyboardLayout{
+    Qwerty,
+    Azerty,
+}
+
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+pub struct Controls{
+    pub layout: KeyboardLayout,
+    pub bindings: HashMap<Action, Binding>,
+}
+
+impl Default for Controls{
+    fn default() -> Self {
+        Self::preset(KeyboardLayout::Azerty)
+    }
+}
+
+impl Controls{
+    pub fn preset(layout: KeyboardLayout) -> Self{
+        let (forward, left) = match layout{
+            KeyboardLayout::Qwerty => (KeyCode::W, KeyCode::A),
+            KeyboardLayout::Azerty => (KeyCode::Z, KeyCode::Q),
+        };
+
+        let bindings = HashMap::from([
+            (Action::Forward, Binding::Key(forward)),
+            (Action::Backward, Binding::Key(KeyCode::S)),
+            (Action::StrafeLeft, Binding::Key(left)),
+            (Action::StrafeRight, Binding::Key(KeyCode::D)),
+            (Action::Walk, Binding::Key(KeyCode::ShiftLeft)),
+            (Action::Sprint, Binding::Key(KeyCode::AltLeft)),
+            (Action::Jump, Binding::Key(KeyCode::Space)),
+            (Action::Crouch, Binding::Key(KeyCode::ControlLeft)),
+            (Action::Fire, Binding::Mouse(MouseButton::Left)),
+            (Action::Reload, Binding::Key(KeyCode::R)),
+            (Action::ToggleFlashlight, Binding::Key(KeyCode::F)),
+            (Action::Mute, Binding::Key(KeyCode::M)),
+            (Action::ToggleMap, Binding::Key(KeyCode::Tab)),
+            (Action::Spectate, Binding::Key(KeyCode::V)),
+            (Action::Chat, Binding::Key(KeyCode::Return)),
+            (Action::TeamChat, Binding::Key(KeyCode::T)),
+            (Action::NetGraph, Binding::Key(KeyCode::F3)),
+            (Action::ReplayPause, Binding::Key(KeyCode::P)),
+            (Action::ReplaySeekBack, Binding::Key(KeyCode::Left)),
+            (Action::ReplaySeekForward, Binding::Key(KeyCode::Right)),
+            (Action::ReplayFaster, Binding::Key(KeyCode::Up)),
+            (Action::ReplaySlower, Binding::Key(KeyCode::Down)),
+            (Action::ReplayRestart, Binding::Key(KeyCode::Home)),
+        ]);
+
+        Self { layout, bindings }
+    }
+
+    /// load the controls from the config file.
+    /// Actions missing from the file (added in a newer version ...) get the binding of the preset
+    pub fn load() -> Self{
+        let Some(mut controls) = config::load::<Controls>(CONTROLS_FILE) else {return Self::default()};
+
+        for (action, binding) in Self::preset(controls.layout).bindings{
+            controls.bindings.entry(action).or_insert(binding);
+        }
+
+        controls
+    }
+
+    pub fn save(&self){
+        config::save(CONTROLS_FILE, self);
+    }
+
+    /// bind the action, the action previously using this binding loses it
+    fn rebind(&mut self, action: Action, binding: Binding){
+        self.bindings.retain(|_, b| *b != binding);
+        self.bindings.insert(action, binding);
+    }
+}
+
+/// read the state of the actions, use it instead of `Input<KeyCode>`
+#[derive(SystemParam)]
+pub struct ActionInput<'w>{
+    keys: Res<'w, Input<KeyCode>>,
+    mouse_buttons: Res<'w, Input<MouseButton>>,
+    controls: Res<'w, Controls>,
+}
+
+impl<'w> ActionInput<'w>{
+    pub fn pressed(&self, action: Action) -> bool{
+        match self.controls.bindings.get(&action){
+            Some(Binding::Key(key)) => self.keys.pressed(*key),
+            Some(Binding::Mouse(button)) => self.mouse_buttons.pressed(*button),
+            None => false
+        }
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool{
+        match self.controls.bindings.get(&action){
+            Some(Binding::Key(key)) => self.keys.just_pressed(*key),
+            Some(Binding::Mouse(button)) => self.mouse_buttons.just_pressed(*button),
+            None => false
+        }
+    }
+}
+
+/// the action waiting for a new binding
+#[derive(Resource, Default)]
+struct Rebinding(Option<Action>);
+
+//////////////////////////// MENU ///////////////////////////////
+
+#[derive(Component, Clone, Copy)]
+enum ControlsButton{
+    Rebind(Action),
+    Layout(KeyboardLayout),
+}
+
+#[derive(Component)]
+struct BindingText(Action);
+
+/// not a bevy system. The content of the "Controls" tab of the settings
+pub fn spawn_controls_tab(parent: &mut ChildBuilder, controls: &Controls){
+    // layout presets
+    parent.spawn(row()).with_children(|row|{
+        row.spawn(menu_text("Preset".to_string(), 20.));
+        row.spawn((ControlsButton::Layout(KeyboardLayout::Qwerty), button_bundle())).with_children(|button|{
+            button.spawn(menu_text("QWERTY".to_string(), 20.));
+        });
+        row.spawn((ControlsButton::Layout(KeyboardLayout::Azerty), button_bundle())).with_children(|button|{
+            button.spawn(menu_text("AZERTY".to_string(), 20.));
+        });
+    });
+
+    // key bindings
+    for action in Action::ALL{
+        parent.spawn(row()).with_children(|row|{
+            row.spawn(menu_text(action.label().to_string(), 20.));
+            row.spawn((ControlsButton::Rebind(action), button_bundle())).with_children(|button|{
+                button.spawn((
+                    BindingText(action),
+                    menu_text(binding_label(controls, action), 20.)
+                ));
+            });
+        });
+    }
+}
+
+fn controls_menu_buttons(
+    button_q: Query<(&Interaction, &ControlsButton), Changed<Interaction>>,
+    mut controls: ResMut<Controls>,
+    mut rebinding: ResMut<Rebinding>,
+){
+    for (interaction, button) in button_q.iter(){
+        if *interaction != Interaction::Pressed {continue}
+
+        match *button{
+            ControlsButton::Rebind(action) => rebinding.0 = Some(action),
+            ControlsButton::Layout(layout) => {
+                *controls = Controls::preset(layout);
+                controls.save();
+            },
+        }
+    }
+}
+
+/// wait for the new key/button of the action being rebound
+fn rebind_listener(
+    keys: Res<Input<KeyCode>>,
+    mouse_buttons: Res<Input<MouseButton>>,
+    binding_text_q: Query<(), With<BindingText>>,
+    mut controls: ResMut<Controls>,
+    mut rebinding: ResMut<Rebinding>,
+){
+    let Some(action) = rebinding.0 else {return};
+
+    // the settings were closed
+    if binding_text_q.is_empty(){
+        rebinding.0 = None;
+        return;
+    }
+
+    let binding = if let Some(key) = keys.get_just_pressed().next(){
+        Binding::Key(*key)
+    }else if let Some(button) = mouse_buttons.get_just_pressed().next(){
+        Binding::Mouse(*button)
+    }else{
+        return
+    };
+
+    rebinding.0 = None;
+    // escape closes the menu, it cannot be bound
+    if binding == Binding::Key(KeyCode::Escape) {return}
+
+    controls.rebind(action, binding);
+    controls.save();
+}
+
+fn update_controls_menu(
+    controls: Res<Controls>,
+    rebinding: Res<Rebinding>,
+    mut binding_text_q: Query<(&mut Text, &BindingText)>,
+    mut button_q: Query<(&ControlsButton, &Interaction, &mut BackgroundColor)>,
+){
+    if !controls.is_changed() && !rebinding.is_changed() {return}
+
+    for (mut text, binding_text) in binding_text_q.iter_mut(){
+        text.sections[0].value = if rebinding.0 == Some(binding_text.0){
+            "press a key ...".to_string()
+        }else{
+            binding_label(&controls, binding_text.0)
+        };
+    }
+
+    for (button, interaction, mut color) in button_q.iter_mut(){
+        let ControlsButton::Rebind(action) = button else {continue};
+        *color = if rebinding.0 == Some(*action){
+            WAITING_BUTTON_COLOR.into()
+        }else if *interaction == Interaction::Hovered{
+            HOVERED_BUTTON_COLOR.into()
+        }else{
+            BUTTON_COLOR.into()
+        };
+    }
+}
+
+pub fn binding_label(controls: &Controls, action: Action) -> String{
+    controls.bindings.get(&action)
+        .map(|binding| binding.label())
+        .unwrap_or_else(|| "-".to_string())
+}
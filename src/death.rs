@@ -0,0 +1,395 @@
+//! What happens when a player dies.
+//! The server freezes the dead players, tells them who killed them and respawns them after a delay.
+//! The clients show the death screen with a respawn countdown, and can replay the last seconds
+//! from the killer's view (kill cam) with the player states buffered in `SnapshotBuffer`.
+use bevy::{prelude::*, utils::HashMap};
+use std::collections::VecDeque;
+
+use crate::{
+    player::{Player, PlayerName, CurrentPlayer, PLAYER_SCALE, get_spawn},
+    camera::{LookAngles, camera_height},
+    weapons::{Weapon, WeaponKind},
+    world::Maze,
+    shoot::PlayerHit,
+    settings::Settings,
+    game::{AppState, Cvars},
+    network::mods::{Lobby, RenetServer, DefaultChannel, ServerMessages},
+    bots::Bot,
+};
+
+//////////////////////////// PLUGIN, COMPONENTS, CONSTS ///////////////////////////////
+
+/// client side of the death screen, the kill cam itself is played in `play_kill_cam` (see network/mods.rs)
+pub struct DeathPlugin;
+impl Plugin for DeathPlugin{
+
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SnapshotBuffer>();
+        app.add_systems(OnEnter(AppState::DeathScreen), spawn_death_screen);
+        app.add_systems(OnExit(AppState::DeathScreen), despawn_death_screen);
+        app.add_systems(Update,(
+            start_kill_cam.run_if(resource_exists::<DeathInfo>().and_then(not(resource_exists::<KillCam>()))),
+            update_death_screen,
+        ).run_if(in_state(AppState::DeathScreen)));
+    }
+}
+
+// length (in seconds) of the replay of the kill cam
+const KILL_CAM_DURATION: f32 = 4.;
+
+/// a dead player, respawned when the timer reaches 0 (server side)
+#[derive(Component)]
+pub struct RespawnTimer(pub f32);
+
+/// how the current player died, sent by the server (client side)
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct DeathInfo{
+    pub killer: u64,
+    pub weapon: WeaponKind,
+    pub headshot: bool,
+    /// hp of the killer when the current player died
+    pub killer_hp: f32,
+    /// remaining time (in seconds) before the respawn
+    pub respawn_in: f32,
+}
+
+/// what the kill cam needs to know about a player
+#[derive(Clone, Copy)]
+pub struct PlayerSnapshot{
+    pub translation: Vec3,
+    pub look: LookAngles,
+    pub is_crouching: bool,
+}
+
+#[derive(Clone)]
+struct Snapshot{
+    time: f32,
+    players: HashMap<u64, PlayerSnapshot>,
+}
+
+/// the states of the players received during the last seconds (client side)
+#[derive(Resource, Default)]
+pub struct SnapshotBuffer(VecDeque<Snapshot>);
+
+impl SnapshotBuffer{
+    pub fn record(&mut self, time: f32, players: HashMap<u64, PlayerSnapshot>){
+        self.0.push_back(Snapshot{ time, players });
+        while self.0.front().is_some_and(|snapshot| snapshot.time < time - KILL_CAM_DURATION){
+            self.0.pop_front();
+        }
+    }
+}
+
+/// the replay of the last seconds before the death of the current player
+#[derive(Resource)]
+pub struct KillCam{
+    snapshots: VecDeque<Snapshot>,
+    killer: u64,
+    /// time of the replay, in the time of the snapshots
+    time: f32,
+    finished: bool,
+    /// the player's camera, disabled during the replay
+    player_cameras: Vec<Entity>,
+}
+
+impl KillCam{
+    /// interpolated state of a player at the current time of the replay
+    fn sample(&self, id: u64) -> Option<PlayerSnapshot>{
+        let next_index = self.snapshots.iter().position(|snapshot| snapshot.time > self.time)?;
+        let next = self.snapshots[next_index].players.get(&id)?;
+        let Some(previous_snapshot) = next_index.checked_sub(1).map(|index| &self.snapshots[index]) else {
+            return Some(*next);
+        };
+        let Some(previous) = previous_snapshot.players.get(&id) else {return Some(*next)};
+
+        let t = (self.time - previous_snapshot.time) / (self.snapshots[next_index].time - previous_snapshot.time);
+        Some(PlayerSnapshot{
+            translation: previous.translation.lerp(next.translation, t),
+            look: LookAngles{
+                yaw: previous.look.yaw + (next.look.yaw - previous.look.yaw) * t,
+                pitch: previous.look.pitch + (next.look.pitch - previous.look.pitch) * t,
+            },
+            is_crouching: next.is_crouching,
+        })
+    }
+}
+
+#[derive(Component)]
+struct DeathScreen;
+
+#[derive(Component)]
+pub struct KillCamCamera;
+
+#[derive(Component)]
+enum DeathScreenText{
+    Killer,
+    KillerHp,
+    Respawn,
+    KillCam,
+}
+
+//////////////////////////// SERVER ///////////////////////////////
+
+/// start the respawn timer of the killed players, and tell them who killed them
+pub fn start_respawn_timers(
+    mut cmd: Commands,
+    mut hits: EventReader<PlayerHit>,
+    mut server: ResMut<RenetServer>,
+    player_q: Query<&Player>,
+    bots_q: Query<(), With<Bot>>,
+    lobby: Res<Lobby>,
+    cvars: Res<Cvars>,
+){
+    for hit in hits.iter().filter(|hit| hit.killed){
+        let Some(&victim) = lobby.players.get(&hit.victim) else {continue};
+        cmd.entity(victim).insert(RespawnTimer(cvars.respawn_delay));
+
+        let killer_hp = lobby.players.get(&hit.shooter)
+            .and_then(|&killer| player_q.get(killer).ok())
+            .map_or(0., |killer| killer.hp.max(0.));
+
+        let message = bincode::serialize(&ServerMessages::Died {
+            killer: hit.shooter,
+            weapon: hit.weapon,
+            headshot: hit.headshot,
+            killer_hp,
+            respawn_in: cvars.respawn_delay,
+        }).unwrap();
+        // the bots have no client
+        if !bots_q.contains(victim){
+            server.send_message(hit.victim, DefaultChannel::ReliableOrdered, message);
+        }
+    }
+}
+
+/// the dead players come back with the default loadout, in a random corner of the maze
+pub fn respawn_players(
+    mut cmd: Commands,
+    mut player_q: Query<(Entity, &mut RespawnTimer, &mut Player, &mut Transform)>,
+    maze: Res<Maze>,
+    time: Res<Time>,
+){
+    for (entity, mut timer, mut player, mut transform) in player_q.iter_mut(){
+        timer.0 -= time.delta_seconds();
+        if timer.0 > 0. {continue}
+
+        let spawn_position = get_spawn(rand::random::<usize>() % 4, &maze);
+        transform.translation = Vec3::new(spawn_position.0, 1.5, spawn_position.1);
+        *player = Player{ flashlight_on: player.flashlight_on, ..default() };
+
+        cmd.entity(entity)
+            .insert(Weapon::rifle())
+            .remove::<RespawnTimer>();
+    }
+}
+
+//////////////////////////// DEATH SCREEN ///////////////////////////////
+
+fn spawn_death_screen(mut cmd: Commands){
+    let text = |value: &str, font_size: f32| TextBundle::from_section(
+        value,
+        TextStyle{ font_size, ..default() }
+    );
+
+    cmd.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.),
+                height: Val::Percent(100.),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::FlexEnd,
+                padding: UiRect::bottom(Val::Percent(20.)),
+                row_gap: Val::Px(8.),
+                ..default()
+            },
+            background_color: Color::rgba(0.3, 0., 0., 0.3).into(),
+            ..default()
+        },
+        DeathScreen,
+        Name::new("Death Screen")
+    )).with_children(|screen|{
+        screen.spawn((text("", 30.), DeathScreenText::KillCam));
+        screen.spawn(text("YOU ARE DEAD", 50.));
+        screen.spawn((text("", 25.), DeathScreenText::Killer));
+        screen.spawn((text("", 25.), DeathScreenText::KillerHp));
+        screen.spawn((text("", 25.), DeathScreenText::Respawn));
+    });
+}
+
+fn update_death_screen(
+    mut text_q: Query<(&mut Text, &DeathScreenText)>,
+    death_info: Option<ResMut<DeathInfo>>,
+    kill_cam: Option<Res<KillCam>>,
+    names_q: Query<&PlayerName>,
+    lobby: Res<Lobby>,
+    time: Res<Time>,
+){
+    // the server's message may arrive after the player's state
+    let Some(mut death_info) = death_info else {return};
+    death_info.respawn_in = (death_info.respawn_in - time.delta_seconds()).max(0.);
+
+    let killer = lobby.players.get(&death_info.killer)
+        .and_then(|&entity| names_q.get(entity).ok())
+        .map_or_else(|| death_info.killer.to_string(), |PlayerName(name)| name.clone());
+    let replaying = kill_cam.is_some_and(|kill_cam| !kill_cam.finished);
+
+    for (mut text, kind) in text_q.iter_mut(){
+        text.sections[0].value = match kind{
+            DeathScreenText::Killer => {
+                let headshot = if death_info.headshot {" (headshot)"} else {""};
+                format!("Killed by {} with {:?}{}", killer, death_info.weapon, headshot)
+            }
+            DeathScreenText::KillerHp => format!("{} had {:.0} HP left", killer, death_info.killer_hp.ceil()),
+            DeathScreenText::Respawn => format!("Respawn in {:.0}", death_info.respawn_in.ceil()),
+            DeathScreenText::KillCam => if replaying {"KILL CAM".to_string()} else {String::new()},
+        };
+    }
+}
+
+/// the death screen and the camera of the kill cam
+type DeathScreenQuery<'w, 's> = Query<'w, 's, Entity, Or<(With<DeathScreen>, With<KillCamCamera>)>>;
+
+fn despawn_death_screen(
+    mut cmd: Commands,
+    screen_q: DeathScreenQuery,
+    mut camera_q: Query<&mut Camera>,
+    mut visibility_q: Query<&mut Visibility>,
+    kill_cam: Option<Res<KillCam>>,
+    lobby: Res<Lobby>,
+){
+    for entity in screen_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+
+    if let Some(kill_cam) = kill_cam{
+        stop_kill_cam(&kill_cam, &mut camera_q, &mut visibility_q, &lobby);
+    }
+
+    cmd.remove_resource::<DeathInfo>();
+    cmd.remove_resource::<KillCam>();
+}
+
+//////////////////////////// KILL CAM ///////////////////////////////
+
+#[allow(clippy::too_many_arguments)]
+fn start_kill_cam(
+    mut cmd: Commands,
+    death_info: Res<DeathInfo>,
+    snapshots: Res<SnapshotBuffer>,
+    settings: Res<Settings>,
+    mut camera_q: Query<(Entity, &mut Camera, &Parent)>,
+    current_player_q: Query<Entity, With<CurrentPlayer>>,
+    mut visibility_q: Query<&mut Visibility>,
+    lobby: Res<Lobby>,
+){
+    let Some(start) = snapshots.0.front().map(|snapshot| snapshot.time) else {return};
+
+    // the kill cam is only played once, and not at all if it is disabled
+    let mut kill_cam = KillCam{
+        snapshots: snapshots.0.clone(),
+        killer: death_info.killer,
+        time: start,
+        finished: !settings.kill_cam,
+        player_cameras: vec![],
+    };
+
+    let killer = lobby.players.get(&death_info.killer);
+    if kill_cam.finished || killer.is_none(){
+        kill_cam.finished = true;
+        cmd.insert_resource(kill_cam);
+        return;
+    }
+
+    // the view of the player is replaced by the one of the killer
+    let Ok(current_player) = current_player_q.get_single() else {return};
+    for (entity, mut camera, parent) in camera_q.iter_mut(){
+        if parent.get() != current_player {continue}
+        camera.is_active = false;
+        kill_cam.player_cameras.push(entity);
+    }
+
+    // the camera is inside the killer's model
+    if let Some(&killer) = killer{
+        if let Ok(mut visibility) = visibility_q.get_mut(killer){
+            *visibility = Visibility::Hidden;
+        }
+    }
+
+    cmd.spawn((
+        Camera3dBundle{
+            camera: Camera{
+                order: isize::MAX,
+                ..default()
+            },
+            ..default()
+        },
+        KillCamCamera,
+        Name::new("Kill Cam")
+    ));
+    cmd.insert_resource(kill_cam);
+}
+
+/// move the players and the camera as they were during the replayed time.
+/// runs after `client_sync_players` so the replayed positions replace the current ones
+pub fn play_kill_cam(
+    mut cmd: Commands,
+    kill_cam: Option<ResMut<KillCam>>,
+    mut kill_cam_camera_q: Query<(Entity, &mut Transform), With<KillCamCamera>>,
+    mut camera_q: Query<&mut Camera>,
+    mut visibility_q: Query<&mut Visibility>,
+    lobby: Res<Lobby>,
+    time: Res<Time>,
+){
+    let Some(mut kill_cam) = kill_cam else {return};
+    if kill_cam.finished {return}
+    let Ok((camera_entity, mut camera_transform)) = kill_cam_camera_q.get_single_mut() else {return};
+
+    kill_cam.time += time.delta_seconds();
+    let end = kill_cam.snapshots.back().map_or(0., |snapshot| snapshot.time);
+    if kill_cam.time >= end{
+        kill_cam.finished = true;
+        stop_kill_cam(&kill_cam, &mut camera_q, &mut visibility_q, &lobby);
+        cmd.entity(camera_entity).despawn_recursive();
+        return;
+    }
+
+    for (&id, &entity) in lobby.players.iter(){
+        let Some(snapshot) = kill_cam.sample(id) else {continue};
+
+        if id == kill_cam.killer{
+            camera_transform.translation = snapshot.translation + Vec3::Y * camera_height(snapshot.is_crouching) * PLAYER_SCALE;
+            camera_transform.rotation = snapshot.look.body_rotation() * snapshot.look.view_rotation();
+        }
+
+        cmd.entity(entity).insert((
+            Transform{
+                translation: snapshot.translation,
+                rotation: snapshot.look.body_rotation(),
+                scale: PLAYER_SCALE * Vec3::ONE,
+            },
+            snapshot.look,
+        ));
+    }
+}
+
+/// give the view back to the player.
+/// not a bevy system
+fn stop_kill_cam(
+    kill_cam: &KillCam,
+    camera_q: &mut Query<&mut Camera>,
+    visibility_q: &mut Query<&mut Visibility>,
+    lobby: &Lobby,
+){
+    for &entity in kill_cam.player_cameras.iter(){
+        if let Ok(mut camera) = camera_q.get_mut(entity){
+            camera.is_active = true;
+        }
+    }
+
+    let Some(&killer) = lobby.players.get(&kill_cam.killer) else {return};
+    if let Ok(mut visibility) = visibility_q.get_mut(killer){
+        *visibility = Visibility::Inherited;
+    }
+}
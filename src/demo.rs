@@ -0,0 +1,545 @@
+//! Match demos : the server records the snapshots and the messages broadcast to the players
+//! in a demo file (`record` and `stop` in the server console), the client plays it back without
+//! a server (`cargo run replay <file>`), with the spectator cameras (free camera or player POVs).
+//! The file starts with the magic bytes and the `DemoHeader`, followed by the frames (bincode).
+//! The messages sent to a single player (damage, hit markers, team chat ...) are not recorded.
+use bevy::{prelude::*, ecs::system::SystemParam, window::PrimaryWindow};
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{
+    game::{AppState, Cvars, GameMode},
+    player::PlayerName,
+    team::Team,
+    pickup::Pickup,
+    spectator::Spectator,
+    world::{Maze, Surface},
+    controls::{Action, ActionInput, Controls, binding_label},
+    network::mods::{
+        Lobby, RenetServer, DefaultChannel, ServerMessages, ServerInbox, PROTOCOL_ID,
+        client_connections_handler, client_sync_players,
+    },
+};
+
+//////////////////////////// PLUGIN, CONSTS, FILE FORMAT ///////////////////////////////
+
+/// plays the `DemoPlayback` resource, inserted by main.rs
+pub struct ReplayPlugin;
+impl Plugin for ReplayPlugin{
+
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, start_replay);
+        app.add_systems(Update, (
+            replay_controls,
+            play_demo.after(replay_controls)
+                .before(client_connections_handler)
+                .before(client_sync_players),
+            update_replay_text.after(play_demo),
+        ));
+    }
+}
+
+const DEMOS_DIRECTORY: &str = "demos";
+const DEMO_MAGIC: [u8; 4] = *b"SSDM";
+/// changed when the format of the file changes
+const DEMO_VERSION: u32 = 1;
+// time (in seconds) between two writes of the recorded frames on the disk
+const FLUSH_INTERVAL: f32 = 1.;
+// time (in seconds) skipped by the seek keys
+const SEEK_STEP: f32 = 5.;
+const SPEEDS: [f32; 7] = [0.125, 0.25, 0.5, 1., 2., 4., 8.];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DemoHeader{
+    pub version: u32,
+    /// the messages can only be read by the same version of the game
+    pub protocol_id: u64,
+    /// when the recording started (seconds since the unix epoch)
+    pub date: u64,
+    pub map_name: String,
+    pub map: Vec<Vec<i32>>,
+    pub surfaces: Vec<Vec<Surface>>,
+    pub mode: GameMode,
+    /// the players connected when the recording started, the next ones are in the messages
+    pub players: Vec<(u64, String, Team)>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum DemoData{
+    /// a `ServerMessages` broadcast on the reliable channel
+    Message(Vec<u8>),
+    /// the states of the players, broadcast every frame on the unreliable channel
+    Snapshot(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize)]
+struct DemoFrame{
+    /// time (in seconds) since the start of the recording
+    time: f32,
+    data: DemoData,
+}
+
+/// not a bevy system. Varint encoding, more compact than the default one of `bincode::serialize`
+fn demo_encoding() -> impl Options{
+    bincode::DefaultOptions::new()
+}
+
+/// not a bevy system. `demos/<name>.demo`, named after the date and the map if no name is given
+pub fn demo_path(name: Option<&str>, map_name: &str) -> PathBuf{
+    let name = name.map_or_else(|| format!("{}_{}", unix_time(), map_name), str::to_string);
+    Path::new(DEMOS_DIRECTORY).join(format!("{}.demo", name))
+}
+
+/// not a bevy system
+fn unix_time() -> u64{
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+//////////////////////////// RECORDING (server side) ///////////////////////////////
+
+/// sent by the server console
+#[derive(Event, Debug, Clone)]
+pub enum DemoCommand{
+    Record(PathBuf),
+    Stop,
+}
+
+/// the demo being recorded
+#[derive(Resource)]
+pub struct DemoRecorder{
+    pub path: PathBuf,
+    writer: BufWriter<File>,
+    start: f32,
+    last_flush: f32,
+}
+
+impl DemoRecorder{
+    fn create(path: PathBuf, header: &DemoHeader, now: f32) -> Result<Self, String>{
+        if let Some(directory) = path.parent(){
+            fs::create_dir_all(directory).map_err(|e| e.to_string())?;
+        }
+        let mut writer = BufWriter::new(File::create(&path).map_err(|e| e.to_string())?);
+        writer.write_all(&DEMO_MAGIC).map_err(|e| e.to_string())?;
+        demo_encoding().serialize_into(&mut writer, header).map_err(|e| e.to_string())?;
+
+        Ok(Self{ path, writer, start: now, last_flush: now })
+    }
+
+    fn record(&mut self, now: f32, data: DemoData){
+        let frame = DemoFrame{ time: now - self.start, data };
+        if let Err(e) = demo_encoding().serialize_into(&mut self.writer, &frame){
+            eprintln!("ERR: cannot write the demo {}: {}", self.path.display(), e);
+        }
+
+        // the demo is readable even if the server is killed
+        if now - self.last_flush >= FLUSH_INTERVAL{
+            self.last_flush = now;
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self){
+        if let Err(e) = self.writer.flush(){
+            eprintln!("ERR: cannot write the demo {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// the renet server, the messages broadcast to everyone are also written in the demo being recorded.
+/// Use it instead of `ResMut<RenetServer>` in the systems broadcasting messages
+#[derive(SystemParam)]
+pub struct GameServer<'w>{
+    server: ResMut<'w, RenetServer>,
+    recorder: Option<ResMut<'w, DemoRecorder>>,
+    time: Res<'w, Time>,
+}
+
+impl<'w> GameServer<'w>{
+    pub fn broadcast_message(&mut self, channel: DefaultChannel, message: Vec<u8>){
+        if let Some(recorder) = self.recorder.as_mut(){
+            let data = match channel{
+                DefaultChannel::Unreliable => DemoData::Snapshot(message.clone()),
+                _ => DemoData::Message(message.clone()),
+            };
+            recorder.record(self.time.elapsed_seconds(), data);
+        }
+        self.server.broadcast_message(channel, message);
+    }
+}
+
+impl<'w> Deref for GameServer<'w>{
+    type Target = RenetServer;
+
+    fn deref(&self) -> &RenetServer{
+        &self.server
+    }
+}
+
+impl<'w> DerefMut for GameServer<'w>{
+    fn deref_mut(&mut self) -> &mut RenetServer{
+        &mut self.server
+    }
+}
+
+/// start or stop the recording. A new recording stops the current one
+#[allow(clippy::too_many_arguments)]
+pub fn handle_demo_commands(
+    mut cmd: Commands,
+    mut commands: EventReader<DemoCommand>,
+    mut recorder: Option<ResMut<DemoRecorder>>,
+    maze: Res<Maze>,
+    cvars: Res<Cvars>,
+    lobby: Res<Lobby>,
+    players_q: Query<(&PlayerName, &Team)>,
+    pickup_q: Query<(&Pickup, &Transform)>,
+    time: Res<Time>,
+){
+    for command in commands.iter(){
+        match (command, recorder.take()){
+            (_, Some(mut current)) => {
+                current.flush();
+                cmd.remove_resource::<DemoRecorder>();
+                println!("demo saved: {}", current.path.display());
+            }
+            (DemoCommand::Stop, None) => eprintln!("ERR: no demo is being recorded"),
+            _ => (),
+        }
+
+        let DemoCommand::Record(path) = command else {continue};
+        let mut players: Vec<(u64, String, Team)> = lobby.players.iter()
+            .filter_map(|(&id, &entity)| players_q.get(entity).ok().map(|(PlayerName(name), team)| (id, name.clone(), *team)))
+            .collect();
+        players.sort_by_key(|(id, ..)| *id);
+
+        let header = DemoHeader{
+            version: DEMO_VERSION,
+            protocol_id: PROTOCOL_ID,
+            date: unix_time(),
+            map_name: maze.name.clone(),
+            map: maze.map.clone(),
+            surfaces: maze.surfaces.clone(),
+            mode: cvars.mode,
+            players,
+        };
+        let now = time.elapsed_seconds();
+
+        match DemoRecorder::create(path.clone(), &header, now){
+            Ok(mut new_recorder) => {
+                // the pickups taken before the recording started
+                for (pickup, transform) in pickup_q.iter(){
+                    let message = bincode::serialize(&pickup.to_message(transform)).unwrap();
+                    new_recorder.record(now, DemoData::Message(message));
+                }
+                println!("recording the demo {}", path.display());
+                cmd.insert_resource(new_recorder);
+            }
+            Err(e) => eprintln!("ERR: cannot record the demo {}: {}", path.display(), e),
+        }
+    }
+}
+
+//////////////////////////// PLAYBACK (client side) ///////////////////////////////
+
+/// a demo read from a file, its frames fill the `ServerInbox` as if they came from the server
+#[derive(Resource)]
+pub struct DemoPlayback{
+    pub header: DemoHeader,
+    frames: Vec<DemoFrame>,
+    /// index of the next frame to play
+    next: usize,
+    /// time (in seconds) since the start of the demo
+    pub time: f32,
+    pub speed: f32,
+    pub paused: bool,
+}
+
+#[derive(Component)]
+struct ReplayText;
+
+impl DemoPlayback{
+    pub fn open(path: &Path) -> Result<Self, String>{
+        let file = File::open(path).map_err(|e| format!("cannot open the demo {}: {}", path.display(), e))?;
+        let mut reader = BufReader::new(file);
+
+        let mut magic = [0; 4];
+        if reader.read_exact(&mut magic).is_err() || magic != DEMO_MAGIC{
+            return Err(format!("{} is not a demo", path.display()));
+        }
+        let header: DemoHeader = demo_encoding().deserialize_from(&mut reader)
+            .map_err(|e| format!("invalid demo header: {}", e))?;
+        if header.version != DEMO_VERSION || header.protocol_id != PROTOCOL_ID{
+            return Err(format!(
+                "the demo was recorded by another version of the game (format {}, protocol {})",
+                header.version, header.protocol_id
+            ));
+        }
+        // the spectator and the minimap index the map, an edited demo must not crash the game
+        Maze::check_map(&header.map).map_err(|e| format!("invalid demo map: {}", e))?;
+
+        // a demo cut by a crash of the server ends with an incomplete frame, it is ignored
+        let mut frames = vec![];
+        while let Ok(frame) = demo_encoding().deserialize_from::<_, DemoFrame>(&mut reader){
+            frames.push(frame);
+        }
+
+        let playback = Self{ header, frames, next: 0, time: 0., speed: 1., paused: false };
+        println!(
+            "demo {}: map {}, mode {}, {} players, {} frames, {:.0} seconds",
+            path.display(), playback.header.map_name, playback.header.mode.name(),
+            playback.header.players.len(), playback.frames.len(), playback.duration()
+        );
+        Ok(playback)
+    }
+
+    pub fn duration(&self) -> f32{
+        self.frames.last().map_or(0., |frame| frame.time)
+    }
+
+    /// back to the state of the start of the recording
+    fn restart(&mut self, lobby: &Lobby, inbox: &mut ServerInbox){
+        for &id in lobby.players.keys(){
+            inbox.messages.push_back(ServerMessages::PlayerDisconnected{ id });
+        }
+        inbox.messages.push_back(ServerMessages::Map{
+            name: self.header.map_name.clone(),
+            map: self.header.map.clone(),
+            surfaces: self.header.surfaces.clone(),
+        });
+        for (id, name, team) in self.header.players.iter(){
+            inbox.messages.push_back(ServerMessages::PlayerConnected{ id: *id, name: name.clone(), team: *team });
+        }
+        self.next = 0;
+        self.time = 0.;
+    }
+
+    /// play the frames until `time`. When seeking, only the messages changing the state of the game
+    /// and the last snapshot are played (no sounds, kill feed ... of the skipped part)
+    fn play_until(&mut self, time: f32, seeking: bool, inbox: &mut ServerInbox){
+        let mut last_snapshot = None;
+
+        while let Some(frame) = self.frames.get(self.next){
+            if frame.time > time {break}
+            self.next += 1;
+
+            match &frame.data{
+                DemoData::Message(data) => {
+                    let Ok(message) = bincode::deserialize::<ServerMessages>(data) else {
+                        eprintln!("ERR: invalid message in the demo at {:.1} s", frame.time);
+                        continue;
+                    };
+                    let changes_state = matches!(message,
+                        ServerMessages::PlayerConnected{..} | ServerMessages::PlayerDisconnected{..}
+                        | ServerMessages::PickupUpdate{..} | ServerMessages::Map{..}
+                    );
+                    if seeking && !changes_state {continue}
+                    inbox.messages.push_back(message);
+                }
+                DemoData::Snapshot(data) => {
+                    let Ok(snapshot) = bincode::deserialize(data) else {
+                        eprintln!("ERR: invalid snapshot in the demo at {:.1} s", frame.time);
+                        continue;
+                    };
+                    if seeking {
+                        last_snapshot = Some(snapshot);
+                    }else{
+                        inbox.snapshots.push(snapshot);
+                    }
+                }
+            }
+        }
+
+        inbox.snapshots.extend(last_snapshot);
+        self.time = time;
+    }
+
+    fn seek(&mut self, time: f32, lobby: &Lobby, inbox: &mut ServerInbox){
+        let time = time.clamp(0., self.duration());
+        if time < self.time{
+            self.restart(lobby, inbox);
+        }
+        self.play_until(time, true, inbox);
+    }
+}
+
+/// load the map and the players of the demo, and watch it as a spectator
+fn start_replay(
+    mut cmd: Commands,
+    mut playback: ResMut<DemoPlayback>,
+    lobby: Res<Lobby>,
+    mut inbox: ResMut<ServerInbox>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+    mut next_state: ResMut<NextState<AppState>>,
+){
+    // the spectator camera starts above the center of the map
+    let header = &playback.header;
+    cmd.insert_resource(Maze::new(&header.map_name, header.map.clone(), header.surfaces.clone()));
+    playback.restart(&lobby, &mut inbox);
+
+    cmd.insert_resource(Spectator);
+    if let Ok(mut window) = windows.get_single_mut(){
+        window.cursor.visible = false;
+    }
+    next_state.set(AppState::Spectating);
+
+    cmd.spawn((
+        NodeBundle{
+            style: Style{
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.),
+                top: Val::Px(10.),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        },
+        Name::new("Replay")
+    )).with_children(|parent|{
+        parent.spawn((
+            TextBundle::from_section("", TextStyle{ font_size: 20., ..default() })
+                .with_text_alignment(TextAlignment::Center),
+            ReplayText,
+        ));
+    });
+}
+
+/// pause, seek, change the speed and restart, with the replay actions of the controls
+fn replay_controls(
+    actions: ActionInput,
+    mut playback: ResMut<DemoPlayback>,
+    lobby: Res<Lobby>,
+    mut inbox: ResMut<ServerInbox>,
+){
+    if actions.just_pressed(Action::ReplayPause){
+        playback.paused = !playback.paused;
+    }
+
+    let speed = SPEEDS.iter().position(|&speed| speed == playback.speed).unwrap_or(3);
+    if actions.just_pressed(Action::ReplayFaster){
+        playback.speed = SPEEDS[(speed + 1).min(SPEEDS.len() - 1)];
+    }
+    if actions.just_pressed(Action::ReplaySlower){
+        playback.speed = SPEEDS[speed.saturating_sub(1)];
+    }
+
+    let target = if actions.just_pressed(Action::ReplayRestart){
+        Some(0.)
+    }else if actions.just_pressed(Action::ReplaySeekBack){
+        Some(playback.time - SEEK_STEP)
+    }else if actions.just_pressed(Action::ReplaySeekForward){
+        Some(playback.time + SEEK_STEP)
+    }else{
+        None
+    };
+    if let Some(target) = target{
+        playback.seek(target, &lobby, &mut inbox);
+    }
+}
+
+fn play_demo(
+    mut playback: ResMut<DemoPlayback>,
+    mut inbox: ResMut<ServerInbox>,
+    time: Res<Time>,
+){
+    if playback.paused {return}
+
+    let end = playback.duration();
+    let now = (playback.time + time.delta_seconds() * playback.speed).min(end);
+    playback.play_until(now, false, &mut inbox);
+
+    // stays on the last frame
+    if now >= end{
+        playback.paused = true;
+    }
+}
+
+fn update_replay_text(
+    playback: Res<DemoPlayback>,
+    controls: Res<Controls>,
+    mut text_q: Query<&mut Text, With<ReplayText>>,
+){
+    let Ok(mut text) = text_q.get_single_mut() else {return};
+    let minutes_seconds = |time: f32| format!("{}:{:02}", time as u32 / 60, time as u32 % 60);
+    let key = |action| binding_label(&controls, action);
+
+    text.sections[0].value = format!(
+        "REPLAY  {}  {}  {} / {}  x{}{}\n[{}] pause  [{}/{}] seek  [{}/{}] speed  [{}] restart",
+        playback.header.map_name, playback.header.mode.name(),
+        minutes_seconds(playback.time), minutes_seconds(playback.duration()),
+        playback.speed, if playback.paused {"  (paused)"} else {""},
+        key(Action::ReplayPause), key(Action::ReplaySeekBack), key(Action::ReplaySeekForward),
+        key(Action::ReplayFaster), key(Action::ReplaySlower), key(Action::ReplayRestart)
+    );
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn header() -> DemoHeader{
+        let maze = Maze::default();
+        DemoHeader{
+            version: DEMO_VERSION,
+            protocol_id: PROTOCOL_ID,
+            date: 0,
+            map_name: maze.name,
+            map: maze.map,
+            surfaces: maze.surfaces,
+            mode: GameMode::Deathmatch,
+            players: vec![(1, "player".to_string(), Team::Red)],
+        }
+    }
+
+    /// not a bevy system. A demo with two frames in the temp directory
+    fn write_demo(name: &str, header: &DemoHeader) -> PathBuf{
+        let path = std::env::temp_dir().join(format!("{}_{}.demo", name, std::process::id()));
+        let mut recorder = DemoRecorder::create(path.clone(), header, 10.).unwrap();
+        recorder.record(10.5, DemoData::Message(vec![1, 2, 3]));
+        recorder.record(12., DemoData::Snapshot(vec![4, 5]));
+        recorder.flush();
+        path
+    }
+
+    #[test]
+    fn read_recorded_demo(){
+        let path = write_demo("read_recorded_demo", &header());
+        let playback = DemoPlayback::open(&path).unwrap();
+        assert_eq!(playback.header.players, header().players);
+        assert_eq!(playback.header.map, header().map);
+        assert_eq!(playback.frames.len(), 2);
+        assert_eq!(playback.duration(), 2.);
+        assert!(matches!(&playback.frames[0].data, DemoData::Message(data) if *data == [1, 2, 3]));
+
+        // a crash of the server cuts the last frame
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let playback = DemoPlayback::open(&path).unwrap();
+        assert_eq!(playback.frames.len(), 1);
+        assert_eq!(playback.duration(), 0.5);
+
+        // not a demo
+        fs::write(&path, &bytes[4..]).unwrap();
+        assert!(DemoPlayback::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reject_other_versions_and_invalid_maps(){
+        let mut headers = [header(), header(), header(), header()];
+        headers[0].version += 1;
+        headers[1].protocol_id += 1;
+        headers[2].map = vec![];
+        headers[3].map[1].pop();
+        for (i, header) in headers.iter().enumerate(){
+            let path = write_demo(&format!("reject_demo_{}", i), header);
+            assert!(DemoPlayback::open(&path).is_err(), "header {} accepted", i);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
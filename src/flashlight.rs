@@ -1,8 +1,6 @@
-use std::f32::consts::PI;
-
 use bevy::prelude::*;
 
-use crate::player::{PlayerId, PlayerInput, Player};
+use crate::{player::{PlayerId, PlayerInput, Player}, camera::{CAMERA_HEIGHT, LookAngles}};
 
 #[derive(Component)]
 pub struct FlashlightOn(pub bool);
@@ -10,8 +8,8 @@ pub struct FlashlightOn(pub bool);
 
 /// not a bevy system. returns the flashlight bundle for the client
 pub fn get_flashlight_bundle(id: u64) -> impl Bundle{
-    let mut flashlight_transform = Transform::from_xyz(0., 0.65, -0.2);
-    flashlight_transform.rotate_y(PI);
+    let flashlight_transform = Transform::from_xyz(0., CAMERA_HEIGHT, -0.2)
+        .with_rotation(LookAngles::default().view_rotation());
     
     // setting the flashlight
     let flashlight_bundle = SpotLightBundle{
@@ -35,8 +33,8 @@ pub fn get_flashlight_bundle(id: u64) -> impl Bundle{
 
 pub fn get_inner_flashlight(id: u64) -> impl Bundle{
 
-    let mut flashlight_transform = Transform::from_xyz(0., 0.65, -0.2);
-    flashlight_transform.rotate_y(PI);
+    let flashlight_transform = Transform::from_xyz(0., CAMERA_HEIGHT, -0.2)
+        .with_rotation(LookAngles::default().view_rotation());
     
     let flashlight_bundle = SpotLightBundle{
         transform: flashlight_transform,                    
@@ -61,9 +59,5 @@ pub fn toggle_flashlight(
 ){
        for (input, mut player) in query.iter_mut(){
             player.flashlight_on = input.toggle_flashlight;
-
-            if input.mute{
-                player.is_muted = !player.is_muted;
-            }
        }
 }
\ No newline at end of file
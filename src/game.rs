@@ -1,86 +1,207 @@
 //! This file contains a bunch of high-level games functionalities 
 //! such as the bevy's GameState (Menu, InGame ...), window managing etc ....
-use bevy::{prelude::*, window::{PrimaryWindow, WindowMode}};
+use bevy::{prelude::*, window::PrimaryWindow};
+use serde::{Serialize, Deserialize};
 
-use crate::player::{CurrentPlayer, Player};
+use crate::{settings::{Settings, WindowModeSetting}, spectator::Spectator, team::Team};
 
 #[derive(States, Default, Debug, Hash, Eq, PartialEq, Clone)]
 pub enum AppState{
-    #[default]
     InGame,
     GameMenu,
+    #[default]
     MainMenu,
-    DeathScreen
+    DeathScreen,
+    /// free-fly or follow camera, for the spectators and the dead players
+    Spectating,
+}
+/// the rules of the match, chosen with the `mode` command of the server console
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameMode{
+    /// everyone against everyone
+    #[default]
+    Deathmatch,
+    /// red against blue, see `Cvars::friendly_fire`
+    TeamDeathmatch,
+}
+
+impl GameMode{
+    pub const ALL: [GameMode; 2] = [GameMode::Deathmatch, GameMode::TeamDeathmatch];
+
+    pub fn name(&self) -> &'static str{
+        match self{
+            Self::Deathmatch => "dm",
+            Self::TeamDeathmatch => "tdm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self>{
+        Self::ALL.into_iter().find(|mode| mode.name() == name)
+    }
+}
+
+/// settings of the match changed from the server console (server side)
+#[derive(Resource, Debug)]
+pub struct Cvars{
+    pub mode: GameMode,
+    /// time (in seconds) before a dead player respawns
+    pub respawn_delay: f32,
+    /// the teammates can hurt each other in team deathmatch
+    pub friendly_fire: bool,
+    /// aim precision and turn speed of the bots, between 0 and 1 (see bots.rs)
+    pub bot_skill: f32,
+    /// time (in seconds) before a bot shoots at a player it just saw
+    pub bot_reaction_time: f32,
+}
+
+impl Default for Cvars{
+    fn default() -> Self {
+        Self{
+            mode: GameMode::default(),
+            respawn_delay: 5.,
+            friendly_fire: false,
+            bot_skill: 0.5,
+            bot_reaction_time: 0.4,
+        }
+    }
+}
+
+/// what happened since the start of the match, reset by the `restart` command (server side)
+#[derive(Resource, Debug, Default)]
+pub struct MatchState{
+    /// the first kill of the match has been announced
+    pub first_blood_done: bool,
 }
+
+impl Cvars{
+    /// the names of the cvars, for the `set` command
+    pub const NAMES: [&'static str; 4] = ["respawn_delay", "friendly_fire", "bot_skill", "bot_reaction_time"];
+
+    /// returns an error message if the cvar does not exist or the value is invalid
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String>{
+        match name{
+            "respawn_delay" => {
+                self.respawn_delay = value.parse::<f32>()
+                    .ok()
+                    .filter(|delay| *delay >= 0.)
+                    .ok_or_else(|| format!("invalid delay: {}", value))?;
+            }
+            "friendly_fire" => {
+                self.friendly_fire = match value{
+                    "1" | "true" | "on" => true,
+                    "0" | "false" | "off" => false,
+                    _ => return Err(format!("invalid boolean: {}", value)),
+                };
+            }
+            "bot_skill" => {
+                self.bot_skill = value.parse::<f32>()
+                    .ok()
+                    .filter(|skill| (0. ..=1.).contains(skill))
+                    .ok_or_else(|| format!("invalid skill (between 0 and 1): {}", value))?;
+            }
+            "bot_reaction_time" => {
+                self.bot_reaction_time = value.parse::<f32>()
+                    .ok()
+                    .filter(|time| *time >= 0.)
+                    .ok_or_else(|| format!("invalid time: {}", value))?;
+            }
+            _ => return Err(format!("unknown cvar: {}", name)),
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<String>{
+        match name{
+            "respawn_delay" => Some(self.respawn_delay.to_string()),
+            "friendly_fire" => Some(self.friendly_fire.to_string()),
+            "bot_skill" => Some(self.bot_skill.to_string()),
+            "bot_reaction_time" => Some(self.bot_reaction_time.to_string()),
+            _ => None,
+        }
+    }
+
+    /// the teammates don't hurt each other in team deathmatch, unless friendly fire is on
+    pub fn can_damage(&self, shooter: Option<Team>, victim: Option<Team>) -> bool{
+        self.mode != GameMode::TeamDeathmatch
+            || self.friendly_fire
+            || shooter.is_none()
+            || shooter != victim
+    }
+}
+
 // show or hide the cursor
 pub fn toggle_game_menu(
     mut windows: Query<&mut Window, With<PrimaryWindow>>,
     key: Res<Input<KeyCode>>,
     mut next_state: ResMut<NextState<AppState>>,
     current_state: Res<State<AppState>>,
+    spectator: Option<Res<Spectator>>,
 ){
     let mut window = windows.single_mut();
     
-    // toggle game state (inGame/ GameMenu)
+    // toggle game state (inGame or Spectating / GameMenu)
     if key.just_pressed(KeyCode::Escape){
-        if *current_state.get() == AppState::InGame{
-            window.cursor.visible = true;
-            next_state.set(AppState::GameMenu);
-        }
-        
-        if *current_state.get() == AppState::GameMenu{
-            window.cursor.visible = false;
-            next_state.set(AppState::InGame);
+        match current_state.get(){
+            AppState::InGame | AppState::Spectating => {
+                window.cursor.visible = true;
+                next_state.set(AppState::GameMenu);
+            }
+            AppState::GameMenu => {
+                window.cursor.visible = false;
+                next_state.set(resume_state(spectator.is_some()));
+            }
+            _ => (),
         }
     }
 }
 
-pub fn dead_screen(mut cmd: Commands){
-    cmd.spawn(TextBundle {
-        text: Text::from_section(
-            "YOU ARE DEAD",
-            TextStyle{
-                font_size: 50.,
-                ..default()
-            }
-        ),
-        style: Style{
-            align_self: AlignSelf::Center,
-            margin: UiRect {
-                left: Val::Percent(5.),
-                bottom: Val::Percent(40.),
-                ..default()
-            },
-            ..default()
-        },
-        ..default()        
-    });
+/// not a bevy system. The state to go back to when the pause menu is closed
+pub fn resume_state(spectator: bool) -> AppState{
+    if spectator {AppState::Spectating} else {AppState::InGame}
 }
 
+/// F11 switches between windowed and borderless fullscreen
 pub fn toggle_fullscreen(
-    mut windows: Query<&mut Window, With<PrimaryWindow>>,
     keys: Res<Input<KeyCode>>,
-
+    mut settings: ResMut<Settings>,
 ){
     if keys.just_pressed(KeyCode::F11){
-        let mut window = windows
-            .get_single_mut()
-            .expect("cannot find main window in function toggle_fullscreen");
-
-        window.mode = WindowMode::Windowed;
-     }
+        settings.window_mode = match settings.window_mode{
+            WindowModeSetting::Windowed => WindowModeSetting::Borderless,
+            _ => WindowModeSetting::Windowed,
+        };
+        settings.save();
+    }
 }
 
-/// press 'm' to disable the sound
-pub fn toggle_sound(
-    player_q: Query<&Player, With<CurrentPlayer>>,
-    sounds: Query<&AudioSink>,
-){
-    let Ok(player) = player_q.get_single() else {return};
+#[cfg(test)]
+mod tests{
+    use super::*;
 
-    if player.is_muted {
-        for sink in sounds.iter(){
-            sink.set_volume(0.)
-        }
+    #[test]
+    fn set_cvars(){
+        let mut cvars = Cvars::default();
+        cvars.set("respawn_delay", "2.5").unwrap();
+        cvars.set("friendly_fire", "on").unwrap();
+        cvars.set("bot_skill", "1").unwrap();
+        cvars.set("bot_reaction_time", "0").unwrap();
+        assert_eq!(cvars.respawn_delay, 2.5);
+        assert!(cvars.friendly_fire);
+        assert_eq!(cvars.bot_skill, 1.);
+        assert_eq!(cvars.bot_reaction_time, 0.);
+        assert_eq!(cvars.get("friendly_fire"), Some("true".to_string()));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn reject_invalid_cvars(){
+        let mut cvars = Cvars::default();
+        assert!(cvars.set("respawn_delay", "-1").is_err());
+        assert!(cvars.set("respawn_delay", "soon").is_err());
+        assert!(cvars.set("friendly_fire", "maybe").is_err());
+        assert!(cvars.set("bot_skill", "1.5").is_err());
+        assert!(cvars.set("gravity", "0").is_err());
+        // the invalid values don't change the cvars
+        assert_eq!(cvars.respawn_delay, Cvars::default().respawn_delay);
+        assert!(!cvars.friendly_fire);
+    }
+}
@@ -1,34 +1,83 @@
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::Collider;
 
+use crate::player::Player;
+
 #[derive(Component)]
 /// contains the damage multiplier
 pub struct Hitbox(pub f32);
 impl Hitbox{
     pub fn head() -> impl Bundle{
+        let (collider, transform) = BodyPart::Head.shape(false);
         (
             Name::new("Head Collider"),
-            Collider::cuboid(0.05, 0.05, 0.05),
-            TransformBundle::from_transform(Transform::from_xyz(0., 0.6, 0.)),
+            collider,
+            TransformBundle::from_transform(transform),
+            BodyPart::Head,
             Hitbox(3.5) // headshot deals x3.5 damage
         )
     }
 
     pub fn body() -> impl Bundle{
+        let (collider, transform) = BodyPart::Body.shape(false);
         (
             Name::new("Body Collider"),
-            Collider::cylinder(0.115, 0.08),
-            TransformBundle::from_transform(Transform::from_xyz(0., 0.425, 0.)),
+            collider,
+            TransformBundle::from_transform(transform),
+            BodyPart::Body,
             Hitbox(1.)
         )
     }
 
     pub fn legs() -> impl Bundle{
+        let (collider, transform) = BodyPart::Legs.shape(false);
         (
             Name::new("Body Collider"),
-            Collider::cylinder(0.155, 0.08),
-            TransformBundle::from_transform(Transform::from_xyz(0., 0.155, 0.)),
+            collider,
+            TransformBundle::from_transform(transform),
+            BodyPart::Legs,
             Hitbox(0.5)
         )
     }
 }
+
+#[derive(Component, Clone, Copy, PartialEq)]
+pub enum BodyPart{
+    Head,
+    Body,
+    Legs
+}
+
+impl BodyPart{
+    /// collider of the body part and its position relative to the player,
+    /// the body and the legs are shrunk when the player is crouching
+    pub fn shape(&self, is_crouching: bool) -> (Collider, Transform){
+        match (self, is_crouching){
+            (Self::Head, false) => (Collider::cuboid(0.05, 0.05, 0.05), Transform::from_xyz(0., 0.6, 0.)),
+            (Self::Head, true) => (Collider::cuboid(0.05, 0.05, 0.05), Transform::from_xyz(0., 0.4, 0.)),
+            (Self::Body, false) => (Collider::cylinder(0.115, 0.08), Transform::from_xyz(0., 0.425, 0.)),
+            (Self::Body, true) => (Collider::cylinder(0.09, 0.08), Transform::from_xyz(0., 0.26, 0.)),
+            (Self::Legs, false) => (Collider::cylinder(0.155, 0.08), Transform::from_xyz(0., 0.155, 0.)),
+            (Self::Legs, true) => (Collider::cylinder(0.085, 0.08), Transform::from_xyz(0., 0.085, 0.)),
+        }
+    }
+}
+
+/// resize the hitboxes of the players who just (un)crouched
+pub fn crouch_hitboxes(
+    player_q: Query<(&Player, &Children)>,
+    mut hitbox_q: Query<(&BodyPart, &mut Collider, &mut Transform)>,
+){
+    for (player, children) in player_q.iter(){
+        for &child in children.iter(){
+            let Ok((body_part, mut collider, mut transform)) = hitbox_q.get_mut(child) else {continue};
+            let (new_collider, new_transform) = body_part.shape(player.is_crouching);
+
+            // the hitbox is already in the right position
+            if transform.translation == new_transform.translation {continue}
+
+            *collider = new_collider;
+            *transform = new_transform;
+        }
+    }
+}
@@ -1,45 +1,148 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, render::render_resource::{Extent3d, TextureDimension, TextureFormat}};
 
-use crate::player::{ CurrentPlayer, Player};
+use crate::{player::{ CurrentPlayer, Player}, settings::{Settings, CrosshairStyle}, camera::LookAngles};
 
 pub struct HudPlugin;
 
 impl Plugin for HudPlugin{
     fn build(&self, app: &mut App) {
         app
+        .add_event::<DamageTaken>()
+        .add_event::<HitMarker>()
         .add_systems(Startup, setup_hud)
-        .add_systems(Update, update_hud);
+        .add_systems(Update, (
+            update_hud,
+            update_crosshair,
+            update_reload_bar,
+            spawn_damage_indicators,
+            update_damage_indicators.after(spawn_damage_indicators),
+            spawn_hit_markers,
+            update_hit_markers.after(spawn_hit_markers),
+            update_low_health_effect,
+        ));
     }
 }
 
+// how long (in seconds) the damage indicators and the hit markers stay on the screen
+const DAMAGE_INDICATOR_DURATION: f32 = 1.5;
+const HIT_MARKER_DURATION: f32 = 0.3;
+// distance (px) between the center of the screen and the damage indicators
+const DAMAGE_INDICATOR_RADIUS: f32 = 120.;
+// below this hp, the edges of the screen turn red
+const LOW_HP: f32 = 40.;
+
+/// the current player got hit, `origin` is where the bullet came from
+#[derive(Event)]
+pub struct DamageTaken{
+    pub origin: Vec3,
+    pub damage: f32,
+}
+
+/// a bullet of the current player hit someone
+#[derive(Event)]
+pub struct HitMarker{
+    pub headshot: bool,
+    pub kill: bool,
+}
+
 #[derive(Component)]
 struct HPText;
 #[derive(Component)]
 struct AmmoText;
+#[derive(Component)]
+struct Crosshair;
+#[derive(Component)]
+struct ReloadBar;
+#[derive(Component)]
+struct ReloadBarFill;
+#[derive(Component)]
+struct LowHealthVignette;
+
+/// centered on the screen, turned towards the attacker
+#[derive(Component)]
+struct DamageIndicator{
+    origin: Vec3,
+    age: f32,
+}
+
+#[derive(Component)]
+struct HitMarkerCross{
+    age: f32,
+}
 
 
 
 fn setup_hud(
     assets: Res<AssetServer>,
+    mut images: ResMut<Assets<Image>>,
     mut cmd: Commands,
 ){
-
+    // red edges of the screen when the player is low on health
     cmd.spawn((
         ImageBundle {
-            image: UiImage {
-                texture: assets.load("crosshair.png"),
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.),
+                height: Val::Percent(100.),
                 ..default()
             },
+            image: images.add(vignette_image()).into(),
+            background_color: Color::NONE.into(),
+            ..default()
+        },
+        LowHealthVignette,
+        Name::new("Low Health Vignette")
+    ));
+
+    // reload progress, under the crosshair
+    cmd.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Px(120.),
+                height: Val::Px(6.),
+                left: Val::Percent(50.),
+                top: Val::Percent(56.),
+                margin: UiRect::left(Val::Px(-60.)),
+                ..default()
+            },
+            background_color: Color::rgba(0., 0., 0., 0.5).into(),
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+        ReloadBar,
+        Name::new("Reload Bar")
+    )).with_children(|bar|{
+        bar.spawn((
+            NodeBundle {
+                style: Style {
+                    width: Val::Percent(0.),
+                    height: Val::Percent(100.),
+                    ..default()
+                },
+                background_color: Color::WHITE.into(),
+                ..default()
+            },
+            ReloadBarFill,
+        ));
+    });
+
+    // the crosshair itself is drawn in update_crosshair, depending on the settings
+    cmd.spawn((
+        NodeBundle {
             style: Style {
                 position_type: PositionType::Absolute,
                 width: Val::Px(15.),
                 height: Val::Px(15.),
                 left: Val::Percent(50.),
                 top: Val::Percent(50.),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
                 ..default()
             },
             ..default()
-            },
+        },
+        Crosshair,
         Name::new("Crosshair")
     ));
 
@@ -123,10 +226,251 @@ fn update_hud(
     let Ok(player) = player_q.get_single() else{return};
 
     for mut text in hp_text_q.iter_mut(){
-        text.sections[0].value = player.hp.to_string();
+        text.sections[0].value = format!("{:.0}", player.hp.max(0.).ceil());
     }
 
+    // magazine / reserve of the equipped weapon
     for mut text in ammo_text_q.iter_mut(){
-        text.sections[0].value = format!("{}/30", player.ammos);
+        text.sections[0].value = format!("{}/{}", player.ammos, player.reserve_ammos);
     }
-}
\ No newline at end of file
+}
+
+fn update_reload_bar(
+    mut bar_q: Query<&mut Visibility, With<ReloadBar>>,
+    mut fill_q: Query<&mut Style, With<ReloadBarFill>>,
+    player_q: Query<&Player, With<CurrentPlayer>>,
+){
+    let Ok(mut visibility) = bar_q.get_single_mut() else {return};
+    let Ok(mut fill) = fill_q.get_single_mut() else {return};
+
+    let Some(player) = player_q.get_single().ok().filter(|player| player.is_reloading) else{
+        *visibility = Visibility::Hidden;
+        return;
+    };
+
+    let reload_duration = player.weapon.reload_duration();
+    let progress = 1. - (player.reload_timer / reload_duration).clamp(0., 1.);
+    *visibility = Visibility::Visible;
+    fill.width = Val::Percent(progress * 100.);
+}
+
+fn spawn_damage_indicators(
+    mut cmd: Commands,
+    mut damage_events: EventReader<DamageTaken>,
+){
+    for damage in damage_events.iter(){
+        // the more damage taken, the thicker the bar
+        let thickness = (4. + damage.damage / 10.).clamp(4., 14.);
+        // the pivot is turned towards the attacker, the red bar is above it
+        cmd.spawn((
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    left: Val::Percent(50.),
+                    top: Val::Percent(50.),
+                    ..default()
+                },
+                ..default()
+            },
+            DamageIndicator{ origin: damage.origin, age: 0. },
+            Name::new("Damage Indicator")
+        )).with_children(|pivot|{
+            pivot.spawn(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Px(80.),
+                    height: Val::Px(thickness),
+                    left: Val::Px(-40.),
+                    top: Val::Px(-DAMAGE_INDICATOR_RADIUS),
+                    ..default()
+                },
+                background_color: Color::rgba(0.9, 0., 0., 0.8).into(),
+                ..default()
+            });
+        });
+    }
+}
+
+/// the position and the direction of the current player
+type CurrentPlayerQuery<'w, 's> = Query<'w, 's, (&'static Transform, &'static LookAngles), (With<CurrentPlayer>, Without<DamageIndicator>)>;
+
+fn update_damage_indicators(
+    mut cmd: Commands,
+    mut indicator_q: Query<(Entity, &mut DamageIndicator, &mut Transform, &Children)>,
+    mut color_q: Query<&mut BackgroundColor>,
+    player_q: CurrentPlayerQuery,
+    time: Res<Time>,
+){
+    let player = player_q.get_single().ok();
+
+    for (entity, mut indicator, mut transform, children) in indicator_q.iter_mut(){
+        indicator.age += time.delta_seconds();
+        if indicator.age > DAMAGE_INDICATOR_DURATION{
+            cmd.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        // angle between where the player looks and the attacker, clockwise on the screen
+        if let Some((player_transform, look)) = player{
+            let direction = indicator.origin - player_transform.translation;
+            let forward = Quat::from_rotation_y(look.yaw) * Vec3::Z;
+            let right = Quat::from_rotation_y(look.yaw) * Vec3::NEG_X;
+            let angle = direction.dot(right).atan2(direction.dot(forward));
+            transform.rotation = Quat::from_rotation_z(angle);
+        }
+
+        let alpha = 0.8 * (1. - indicator.age / DAMAGE_INDICATOR_DURATION);
+        for &child in children.iter(){
+            let Ok(mut color) = color_q.get_mut(child) else {continue};
+            color.0.set_a(alpha);
+        }
+    }
+}
+
+fn spawn_hit_markers(
+    mut cmd: Commands,
+    mut hit_markers: EventReader<HitMarker>,
+    marker_q: Query<Entity, With<HitMarkerCross>>,
+){
+    // only the last hit of the frame is shown
+    let Some(hit) = hit_markers.iter().last() else {return};
+    for entity in marker_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+
+    // a cross around the crosshair, red and bigger for the headshots
+    let (color, length) = match hit{
+        HitMarker{ headshot: true, .. } => (Color::RED, 16.),
+        HitMarker{ kill: true, .. } => (Color::ORANGE_RED, 12.),
+        _ => (Color::WHITE, 12.),
+    };
+
+    cmd.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Percent(50.),
+                top: Val::Percent(50.),
+                ..default()
+            },
+            ..default()
+        },
+        HitMarkerCross{ age: 0. },
+        Name::new("Hit Marker")
+    )).with_children(|cross|{
+        for angle in [45_f32, 135., 225., 315.]{
+            let direction = Vec2::from_angle(angle.to_radians());
+            let center = direction * (6. + length / 2.);
+            cross.spawn(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Px(length),
+                    height: Val::Px(2.),
+                    left: Val::Px(center.x - length / 2.),
+                    top: Val::Px(center.y - 1.),
+                    ..default()
+                },
+                transform: Transform::from_rotation(Quat::from_rotation_z(angle.to_radians())),
+                background_color: color.into(),
+                ..default()
+            });
+        }
+    });
+}
+
+fn update_hit_markers(
+    mut cmd: Commands,
+    mut marker_q: Query<(Entity, &mut HitMarkerCross, &Children)>,
+    mut color_q: Query<&mut BackgroundColor>,
+    time: Res<Time>,
+){
+    for (entity, mut marker, children) in marker_q.iter_mut(){
+        marker.age += time.delta_seconds();
+        if marker.age > HIT_MARKER_DURATION{
+            cmd.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        let alpha = 1. - marker.age / HIT_MARKER_DURATION;
+        for &child in children.iter(){
+            let Ok(mut color) = color_q.get_mut(child) else {continue};
+            color.0.set_a(alpha);
+        }
+    }
+}
+
+/// the edges of the screen pulse in red when the player is low on health
+fn update_low_health_effect(
+    mut vignette_q: Query<&mut BackgroundColor, With<LowHealthVignette>>,
+    player_q: Query<&Player, With<CurrentPlayer>>,
+    time: Res<Time>,
+){
+    let Ok(mut color) = vignette_q.get_single_mut() else {return};
+
+    let intensity = match player_q.get_single(){
+        Ok(player) if player.hp > 0. => ((LOW_HP - player.hp) / LOW_HP).clamp(0., 1.),
+        _ => 0.,
+    };
+    // the lower the health, the faster the pulse
+    let pulse = 0.8 + 0.2 * (time.elapsed_seconds() * (2. + 4. * intensity)).sin();
+    color.0 = Color::rgba(1., 1., 1., intensity * pulse);
+}
+
+/// transparent in the center, red on the edges
+fn vignette_image() -> Image{
+    let size = 128;
+    let mut image = Image::new_fill(
+        Extent3d{ width: size, height: size, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+
+    for y in 0..size{
+        for x in 0..size{
+            let position = (Vec2::new(x as f32, y as f32) + 0.5) / size as f32 * 2. - 1.;
+            let alpha = ((position.length() - 0.6) / 0.6).clamp(0., 1.).powi(2);
+            let i = ((y * size + x) * 4) as usize;
+            image.data[i..i + 4].copy_from_slice(&[180, 0, 0, (alpha * 255.) as u8]);
+        }
+    }
+    image
+}
+fn update_crosshair(
+    mut cmd: Commands,
+    crosshair_q: Query<Entity, With<Crosshair>>,
+    new_crosshair_q: Query<(), Added<Crosshair>>,
+    settings: Res<Settings>,
+    assets: Res<AssetServer>,
+){
+    if !settings.is_changed() && new_crosshair_q.is_empty() {return}
+    let Ok(crosshair) = crosshair_q.get_single() else {return};
+
+    let line = |width: f32, height: f32| NodeBundle{
+        style: Style{
+            position_type: PositionType::Absolute,
+            width: Val::Px(width),
+            height: Val::Px(height),
+            ..default()
+        },
+        background_color: Color::WHITE.into(),
+        ..default()
+    };
+
+    cmd.entity(crosshair).despawn_descendants();
+    cmd.entity(crosshair).with_children(|crosshair|{
+        match settings.crosshair{
+            CrosshairStyle::Image => {
+                crosshair.spawn(get_image_bundle("crosshair.png", 15., 15., &assets));
+            },
+            CrosshairStyle::Dot => {
+                crosshair.spawn(line(3., 3.));
+            },
+            CrosshairStyle::Cross => {
+                crosshair.spawn(line(15., 2.));
+                crosshair.spawn(line(2., 15.));
+            },
+            CrosshairStyle::Hidden => {}
+        }
+    });
+}
@@ -0,0 +1,355 @@
+//! The kill feed : the last kills and connections, in the top right corner under the minimap.
+//! The multi-kills of the current player and the match events are announced in the center of the screen.
+use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    player::{CurrentPlayer, PlayerName},
+    team::Team,
+    weapons::WeaponKind,
+    network::mods::Lobby,
+    game::{AppState, GameMode},
+};
+
+//////////////////////////// PLUGIN, COMPONENTS, CONSTS ///////////////////////////////
+
+pub struct KillFeedPlugin;
+impl Plugin for KillFeedPlugin{
+
+    fn build(&self, app: &mut App) {
+        app.add_event::<Kill>();
+        app.add_event::<Notice>();
+        app.add_systems(OnExit(AppState::MainMenu), spawn_kill_feed);
+        app.add_systems(OnEnter(AppState::MainMenu), despawn_kill_feed);
+        app.add_systems(Update,(
+            add_kills,
+            add_notices,
+            fade_feed_entries.after(add_kills).after(add_notices),
+            fade_announcements.after(add_kills).after(add_notices),
+        ));
+    }
+}
+
+// the feed is under the minimap (see minimap.rs)
+const KILL_FEED_TOP: f32 = 240.;
+const MAX_FEED_ENTRIES: usize = 5;
+// how long (in seconds) an entry stays in the feed, it fades out at the end
+const FEED_ENTRY_DURATION: f32 = 6.;
+const ANNOUNCEMENT_DURATION: f32 = 2.5;
+const FADE_DURATION: f32 = 1.;
+// the kills of the current player made in this time (in seconds) after the previous one count in a multi-kill
+const MULTI_KILL_WINDOW: f32 = 4.;
+
+const FEED_FONT_SIZE: f32 = 18.;
+const ANNOUNCEMENT_FONT_SIZE: f32 = 48.;
+const NOTICE_COLOR: Color = Color::rgb(0.7, 0.7, 0.7);
+const ANNOUNCEMENT_COLOR: Color = Color::rgb(1., 0.8, 0.2);
+const FEED_BACKGROUND_ALPHA: f32 = 0.4;
+
+/// a player killed another one, sent by the server to every client
+#[derive(Event, Debug, Clone, Copy)]
+pub struct Kill{
+    pub killer: u64,
+    pub victim: u64,
+    pub weapon: WeaponKind,
+    pub headshot: bool,
+    /// the bullet went through a wall
+    pub wallbang: bool,
+}
+
+/// connections and match events, sent by the server to every client
+#[derive(Event, Debug, Clone, Serialize, Deserialize)]
+pub enum Notice{
+    Joined{ name: String },
+    Left{ name: String },
+    /// joined as a spectator
+    Spectating{ name: String },
+    /// the first kill of the match
+    FirstBlood{ name: String },
+    /// the server restarted the match (see network/console.rs)
+    MatchRestarted,
+    MapChanged{ name: String },
+    ModeChanged{ mode: GameMode },
+}
+
+#[derive(Component)]
+struct KillFeed;
+
+#[derive(Component)]
+struct FeedEntry{
+    age: f32,
+}
+
+#[derive(Component)]
+struct Announcement{
+    age: f32,
+}
+
+/// kills of the current player in a row
+#[derive(Default)]
+struct MultiKill{
+    count: u32,
+    last_kill: f32,
+}
+
+//////////////////////////// SETUP ///////////////////////////////
+
+fn spawn_kill_feed(mut cmd: Commands){
+    cmd.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                right: Val::Px(10.),
+                top: Val::Px(KILL_FEED_TOP),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::FlexEnd,
+                row_gap: Val::Px(4.),
+                ..default()
+            },
+            ..default()
+        },
+        KillFeed,
+        Name::new("Kill Feed")
+    ));
+}
+
+/// the kill feed and the announcement
+type FeedEntitiesQuery<'w, 's> = Query<'w, 's, Entity, Or<(With<KillFeed>, With<Announcement>)>>;
+
+fn despawn_kill_feed(
+    mut cmd: Commands,
+    feed_q: FeedEntitiesQuery,
+){
+    for entity in feed_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+}
+
+//////////////////////////// FEED ///////////////////////////////
+
+#[allow(clippy::too_many_arguments)]
+fn add_kills(
+    mut cmd: Commands,
+    mut kills: EventReader<Kill>,
+    feed_q: Query<(Entity, Option<&Children>), With<KillFeed>>,
+    announcement_q: Query<Entity, With<Announcement>>,
+    names_q: Query<(&PlayerName, Option<&Team>)>,
+    current_player_q: Query<&CurrentPlayer>,
+    lobby: Res<Lobby>,
+    time: Res<Time>,
+    mut multi_kill: Local<MultiKill>,
+){
+    let Ok((feed, entries)) = feed_q.get_single() else {return};
+    let mut entries_count = entries.map_or(0, |entries| entries.len());
+    let current_player = current_player_q.get_single().ok().map(|player| player.0);
+
+    // the name of a player, in the color of his team
+    let name_section = |id: u64| {
+        let (name, team) = lobby.players.get(&id)
+            .and_then(|&entity| names_q.get(entity).ok())
+            .map_or((id.to_string(), None), |(PlayerName(name), team)| (name.clone(), team.copied()));
+
+        // the current player is always in white
+        let color = match team{
+            Some(team) if current_player != Some(id) => team.color(),
+            _ => Color::WHITE,
+        };
+        TextSection::new(name, TextStyle{ font_size: FEED_FONT_SIZE, color, ..default() })
+    };
+
+    for kill in kills.iter(){
+        let mut weapon = format!("  [{:?}", kill.weapon);
+        if kill.headshot {weapon.push_str(" headshot")}
+        if kill.wallbang {weapon.push_str(" wallbang")}
+        weapon.push_str("]  ");
+
+        let sections = vec![
+            name_section(kill.killer),
+            TextSection::new(weapon, TextStyle{ font_size: FEED_FONT_SIZE, color: NOTICE_COLOR, ..default() }),
+            name_section(kill.victim),
+        ];
+        let highlighted = current_player == Some(kill.killer) || current_player == Some(kill.victim);
+        spawn_feed_entry(&mut cmd, feed, entries, &mut entries_count, sections, highlighted);
+
+        // multi-kills of the current player (suicides don't count)
+        if current_player != Some(kill.killer) || kill.killer == kill.victim {continue}
+
+        let now = time.elapsed_seconds();
+        if now - multi_kill.last_kill > MULTI_KILL_WINDOW{
+            multi_kill.count = 0;
+        }
+        multi_kill.count += 1;
+        multi_kill.last_kill = now;
+
+        let text = match multi_kill.count{
+            0 | 1 => continue,
+            2 => "Double kill",
+            3 => "Triple kill",
+            4 => "Quad kill",
+            _ => "Rampage",
+        };
+        spawn_announcement(&mut cmd, &announcement_q, text.to_string());
+    }
+}
+
+fn add_notices(
+    mut cmd: Commands,
+    mut notices: EventReader<Notice>,
+    feed_q: Query<(Entity, Option<&Children>), With<KillFeed>>,
+    announcement_q: Query<Entity, With<Announcement>>,
+){
+    let Ok((feed, entries)) = feed_q.get_single() else {return};
+    let mut entries_count = entries.map_or(0, |entries| entries.len());
+
+    for notice in notices.iter(){
+        let text = match notice{
+            Notice::Joined { name } => format!("{} joined the game", name),
+            Notice::Left { name } => format!("{} left the game", name),
+            Notice::Spectating { name } => format!("{} is spectating", name),
+            Notice::FirstBlood { name } => {
+                spawn_announcement(&mut cmd, &announcement_q, format!("First blood : {}", name));
+                continue;
+            }
+            Notice::MatchRestarted => {
+                spawn_announcement(&mut cmd, &announcement_q, "Match restarted".to_string());
+                continue;
+            }
+            Notice::MapChanged { name } => {
+                spawn_announcement(&mut cmd, &announcement_q, format!("Map : {}", name));
+                continue;
+            }
+            Notice::ModeChanged { mode } => {
+                let mode = match mode{
+                    GameMode::Deathmatch => "Deathmatch",
+                    GameMode::TeamDeathmatch => "Team deathmatch",
+                };
+                spawn_announcement(&mut cmd, &announcement_q, format!("Mode : {}", mode));
+                continue;
+            }
+        };
+
+        let sections = vec![TextSection::new(text, TextStyle{ font_size: FEED_FONT_SIZE, color: NOTICE_COLOR, ..default() })];
+        spawn_feed_entry(&mut cmd, feed, entries, &mut entries_count, sections, false);
+    }
+}
+
+/// add a line at the bottom of the feed, the oldest lines are removed when the feed is full.
+/// not a bevy system
+fn spawn_feed_entry(
+    cmd: &mut Commands,
+    feed: Entity,
+    entries: Option<&Children>,
+    entries_count: &mut usize,
+    sections: Vec<TextSection>,
+    highlighted: bool,
+){
+    // the children of the feed are sorted from the oldest to the newest
+    if *entries_count >= MAX_FEED_ENTRIES{
+        if let Some(&oldest) = entries.and_then(|entries| entries.get(*entries_count - MAX_FEED_ENTRIES)){
+            cmd.entity(oldest).despawn_recursive();
+        }
+    }
+    *entries_count += 1;
+
+    // the kills involving the current player have a red background
+    let background = if highlighted {Color::rgba(0.6, 0.1, 0.1, FEED_BACKGROUND_ALPHA)} else {Color::rgba(0., 0., 0., FEED_BACKGROUND_ALPHA)};
+
+    let entry = cmd.spawn((
+        NodeBundle {
+            style: Style {
+                padding: UiRect::axes(Val::Px(8.), Val::Px(3.)),
+                ..default()
+            },
+            background_color: background.into(),
+            ..default()
+        },
+        FeedEntry{ age: 0. },
+        Name::new("Kill Feed Entry")
+    )).with_children(|entry|{
+        entry.spawn(TextBundle::from_sections(sections));
+    }).id();
+
+    cmd.entity(feed).add_child(entry);
+}
+
+/// replace the current announcement.
+/// not a bevy system
+fn spawn_announcement(cmd: &mut Commands, announcement_q: &Query<Entity, With<Announcement>>, text: String){
+    for entity in announcement_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+
+    cmd.spawn((
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.),
+                top: Val::Percent(25.),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        },
+        Announcement{ age: 0. },
+        Name::new("Announcement")
+    )).with_children(|container|{
+        container.spawn(TextBundle::from_section(
+            text,
+            TextStyle{ font_size: ANNOUNCEMENT_FONT_SIZE, color: ANNOUNCEMENT_COLOR, ..default() }
+        ));
+    });
+}
+
+//////////////////////////// FADING ///////////////////////////////
+
+/// opacity at the given age, the element fades out at the end of its life
+fn fade_alpha(age: f32, duration: f32) -> f32{
+    ((duration - age) / FADE_DURATION).clamp(0., 1.)
+}
+
+fn fade_feed_entries(
+    mut cmd: Commands,
+    mut entry_q: Query<(Entity, &mut FeedEntry, &mut BackgroundColor, &Children)>,
+    mut text_q: Query<&mut Text>,
+    time: Res<Time>,
+){
+    for (entity, mut entry, mut background, children) in entry_q.iter_mut(){
+        entry.age += time.delta_seconds();
+        if entry.age > FEED_ENTRY_DURATION{
+            cmd.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        let alpha = fade_alpha(entry.age, FEED_ENTRY_DURATION);
+        background.0.set_a(FEED_BACKGROUND_ALPHA * alpha);
+        for &child in children.iter(){
+            let Ok(mut text) = text_q.get_mut(child) else {continue};
+            for section in text.sections.iter_mut(){
+                section.style.color.set_a(alpha);
+            }
+        }
+    }
+}
+
+fn fade_announcements(
+    mut cmd: Commands,
+    mut announcement_q: Query<(Entity, &mut Announcement, &Children)>,
+    mut text_q: Query<&mut Text>,
+    time: Res<Time>,
+){
+    for (entity, mut announcement, children) in announcement_q.iter_mut(){
+        announcement.age += time.delta_seconds();
+        if announcement.age > ANNOUNCEMENT_DURATION{
+            cmd.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        let alpha = fade_alpha(announcement.age, ANNOUNCEMENT_DURATION);
+        for &child in children.iter(){
+            let Ok(mut text) = text_q.get_mut(child) else {continue};
+            for section in text.sections.iter_mut(){
+                section.style.color.set_a(alpha);
+            }
+        }
+    }
+}
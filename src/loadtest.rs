@@ -0,0 +1,357 @@
+//! Headless load test : one process opens many connections to a server, without window
+//! nor rendering. Each simulated client sends a stream of inputs and decodes the server's
+//! messages and snapshots. The connections, the quality of the network and the tick rate
+//! of the server (one snapshot per server frame, see server_sync_players) are reported every second.
+use std::{net::SocketAddr, thread, time::{Duration, Instant}};
+use bevy::prelude::Vec2;
+use rand::Rng;
+
+use crate::{
+    player::{PlayerInput, PlayerState},
+    network::mods::{
+        HashMap, RenetClient, NetcodeClientTransport, DefaultChannel, ServerMessages, GAME_PORT, new_renet_client
+    },
+};
+
+//////////////////////////// CONFIG ///////////////////////////////
+
+const DEFAULT_CLIENTS: usize = 32;
+const DEFAULT_DURATION: u64 = 60;
+// the simulated clients run at 60 fps, like a real client
+const FRAME_DURATION: Duration = Duration::from_micros(16_667);
+// the clients not connected after this time count as failed
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// a random client changes its input after this time (in seconds)
+const RANDOM_INPUT_DURATION: (f32, f32) = (0.5, 2.);
+// turn speed (radians per second) of the scripted inputs
+const TURN_SPEED: f32 = 1.5;
+
+/// what the simulated players do
+#[derive(Debug, Clone, Copy)]
+pub enum InputScript{
+    /// random moves, turns and shots
+    Random,
+    /// walk in circles
+    Circle,
+    /// no input, only the snapshots are received
+    Idle,
+}
+
+#[derive(Debug)]
+pub struct LoadTest{
+    server_addr: SocketAddr,
+    clients: usize,
+    duration: Duration,
+    script: InputScript,
+}
+
+impl LoadTest{
+    /// `loadtest <ip[:port]> [clients] [seconds] [random|circle|idle]`
+    pub fn from_args(args: &[String]) -> Result<Self, String>{
+        let usage = "usage: loadtest <ip[:port]> [clients] [seconds] [random|circle|idle]";
+        let address = args.first().ok_or(usage)?;
+        let server_addr = address.parse::<SocketAddr>()
+            .or_else(|_| address.parse().map(|ip| SocketAddr::new(ip, GAME_PORT)))
+            .map_err(|_| format!("invalid address: {}\n{}", address, usage))?;
+
+        let clients = match args.get(1){
+            Some(clients) => clients.parse().map_err(|_| format!("invalid number of clients: {}\n{}", clients, usage))?,
+            None => DEFAULT_CLIENTS,
+        };
+        let seconds = match args.get(2){
+            Some(seconds) => seconds.parse().map_err(|_| format!("invalid duration: {}\n{}", seconds, usage))?,
+            None => DEFAULT_DURATION,
+        };
+        let script = match args.get(3).map(String::as_str){
+            None | Some("random") => InputScript::Random,
+            Some("circle") => InputScript::Circle,
+            Some("idle") => InputScript::Idle,
+            Some(script) => return Err(format!("unknown script: {}\n{}", script, usage)),
+        };
+
+        Ok(Self{ server_addr, clients, duration: Duration::from_secs(seconds), script })
+    }
+}
+
+//////////////////////////// SIMULATED CLIENT ///////////////////////////////
+
+struct SimulatedClient{
+    client: RenetClient,
+    transport: NetcodeClientTransport,
+    /// time to connect to the server
+    connected_after: Option<Duration>,
+    /// why the connection failed or was lost
+    error: Option<String>,
+    input: PlayerInput,
+    input_timer: f32,
+    /// radians per second
+    turn_speed: f32,
+    /// received since the last report
+    stats: ClientStats,
+}
+
+#[derive(Default)]
+struct ClientStats{
+    snapshots: u32,
+    /// time (in seconds) between two snapshots
+    snapshot_intervals: Vec<f32>,
+    last_snapshot: Option<Instant>,
+    /// players in the last snapshot
+    players: usize,
+    decode_errors: u32,
+}
+
+impl SimulatedClient{
+    fn is_connected(&self) -> bool{
+        self.connected_after.is_some() && self.error.is_none() && self.transport.is_connected()
+    }
+
+    /// update the connection, read the messages and send the input of this frame
+    fn update(&mut self, delta: Duration, started: Instant, script: InputScript){
+        if self.error.is_some() {return}
+
+        self.client.update(delta);
+        if let Err(e) = self.transport.update(delta, &mut self.client){
+            self.error = Some(e.to_string());
+            return;
+        }
+
+        if !self.transport.is_connected(){
+            if self.connected_after.is_some(){
+                self.error = Some("disconnected".to_string());
+            }else if started.elapsed() > CONNECTION_TIMEOUT{
+                self.error = Some("connection timeout".to_string());
+            }
+            return;
+        }
+        self.connected_after.get_or_insert_with(|| started.elapsed());
+
+        while let Some(message) = self.client.receive_message(DefaultChannel::ReliableOrdered){
+            if bincode::deserialize::<ServerMessages>(&message).is_err(){
+                self.stats.decode_errors += 1;
+            }
+        }
+        while let Some(message) = self.client.receive_message(DefaultChannel::Unreliable){
+            let Ok(players) = bincode::deserialize::<HashMap<u64, PlayerState>>(&message) else {
+                self.stats.decode_errors += 1;
+                continue;
+            };
+            let now = Instant::now();
+            if let Some(last) = self.stats.last_snapshot.replace(now){
+                self.stats.snapshot_intervals.push((now - last).as_secs_f32());
+            }
+            self.stats.snapshots += 1;
+            self.stats.players = players.len();
+        }
+
+        self.script_input(delta.as_secs_f32(), script);
+        let message = bincode::serialize(&self.input).unwrap();
+        self.client.send_message(DefaultChannel::ReliableOrdered, message);
+
+        if let Err(e) = self.transport.send_packets(&mut self.client){
+            self.error = Some(e.to_string());
+        }
+    }
+
+    fn script_input(&mut self, delta: f32, script: InputScript){
+        match script{
+            InputScript::Idle => (),
+            InputScript::Circle => {
+                self.input.up = true;
+                self.turn_speed = TURN_SPEED;
+            }
+            InputScript::Random => {
+                let mut rng = rand::thread_rng();
+                self.input_timer -= delta;
+                let fire_was_pressed = self.input.left_click;
+
+                if self.input_timer <= 0.{
+                    self.input_timer = rng.gen_range(RANDOM_INPUT_DURATION.0..RANDOM_INPUT_DURATION.1);
+                    self.turn_speed = rng.gen_range(-TURN_SPEED..TURN_SPEED);
+                    self.input = PlayerInput{
+                        up: rng.gen_bool(0.6),
+                        down: rng.gen_bool(0.1),
+                        left: rng.gen_bool(0.2),
+                        right: rng.gen_bool(0.2),
+                        sprint: rng.gen_bool(0.3),
+                        crouch: rng.gen_bool(0.1),
+                        jump: rng.gen_bool(0.1),
+                        reload: rng.gen_bool(0.1),
+                        left_click: rng.gen_bool(0.3),
+                        ..Default::default()
+                    };
+                }
+                self.input.left_click_just_pressed = self.input.left_click && !fire_was_pressed;
+            }
+        }
+        // the mouse of the input is the rotation of this frame (see player_input)
+        self.input.mouse = Vec2::new(self.turn_speed * delta, 0.);
+    }
+}
+
+//////////////////////////// REPORT ///////////////////////////////
+
+/// the averages of one report
+#[derive(Default)]
+struct Report{
+    connected: usize,
+    failed: usize,
+    /// in milliseconds
+    rtt: f64,
+    max_rtt: f64,
+    /// between 0 and 1
+    packet_loss: f64,
+    /// bytes per second, every client together
+    bytes_in: f64,
+    bytes_out: f64,
+    /// snapshots per second received by a client, the server's tick rate
+    snapshot_rate: f32,
+    /// standard deviation (in milliseconds) of the time between two snapshots
+    snapshot_jitter: f32,
+    players: usize,
+    decode_errors: u32,
+}
+
+impl Report{
+    fn new(clients: &mut [SimulatedClient], interval: f32) -> Self{
+        let mut report = Report{
+            failed: clients.iter().filter(|client| client.error.is_some()).count(),
+            ..Default::default()
+        };
+        let mut intervals = vec![];
+
+        for client in clients.iter_mut(){
+            let stats = std::mem::take(&mut client.stats);
+            // the next interval starts from the last snapshot
+            client.stats.last_snapshot = stats.last_snapshot;
+            report.decode_errors += stats.decode_errors;
+            if !client.is_connected() {continue}
+
+            let info = client.client.network_info();
+            // the rtt of renet is in seconds
+            let rtt = info.rtt * 1000.;
+            report.connected += 1;
+            report.rtt += rtt;
+            report.max_rtt = report.max_rtt.max(rtt);
+            report.packet_loss += info.packet_loss;
+            report.bytes_in += info.bytes_received_per_second;
+            report.bytes_out += info.bytes_sent_per_second;
+            report.snapshot_rate += stats.snapshots as f32 / interval;
+            report.players = report.players.max(stats.players);
+            intervals.extend(stats.snapshot_intervals);
+        }
+
+        if report.connected > 0{
+            report.rtt /= report.connected as f64;
+            report.packet_loss /= report.connected as f64;
+            report.snapshot_rate /= report.connected as f32;
+        }
+        if !intervals.is_empty(){
+            let mean = intervals.iter().sum::<f32>() / intervals.len() as f32;
+            let variance = intervals.iter().map(|interval| (interval - mean).powi(2)).sum::<f32>() / intervals.len() as f32;
+            report.snapshot_jitter = variance.sqrt() * 1000.;
+        }
+        report
+    }
+
+    fn print(&self, elapsed: Duration, clients: usize){
+        println!(
+            "[{:>3}s] connected {}/{} ({} failed) | rtt {:.1} ms (max {:.1}) | loss {:.1}% | in {:.1} KB/s, out {:.1} KB/s | tick {:.1}/s, jitter {:.1} ms | {} players{}",
+            elapsed.as_secs(), self.connected, clients, self.failed,
+            self.rtt, self.max_rtt, self.packet_loss * 100.,
+            self.bytes_in / 1024., self.bytes_out / 1024.,
+            self.snapshot_rate, self.snapshot_jitter, self.players,
+            if self.decode_errors > 0 {format!(" | {} decode errors", self.decode_errors)} else {String::new()}
+        );
+    }
+}
+
+//////////////////////////// RUN ///////////////////////////////
+
+pub fn run(test: LoadTest){
+    println!("load test: {} clients on {} for {}s ({:?} inputs)", test.clients, test.server_addr, test.duration.as_secs(), test.script);
+
+    let mut clients = vec![];
+    for index in 0..test.clients{
+        match new_renet_client(test.server_addr, &format!("load-{}", index), false){
+            Ok((client, _, transport)) => clients.push(SimulatedClient{
+                client,
+                transport,
+                connected_after: None,
+                error: None,
+                input: PlayerInput::default(),
+                input_timer: 0.,
+                turn_speed: 0.,
+                stats: ClientStats::default(),
+            }),
+            Err(e) => eprintln!("ERR: cannot create the client {}: {}", index, e),
+        }
+    }
+
+    let started = Instant::now();
+    let mut last_frame = started;
+    let mut last_report = started;
+    let mut reports: Vec<Report> = vec![];
+
+    while started.elapsed() < test.duration{
+        let now = Instant::now();
+        let delta = now - last_frame;
+        last_frame = now;
+
+        for client in clients.iter_mut(){
+            client.update(delta, started, test.script);
+        }
+
+        if last_report.elapsed() >= REPORT_INTERVAL{
+            let report = Report::new(&mut clients, last_report.elapsed().as_secs_f32());
+            report.print(started.elapsed(), test.clients);
+            reports.push(report);
+            last_report = Instant::now();
+        }
+
+        thread::sleep(FRAME_DURATION.saturating_sub(now.elapsed()));
+    }
+
+    for client in clients.iter_mut(){
+        if client.transport.is_connected(){
+            client.transport.disconnect();
+        }
+    }
+    print_summary(&clients, &reports, test.clients);
+}
+
+/// the connections, and the averages of the reports once every client connected
+fn print_summary(clients: &[SimulatedClient], reports: &[Report], total: usize){
+    let connected: Vec<Duration> = clients.iter().filter_map(|client| client.connected_after).collect();
+    println!("\n////// load test summary");
+    println!("connections: {}/{} succeeded", connected.len(), total);
+    if !connected.is_empty(){
+        let mean = connected.iter().sum::<Duration>() / connected.len() as u32;
+        let max = connected.iter().max().copied().unwrap_or_default();
+        println!("connection time: {:.0} ms (max {:.0} ms)", mean.as_secs_f32() * 1000., max.as_secs_f32() * 1000.);
+    }
+
+    let mut errors: HashMap<&str, usize> = HashMap::new();
+    for error in clients.iter().filter_map(|client| client.error.as_deref()){
+        *errors.entry(error).or_default() += 1;
+    }
+    for (error, count) in errors{
+        println!("errors: {} x {}", count, error);
+    }
+
+    let steady: Vec<&Report> = reports.iter().filter(|report| report.connected > 0).collect();
+    if steady.is_empty() {return}
+    let count = steady.len() as f64;
+    let average = |value: fn(&Report) -> f64| steady.iter().map(|report| value(report)).sum::<f64>() / count;
+
+    println!("rtt: {:.1} ms, max {:.1} ms", average(|report| report.rtt), steady.iter().map(|report| report.max_rtt).fold(0., f64::max));
+    println!("packet loss: {:.2}%", average(|report| report.packet_loss) * 100.);
+    println!("bandwidth: in {:.1} KB/s, out {:.1} KB/s", average(|report| report.bytes_in) / 1024., average(|report| report.bytes_out) / 1024.);
+    println!(
+        "server tick: {:.1}/s (min {:.1}/s), jitter {:.1} ms",
+        average(|report| report.snapshot_rate as f64),
+        steady.iter().map(|report| report.snapshot_rate).fold(f32::INFINITY, f32::min),
+        average(|report| report.snapshot_jitter as f64),
+    );
+}
@@ -1,16 +1,16 @@
-use game::toggle_sound;
 use hud::HudPlugin;
-use network::mods::{ClientPlugin, ServerPlugin};
+use network::mods::{ClientPlugin, ServerPlugin, NetSimConfig, RCON_PUBLIC_FLAG};
 use player::PlayerPlugin;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use bevy_rapier3d::prelude::*;
 use sounds::volume_system;
 use world::WorldPlugin;
-use std::env;
-pub use bevy::prelude::*;
+use std::{env, path::Path};
+pub use bevy::{prelude::*, audio::AddAudioSource};
 
 
 mod player;
+mod team;
 mod camera;
 mod world;
 mod weapons;
@@ -22,22 +22,49 @@ mod animation;
 mod hud;
 mod sounds;
 mod minimap;
+mod killfeed;
+mod death;
+mod spectator;
+mod chat;
+mod bots;
 mod bullet_tracer;
+mod pickup;
+mod movement;
+mod controls;
+mod config;
+mod menu;
+mod settings;
+mod sound_propagation;
+mod loadtest;
+mod netgraph;
+mod demo;
 
 mod network{
     mod client;
     mod server;
+    mod discovery;
+    mod console;
+    mod netsim;
     pub mod mods;
 }
 
 ////////// USAGE ////////////
-// "cargo run" => run the game
+// "cargo run" => run the game, join a server from the main menu
+// "cargo run <ip>" => same, with the address already filled in
 // "cargo run server" => host a game
+// "cargo run server --rcon-public" => host a game, rcon accepts the commands of the other machines
+// "cargo run server --latency 100 --jitter 20 --loss 0.05 --duplicate 0.01 --reorder 0.02" => host a game behind a simulated bad network
+// "cargo run loadtest <ip> [clients] [seconds] [random|circle|idle]" => connect many headless clients to a server
+// "cargo run replay demos/<name>.demo" => watch a demo recorded by the server (no server needed)
 // don't forget the --release flag for optimisation in a real game
 
 fn main() {    
     if is_server_mode(){
         run_server_app();
+    }else if is_loadtest_mode(){
+        run_loadtest();
+    }else if is_replay_mode(){
+        run_replay_app();
     }else{
         run_client_app();
     }
@@ -52,12 +79,37 @@ fn is_server_mode() -> bool{
     return false
 }
 
+fn is_loadtest_mode() -> bool{
+    env::args().nth(1).is_some_and(|first_arg| first_arg == "loadtest")
+}
+
+fn is_replay_mode() -> bool{
+    env::args().nth(1).is_some_and(|first_arg| first_arg == "replay")
+}
+
+fn run_loadtest(){
+    let args: Vec<String> = env::args().skip(2).collect();
+    match loadtest::LoadTest::from_args(&args){
+        Ok(test) => loadtest::run(test),
+        Err(e) => eprintln!("ERR: {}", e),
+    }
+}
+
 
 fn run_server_app(){
+    let (rcon_public, args): (Vec<String>, Vec<String>) = env::args().skip(2).partition(|arg| arg == RCON_PUBLIC_FLAG);
+    let netsim = match NetSimConfig::from_args(&args){
+        Ok(netsim) => netsim,
+        Err(e) => {
+            eprintln!("ERR: {}", e);
+            return;
+        }
+    };
+
     App::new()
 
     .add_plugins((
-        ServerPlugin,
+        ServerPlugin{ netsim, rcon_public: !rcon_public.is_empty() },
 
         WorldPlugin,
         camera::CameraPlugin,
@@ -65,14 +117,14 @@ fn run_server_app(){
         RapierPhysicsPlugin::<NoUserData>::default(),
         RapierDebugRenderPlugin::default(),
     ))
-    .add_systems(Update, sounds::handle_sound_emmission)
-
     .run();
 }
 
 
-fn run_client_app(){
-    App::new()
+/// the game without the main menu, shared by the client and the replays
+fn client_app() -> App{
+    let mut app = App::new();
+    app
     .add_plugins((
         ClientPlugin,
         PlayerPlugin,
@@ -80,19 +132,57 @@ fn run_client_app(){
         HudPlugin,
         animation::AnimationPlugin,
         minimap::MinimapPlugin,
+        killfeed::KillFeedPlugin,
+        death::DeathPlugin,
+        spectator::SpectatorPlugin,
+        chat::ChatPlugin,
+        controls::ControlsPlugin,
+        settings::SettingsPlugin,
+        netgraph::NetGraphPlugin,
         RapierPhysicsPlugin::<NoUserData>::default(),
         WorldInspectorPlugin::new()
     ))
+    .add_audio_source::<sounds::MuffledAudio>()
+    .init_resource::<sounds::MuffledSources>()
+    .init_resource::<sounds::SoundCatalog>()
     .add_systems(Startup,(
         sounds::sound_player_setup,
         //minimap::set_minimap,
     ))
     .add_systems(Update, (
         game::toggle_fullscreen,
-        volume_system,
-        toggle_sound,
-    ))
-    
+        sounds::slice_footsteps,
+        sounds::add_muffled_layers,
+        sounds::despawn_orphan_layers,
+        sounds::start_emitted_sounds.after(sounds::add_muffled_layers),
+        volume_system.after(sounds::add_muffled_layers),
+    ));
+
+    app
+}
+
+fn run_client_app(){
+    client_app()
+    .add_plugins(menu::MenuPlugin)
+    .run();
+}
+
+fn run_replay_app(){
+    let Some(path) = env::args().nth(2) else {
+        eprintln!("ERR: usage: cargo run replay <demo file>");
+        return;
+    };
+    let playback = match demo::DemoPlayback::open(Path::new(&path)){
+        Ok(playback) => playback,
+        Err(e) => {
+            eprintln!("ERR: {}", e);
+            return;
+        }
+    };
+
+    client_app()
+    .insert_resource(playback)
+    .add_plugins(demo::ReplayPlugin)
     .run();
 }
 
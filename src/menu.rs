@@ -0,0 +1,540 @@
+//! Main menu (join a server, server browser, settings, quit)
+//! and pause menu (resume, settings, disconnect).
+//! The renet client is created here, when the player joins a server.
+use bevy::{prelude::*, app::AppExit, window::PrimaryWindow};
+use serde::{Serialize, Deserialize};
+use std::{env, net::{SocketAddr, ToSocketAddrs}};
+use local_ip_address::local_ip;
+
+use crate::{
+    config,
+    game::{AppState, resume_state},
+    settings::SettingsMenu,
+    spectator::Spectator,
+    player::PlayerInput,
+    pickup::Pickup,
+    network::mods::{
+        Lobby, RenetClient, NetcodeClientTransport, NetcodeTransportError, ClientId, ServerBrowser, ServerInbox,
+        client_connected, new_renet_client, GAME_PORT, MAX_NAME_LENGTH,
+    },
+};
+
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin{
+    fn build(&self, app: &mut App) {
+        app
+        .insert_resource(Profile::load())
+        .init_resource::<FocusedField>()
+        .init_resource::<MenuStatus>()
+        .add_event::<LeaveServer>()
+
+        .add_systems(OnEnter(AppState::MainMenu), spawn_main_menu)
+        .add_systems(OnExit(AppState::MainMenu), (despawn_menu::<MainMenu>, close_server_browser))
+        .add_systems(OnEnter(AppState::GameMenu), spawn_pause_menu)
+        .add_systems(OnExit(AppState::GameMenu), despawn_menu::<PauseMenu>)
+
+        .add_systems(Update, (
+            button_colors,
+            menu_buttons,
+            (
+                text_input,
+                update_text_fields,
+                update_server_list,
+                wait_for_connection.run_if(client_connected()),
+            ).run_if(in_state(AppState::MainMenu)),
+            update_status_text,
+            handle_transport_errors.run_if(resource_exists::<NetcodeClientTransport>()),
+            leave_server.after(handle_transport_errors).after(menu_buttons),
+        ));
+    }
+}
+
+//////////////////////////// COMPONENTS, RESOURCES ///////////////////////////////
+
+const PROFILE_FILE: &str = "profile.ron";
+
+pub const BUTTON_COLOR: Color = Color::rgb(0.15, 0.15, 0.15);
+pub const HOVERED_BUTTON_COLOR: Color = Color::rgb(0.3, 0.3, 0.3);
+const FOCUSED_FIELD_COLOR: Color = Color::rgb(0.25, 0.25, 0.4);
+
+/// the name and the last server used, saved between sessions
+#[derive(Resource, Serialize, Deserialize)]
+struct Profile{
+    name: String,
+    address: String,
+}
+
+impl Profile{
+    fn load() -> Self{
+        let mut profile = config::load::<Profile>(PROFILE_FILE).unwrap_or(Profile{
+            name: "player".to_string(),
+            address: local_ip().map(|ip| ip.to_string()).unwrap_or_default(),
+        });
+
+        // "cargo run <ip>" still works
+        if let Some(address) = env::args().nth(1){
+            profile.address = address;
+        }
+        profile
+    }
+}
+
+/// sent to leave the server and go back to the main menu
+#[derive(Event)]
+pub struct LeaveServer;
+
+/// message displayed in the main menu (connection errors ...)
+#[derive(Resource, Default)]
+struct MenuStatus(String);
+
+#[derive(Component)]
+struct MainMenu;
+
+#[derive(Component)]
+struct PauseMenu;
+
+#[derive(Component, Clone, Copy)]
+enum MenuButton{
+    Join,
+    Spectate,
+    ServerBrowser,
+    JoinServer(SocketAddr),
+    Settings,
+    Quit,
+    Resume,
+    Disconnect,
+}
+
+#[derive(Component, Clone, Copy, PartialEq)]
+enum TextField{
+    Name,
+    Address,
+}
+
+#[derive(Resource, Default)]
+struct FocusedField(Option<TextField>);
+
+#[derive(Component)]
+struct StatusText;
+
+#[derive(Component)]
+struct ServerList;
+
+//////////////////////////// MENUS ///////////////////////////////
+
+fn spawn_main_menu(
+    mut cmd: Commands,
+    profile: Res<Profile>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+){
+    if let Ok(mut window) = windows.get_single_mut(){
+        window.cursor.visible = true;
+    }
+
+    cmd.spawn((
+        MainMenu,
+        Name::new("Main Menu"),
+        menu_panel()
+    )).with_children(|menu|{
+        menu.spawn(menu_text("SHADOW SHOWDOWN".to_string(), 50.));
+
+        for (field, label, value) in [
+            (TextField::Name, "Name", &profile.name),
+            (TextField::Address, "Address", &profile.address)
+        ]{
+            menu.spawn(row()).with_children(|row|{
+                row.spawn(menu_text(label.to_string(), 20.));
+                row.spawn((field, button_bundle())).with_children(|button|{
+                    button.spawn(menu_text(value.clone(), 20.));
+                });
+            });
+        }
+
+        for (button, label) in [
+            (MenuButton::Join, "Join"),
+            (MenuButton::Spectate, "Spectate"),
+            (MenuButton::ServerBrowser, "Server browser"),
+            (MenuButton::Settings, "Settings"),
+            (MenuButton::Quit, "Quit"),
+        ]{
+            menu.spawn((button, button_bundle())).with_children(|button|{
+                button.spawn(menu_text(label.to_string(), 25.));
+            });
+        }
+
+        menu.spawn((StatusText, menu_text(String::new(), 18.)));
+
+        menu.spawn((
+            ServerList,
+            NodeBundle{
+                style: Style{
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(4.),
+                    ..default()
+                },
+                ..default()
+            }
+        ));
+    });
+}
+
+fn spawn_pause_menu(mut cmd: Commands){
+    cmd.spawn((
+        PauseMenu,
+        Name::new("Pause Menu"),
+        menu_panel()
+    )).with_children(|menu|{
+        menu.spawn(menu_text("PAUSE".to_string(), 50.));
+
+        for (button, label) in [
+            (MenuButton::Resume, "Resume"),
+            (MenuButton::Settings, "Settings"),
+            (MenuButton::Disconnect, "Disconnect"),
+        ]{
+            menu.spawn((button, button_bundle())).with_children(|button|{
+                button.spawn(menu_text(label.to_string(), 25.));
+            });
+        }
+    });
+}
+
+fn despawn_menu<T: Component>(
+    mut cmd: Commands,
+    menu_q: Query<Entity, With<T>>,
+    mut settings_menu: ResMut<SettingsMenu>,
+    mut focused_field: ResMut<FocusedField>,
+){
+    for menu in menu_q.iter(){
+        cmd.entity(menu).despawn_recursive();
+    }
+    settings_menu.0 = false;
+    focused_field.0 = None;
+}
+
+fn close_server_browser(mut browser: ResMut<ServerBrowser>){
+    browser.close();
+}
+
+/// the buttons hovered, pressed or released
+type ButtonQuery<'w, 's> = Query<'w, 's, (&'static Interaction, &'static mut BackgroundColor, Option<&'static TextField>), (Changed<Interaction>, With<Button>)>;
+
+fn button_colors(
+    mut button_q: ButtonQuery,
+    focused_field: Res<FocusedField>,
+){
+    for (interaction, mut color, field) in button_q.iter_mut(){
+        *color = match interaction{
+            Interaction::Hovered | Interaction::Pressed => HOVERED_BUTTON_COLOR.into(),
+            Interaction::None if field.is_some() && field.copied() == focused_field.0 => FOCUSED_FIELD_COLOR.into(),
+            Interaction::None => BUTTON_COLOR.into(),
+        };
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn menu_buttons(
+    mut cmd: Commands,
+    button_q: Query<(&Interaction, &MenuButton), Changed<Interaction>>,
+    field_q: Query<(&Interaction, &TextField), Changed<Interaction>>,
+    mut focused_field: ResMut<FocusedField>,
+    profile: Res<Profile>,
+    mut status: ResMut<MenuStatus>,
+    mut browser: ResMut<ServerBrowser>,
+    mut settings_menu: ResMut<SettingsMenu>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+    mut next_state: ResMut<NextState<AppState>>,
+    spectator: Option<Res<Spectator>>,
+    mut leave_events: EventWriter<LeaveServer>,
+    mut exit: EventWriter<AppExit>,
+){
+    for (interaction, field) in field_q.iter(){
+        if *interaction == Interaction::Pressed{
+            focused_field.0 = Some(*field);
+        }
+    }
+
+    for (interaction, button) in button_q.iter(){
+        if *interaction != Interaction::Pressed {continue}
+
+        match *button{
+            MenuButton::Join | MenuButton::Spectate => {
+                let spectate = matches!(button, MenuButton::Spectate);
+                match parse_address(&profile.address){
+                    Some(server_addr) => join_server(&mut cmd, &mut status, server_addr, &profile, spectate),
+                    None => status.0 = format!("invalid address: {}", profile.address),
+                }
+            },
+            MenuButton::JoinServer(server_addr) => join_server(&mut cmd, &mut status, server_addr, &profile, false),
+            MenuButton::ServerBrowser => {
+                if browser.is_open() {browser.close()} else {browser.open()}
+            },
+            MenuButton::Settings => settings_menu.0 = !settings_menu.0,
+            MenuButton::Quit => exit.send(AppExit),
+            MenuButton::Resume => {
+                if let Ok(mut window) = windows.get_single_mut(){
+                    window.cursor.visible = false;
+                }
+                next_state.set(resume_state(spectator.is_some()));
+            },
+            MenuButton::Disconnect => leave_events.send(LeaveServer),
+        }
+    }
+}
+
+/// type in the focused text field
+fn text_input(
+    mut characters: EventReader<ReceivedCharacter>,
+    keys: Res<Input<KeyCode>>,
+    focused_field: Res<FocusedField>,
+    mut profile: ResMut<Profile>,
+){
+    let Some(field) = focused_field.0 else {
+        characters.clear();
+        return
+    };
+
+    let text = match field{
+        TextField::Name => &mut profile.name,
+        TextField::Address => &mut profile.address,
+    };
+
+    if keys.just_pressed(KeyCode::Back){
+        text.pop();
+    }
+    for character in characters.iter(){
+        if character.char.is_control() {continue}
+        if field == TextField::Name && text.chars().count() >= MAX_NAME_LENGTH {continue}
+        text.push(character.char);
+    }
+}
+
+fn update_text_fields(
+    field_q: Query<(&TextField, &Children)>,
+    mut text_q: Query<&mut Text>,
+    profile: Res<Profile>,
+    focused_field: Res<FocusedField>,
+    mut color_q: Query<(&TextField, &Interaction, &mut BackgroundColor)>,
+){
+    if !profile.is_changed() && !focused_field.is_changed() {return}
+
+    for (field, children) in field_q.iter(){
+        let value = match field{
+            TextField::Name => &profile.name,
+            TextField::Address => &profile.address,
+        };
+        let cursor = if focused_field.0 == Some(*field) {"_"} else {""};
+
+        for &child in children.iter(){
+            let Ok(mut text) = text_q.get_mut(child) else {continue};
+            text.sections[0].value = format!("{}{}", value, cursor);
+        }
+    }
+
+    if focused_field.is_changed(){
+        for (field, interaction, mut color) in color_q.iter_mut(){
+            if *interaction != Interaction::None {continue}
+            *color = if focused_field.0 == Some(*field) {FOCUSED_FIELD_COLOR.into()} else {BUTTON_COLOR.into()};
+        }
+    }
+}
+
+fn update_status_text(
+    status: Res<MenuStatus>,
+    mut text_q: Query<&mut Text, With<StatusText>>,
+){
+    for mut text in text_q.iter_mut(){
+        if text.sections[0].value != status.0{
+            text.sections[0].value = status.0.clone();
+        }
+    }
+}
+
+/// list the servers found by the server browser
+fn update_server_list(
+    mut cmd: Commands,
+    browser: Res<ServerBrowser>,
+    list_q: Query<Entity, With<ServerList>>,
+    // the servers currently displayed, the list is only rebuilt when they change
+    mut displayed: Local<Vec<(SocketAddr, usize)>>,
+    mut was_open: Local<bool>,
+){
+    let Ok(list) = list_q.get_single() else {return};
+
+    let mut servers: Vec<(SocketAddr, usize)> = browser.servers.iter()
+        .map(|(addr, info)| (*addr, info.players))
+        .collect();
+    servers.sort();
+
+    if servers == *displayed && browser.is_open() == *was_open {return}
+    *displayed = servers;
+    *was_open = browser.is_open();
+
+    cmd.entity(list).despawn_descendants();
+    if !browser.is_open() {return}
+
+    cmd.entity(list).with_children(|list|{
+        if browser.servers.is_empty(){
+            list.spawn(menu_text("searching for servers ...".to_string(), 18.));
+        }
+
+        for (addr, _) in displayed.iter(){
+            let info = &browser.servers[addr];
+            list.spawn((MenuButton::JoinServer(*addr), button_bundle())).with_children(|button|{
+                button.spawn(menu_text(format!("{}   {}/{}", addr, info.players, info.max_players), 18.));
+            });
+        }
+    });
+}
+
+//////////////////////////// CONNECTION ///////////////////////////////
+
+/// not a bevy system. Accepts "ip", "ip:port", "host" or "host:port"
+fn parse_address(address: &str) -> Option<SocketAddr>{
+    let address = address.trim();
+    if let Ok(addr) = address.parse() {return Some(addr)}
+    if let Ok(mut addrs) = address.to_socket_addrs(){
+        return addrs.next();
+    }
+    (address, GAME_PORT).to_socket_addrs().ok()?.next()
+}
+
+/// not a bevy system. Create the renet client, the game starts once it is connected
+fn join_server(cmd: &mut Commands, status: &mut MenuStatus, server_addr: SocketAddr, profile: &Profile, spectate: bool){
+    config::save(PROFILE_FILE, profile);
+
+    match new_renet_client(server_addr, &profile.name, spectate){
+        Ok((client, client_id, transport)) => {
+            cmd.insert_resource(client);
+            cmd.insert_resource(client_id);
+            cmd.insert_resource(transport);
+            if spectate{
+                cmd.insert_resource(Spectator);
+            }
+            status.0 = format!("connecting to {} ...", server_addr);
+        },
+        Err(e) => status.0 = format!("cannot connect to {}: {}", server_addr, e),
+    }
+}
+
+fn wait_for_connection(
+    mut status: ResMut<MenuStatus>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+    mut next_state: ResMut<NextState<AppState>>,
+    spectator: Option<Res<Spectator>>,
+){
+    status.0.clear();
+    if let Ok(mut window) = windows.get_single_mut(){
+        window.cursor.visible = false;
+    }
+    next_state.set(resume_state(spectator.is_some()));
+}
+
+/// connection lost, refused, timed out ... back to the main menu
+fn handle_transport_errors(
+    mut errors: EventReader<NetcodeTransportError>,
+    mut status: ResMut<MenuStatus>,
+    mut leave_events: EventWriter<LeaveServer>,
+){
+    // only the first error matters, the client is removed right after
+    if let Some(e) = errors.iter().next(){
+        println!("disconnected: {}", e);
+        status.0 = format!("disconnected: {}", e);
+        leave_events.send(LeaveServer);
+    }
+    errors.clear();
+}
+
+/// remove the client and everything received from the server
+#[allow(clippy::too_many_arguments)]
+fn leave_server(
+    mut cmd: Commands,
+    mut leave_events: EventReader<LeaveServer>,
+    transport: Option<ResMut<NetcodeClientTransport>>,
+    mut lobby: ResMut<Lobby>,
+    pickup_q: Query<Entity, With<Pickup>>,
+    mut player_input: ResMut<PlayerInput>,
+    mut inbox: ResMut<ServerInbox>,
+    state: Res<State<AppState>>,
+    mut next_state: ResMut<NextState<AppState>>,
+){
+    if leave_events.is_empty() {return}
+    leave_events.clear();
+
+    if let Some(mut transport) = transport{
+        if transport.is_connected(){
+            transport.disconnect();
+        }
+    }
+    cmd.remove_resource::<NetcodeClientTransport>();
+    cmd.remove_resource::<RenetClient>();
+    cmd.remove_resource::<ClientId>();
+    cmd.remove_resource::<Spectator>();
+
+    for (_, entity) in lobby.players.drain(){
+        cmd.entity(entity).despawn_recursive();
+    }
+    for entity in pickup_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+    *player_input = PlayerInput::default();
+    *inbox = ServerInbox::default();
+
+    if *state.get() != AppState::MainMenu{
+        next_state.set(AppState::MainMenu);
+    }
+}
+
+//////////////////////////// UI HELPERS ///////////////////////////////
+
+fn menu_panel() -> NodeBundle{
+    NodeBundle{
+        style: Style{
+            position_type: PositionType::Absolute,
+            width: Val::Percent(25.),
+            left: Val::Percent(3.),
+            top: Val::Percent(10.),
+            padding: UiRect::all(Val::Px(20.)),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(10.),
+            ..default()
+        },
+        background_color: BackgroundColor(Color::rgba(0., 0., 0., 0.8)),
+        ..default()
+    }
+}
+
+pub fn row() -> NodeBundle{
+    NodeBundle{
+        style: Style{
+            justify_content: JustifyContent::SpaceBetween,
+            align_items: AlignItems::Center,
+            column_gap: Val::Px(10.),
+            ..default()
+        },
+        ..default()
+    }
+}
+
+pub fn button_bundle() -> ButtonBundle{
+    ButtonBundle{
+        style: Style{
+            min_width: Val::Px(40.),
+            padding: UiRect::axes(Val::Px(10.), Val::Px(2.)),
+            justify_content: JustifyContent::Center,
+            ..default()
+        },
+        background_color: BUTTON_COLOR.into(),
+        ..default()
+    }
+}
+
+pub fn menu_text(content: String, font_size: f32) -> TextBundle{
+    TextBundle::from_section(
+        content,
+        TextStyle{
+            font_size,
+            ..default()
+        }
+    )
+}
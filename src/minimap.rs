@@ -1,140 +1,455 @@
-use bevy::prelude::*;
+//! The map of the maze : a radar in the top right corner, or the whole map on the screen
+//! while `PlayerInput.show_map` is on. Only the tiles explored by the player are drawn (fog of war).
+//! The maze is rasterised in a single image, the player and the markers are drawn on top of it.
+use bevy::{prelude::*, render::{render_resource::{Extent3d, TextureDimension, TextureFormat}, texture::ImageSampler}};
+use std::f32::consts::PI;
 
-use crate::{world::Maze, player::CurrentPlayer};
+use crate::{
+    world::{Maze, WALL_TILE},
+    player::{CurrentPlayer, PlayerId, PlayerInput},
+    team::Team,
+    camera::LookAngles,
+    pickup::Pickup,
+    settings::Settings,
+    game::AppState,
+};
 
-//////////////////////////// PLUGIN, COMPONENTS, EVENTS ///////////////////////////////
+//////////////////////////// PLUGIN, COMPONENTS, CONSTS ///////////////////////////////
 
 pub struct MinimapPlugin;
 impl Plugin for MinimapPlugin{
 
     fn build(&self, app: &mut App) {
-        app.add_event::<ShowMinimapEvent>();
+        app.init_resource::<ExploredTiles>();
+        app.add_systems(OnExit(AppState::MainMenu), spawn_minimap);
+        app.add_systems(OnEnter(AppState::MainMenu), (despawn_minimap, reset_explored_tiles));
         app.add_systems(Update,(
-            toggle_minimap,
-            update_minimap.before(toggle_minimap)
+            explore_tiles,
+            update_map_image.after(explore_tiles),
+            update_minimap_layout,
+            update_markers,
+            update_player_arrow,
         ));
     }
 }
 
+// size (px) of the radar in the corner of the screen, and of a tile in it
+const RADAR_SIZE: f32 = 220.;
+const RADAR_TILE_SIZE: f32 = 14.;
+// space (px) around the map in full screen mode
+const FULL_SCREEN_MARGIN: f32 = 40.;
+// the player discovers the tiles around him, up to this distance (in tiles)
+const EXPLORE_RADIUS: i32 = 2;
+
+const ARROW_SIZE: f32 = 16.;
+const MARKER_SIZE: f32 = 8.;
+const TEAMMATE_COLOR: Color = Color::rgb(0.3, 0.8, 1.);
+
+// pixels of a tile in the map image
+const PIXELS_PER_TILE: u32 = 8;
+const WALL_COLOR: [u8; 4] = [68, 102, 0, 204];
+const PATH_COLOR: [u8; 4] = [0, 176, 60, 128];
+const FOG_COLOR: [u8; 4] = [0, 0, 0, 153];
+
+/// tiles of the maze already seen by the player
+#[derive(Resource, Default)]
+pub struct ExploredTiles(Vec<Vec<bool>>);
+
+impl ExploredTiles{
+    pub fn is_explored(&self, row: usize, col: usize) -> bool{
+        self.0.get(row).and_then(|line| line.get(col)).copied().unwrap_or(false)
+    }
+}
+
+/// background of the minimap, clips the map in radar mode
 #[derive(Component)]
 struct Minimap;
 
+/// centered in the minimap, rotates the map around the player
 #[derive(Component)]
-struct PlayerIndicator;
+struct MapPivot;
 
-#[derive(Event)]
-struct ShowMinimapEvent;
-
-//////////////////////////// FUNCTIONS, SYSTEMS ///////////////////////////////
+/// the image of the whole maze, moved so the player stays at the center of the radar
+#[derive(Component)]
+struct MapLayer;
 
+/// the maze rasterised, see rasterize_maze
+#[derive(Resource)]
+struct MapImage(Handle<Image>);
 
-fn toggle_minimap(
-    key: Res<Input<KeyCode>>,
-    minimap_q : Query<(Entity, With<Minimap>)>,
-    mut cmd: Commands,
-    maze: Res<Maze>,
-){
-    if !key.just_pressed(KeyCode::Tab){
-        return;
-    }
+#[derive(Component)]
+struct PlayerArrow;
 
-    if let Ok(entity) = minimap_q.get_single(){
-        println!("despawn minimap");
-        cmd.entity(entity.0).despawn();
-    }else{
-        minimap_event_listener(&maze, &mut cmd)
-    }
+/// a teammate or an objective shown on the map
+#[derive(Component)]
+struct MinimapMarker{
+    target: Entity,
 }
 
-/// not a bevy system
-fn minimap_event_listener(
-    maze: &Res<Maze>,
-    cmd: &mut Commands, 
-){
-   
-    println!("event trigered");
+//////////////////////////// SPAWN ///////////////////////////////
 
-    let width = maze.map[0].len() as f32 * TILE_SIZE;
-    let height = maze.map.len() as f32 * TILE_SIZE;
+fn spawn_minimap(
+    mut cmd: Commands,
+    maze: Res<Maze>,
+    explored: Res<ExploredTiles>,
+    mut images: ResMut<Assets<Image>>,
+){
+    let rows = maze.map.len() as f32;
+    let cols = maze.map[0].len() as f32;
+    let arrow = images.add(arrow_image());
+    let map = images.add(rasterize_maze(&maze, &explored));
+    cmd.insert_resource(MapImage(map.clone()));
 
     cmd.spawn((
         Minimap,
-        Name::new("minimap"),
-        // minimap container
+        Name::new("Minimap"),
         NodeBundle {
             style: Style {
-                width: Val::Px(width),
-                height: Val::Px(height),
                 position_type: PositionType::Absolute,
+                width: Val::Px(RADAR_SIZE),
+                height: Val::Px(RADAR_SIZE),
                 top: Val::Px(10.),
                 right: Val::Px(10.),
-
-                display: Display::Flex,
-                column_gap: Val::Px(-20.),
+                overflow: Overflow::clip(),
                 ..default()
             },
-            background_color: Color::rgba(0.,0.,0.,0.).into(),
+            background_color: Color::rgba(0., 0., 0., 0.4).into(),
             ..default()
         }
-        ))
-        .with_children(|parent|{
-
-            let mut x = 0.;
-            let mut y = 0.;
-
-            for line in maze.map.iter(){
-                for tile in line.iter(){
-                    let tile_bundle = match tile{
-                        0 => print_tile(x,y,TILE_SIZE, Color::rgba(0., 176./255., 60./255., 0.5)), // path
-                        1 => print_tile(x,y,TILE_SIZE, Color::rgba(68./255.,102./255.,0.,0.5)), // wall
-                        _ => unreachable!()
-                    };
-                    parent.spawn(tile_bundle);
-                    x += TILE_SIZE;
-                }
-                x=0.;
-                y+=TILE_SIZE;
+    )).with_children(|minimap|{
+        minimap.spawn((
+            MapPivot,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    left: Val::Percent(50.),
+                    top: Val::Percent(50.),
+                    ..default()
+                },
+                ..default()
             }
+        )).with_children(|pivot|{
+            pivot.spawn((
+                MapLayer,
+                ImageBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        width: Val::Px(cols * RADAR_TILE_SIZE),
+                        height: Val::Px(rows * RADAR_TILE_SIZE),
+                        ..default()
+                    },
+                    image: map.into(),
+                    ..default()
+                }
+            )).with_children(|layer|{
+                layer.spawn((
+                    PlayerArrow,
+                    ImageBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            width: Val::Px(ARROW_SIZE),
+                            height: Val::Px(ARROW_SIZE),
+                            margin: UiRect::new(Val::Px(-ARROW_SIZE / 2.), Val::Auto, Val::Px(-ARROW_SIZE / 2.), Val::Auto),
+                            ..default()
+                        },
+                        image: arrow.into(),
+                        z_index: ZIndex::Local(2),
+                        ..default()
+                    }
+                ));
+            });
         });
+    });
 }
 
-const TILE_SIZE:f32 = 20.;
+fn despawn_minimap(
+    mut cmd: Commands,
+    minimap_q: Query<Entity, With<Minimap>>,
+    map_image: Option<Res<MapImage>>,
+    mut images: ResMut<Assets<Image>>,
+){
+    for entity in minimap_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+    if let Some(map_image) = map_image{
+        images.remove(&map_image.0);
+        cmd.remove_resource::<MapImage>();
+    }
+}
 
-fn print_tile(x:f32, y:f32, size:f32, color:Color) -> NodeBundle{
-    NodeBundle{
-        style: Style{
-            position_type: PositionType::Relative,
-            width: Val::Px(size),
-            height: Val::Px(size),
-            left: Val::Px(x),
-            top: Val::Px(y),
-            ..default()
+/// not a bevy system. The maze with one block of pixels per tile
+fn rasterize_maze(maze: &Maze, explored: &ExploredTiles) -> Image{
+    let mut image = Image::new_fill(
+        Extent3d{
+            width: maze.map[0].len() as u32 * PIXELS_PER_TILE,
+            height: maze.map.len() as u32 * PIXELS_PER_TILE,
+            depth_or_array_layers: 1
         },
-        background_color: color.into(),
-        ..default()
+        TextureDimension::D2,
+        &FOG_COLOR,
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    // sharp tiles when the map is scaled up
+    image.sampler_descriptor = ImageSampler::nearest();
+    paint_map(&mut image, maze, explored);
+    image
+}
+
+/// not a bevy system. Draw the tiles of the maze in the image, the unexplored ones are hidden by the fog
+fn paint_map(image: &mut Image, maze: &Maze, explored: &ExploredTiles){
+    for (row, line) in maze.map.iter().enumerate(){
+        for col in 0..line.len(){
+            paint_tile(image, maze, row, col, explored.is_explored(row, col));
+        }
     }
 }
 
-/// when the minimap is displayed on the screen, we need to 
-/// update the position of the player while he is moving in the maze
-fn update_minimap(
-    mut cmd: Commands,
-    player_q: Query<&Transform, (With<CurrentPlayer>, Changed<Transform>)>,
-    minimap_q: Query<Entity, With<Minimap>>,
-    maze: Res<Maze>
+/// not a bevy system. Draw one tile of the maze in the image
+fn paint_tile(image: &mut Image, maze: &Maze, row: usize, col: usize, explored: bool){
+    let width = image.texture_descriptor.size.width as usize;
+    let color = match (explored, maze.map[row][col]){
+        (false, _) => FOG_COLOR,
+        (true, WALL_TILE) => WALL_COLOR,
+        (true, _) => PATH_COLOR,
+    };
+
+    let tile_pixels = PIXELS_PER_TILE as usize;
+    for y in row * tile_pixels..(row + 1) * tile_pixels{
+        for x in col * tile_pixels..(col + 1) * tile_pixels{
+            let i = (y * width + x) * 4;
+            image.data[i..i + 4].copy_from_slice(&color);
+        }
+    }
+}
+
+/// a white arrow pointing up
+fn arrow_image() -> Image{
+    let size = 32;
+    let mut image = Image::new_fill(
+        Extent3d{ width: size, height: size, depth_or_array_layers: 1 },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+
+    for y in 0..size{
+        // the arrow gets wider from the tip (top) to the base, with a notch at the bottom
+        let half_width = y as f32 / 2.;
+        let notch = (y as f32 - size as f32 * 0.7).max(0.);
+        for x in 0..size{
+            let dx = (x as f32 + 0.5 - size as f32 / 2.).abs();
+            if dx <= half_width && dx >= notch{
+                let i = ((y * size + x) * 4) as usize;
+                image.data[i..i + 4].copy_from_slice(&[255, 255, 255, 255]);
+            }
+        }
+    }
+    image
+}
+
+//////////////////////////// UPDATE ///////////////////////////////
+
+fn reset_explored_tiles(mut explored: ResMut<ExploredTiles>){
+    explored.0.clear();
+}
+
+/// discover the tiles around the player, they are painted in the map image as they are discovered
+fn explore_tiles(
+    mut explored: ResMut<ExploredTiles>,
+    player_q: Query<&Transform, With<CurrentPlayer>>,
+    maze: Res<Maze>,
+    map_image: Option<Res<MapImage>>,
+    mut images: ResMut<Assets<Image>>,
 ){
-    if let Ok(minimap_entity) = minimap_q.get_single() {
-        if let Ok(player_transform) = player_q.get_single() {
-            cmd.entity(minimap_entity).with_children(|parent|{
-                parent.spawn(
-                    print_tile(
-                        player_transform.translation.x * maze.tile_size,
-                        player_transform.translation.z * maze.tile_size,
-                        TILE_SIZE,
-                        Color::WHITE
-                    )
-                );
-            });
-       };
+    if maze.is_changed() || explored.0.len() != maze.map.len(){
+        explored.0 = maze.map.iter().map(|line| vec![false; line.len()]).collect();
+        // the whole image is drawn again with the new maze (see update_map_image)
+        return;
+    }
+    let Ok(transform) = player_q.get_single() else {return};
+    let Some((row, col)) = maze.world_to_tile(transform.translation) else {return};
+
+    let mut new_tiles = vec![];
+    for d_row in -EXPLORE_RADIUS..=EXPLORE_RADIUS{
+        for d_col in -EXPLORE_RADIUS..=EXPLORE_RADIUS{
+            let (row, col) = (row as i32 + d_row, col as i32 + d_col);
+            if row < 0 || col < 0 {continue}
+            let (row, col) = (row as usize, col as usize);
+            if row >= maze.map.len() || col >= maze.map[row].len() {continue}
+            if explored.is_explored(row, col) {continue}
+            explored.0[row][col] = true;
+            new_tiles.push((row, col));
+        }
+    }
+
+    // get_mut sends the whole image to the GPU again, only when something was explored
+    if new_tiles.is_empty() {return}
+    let Some(image) = map_image.and_then(|map_image| images.get_mut(&map_image.0)) else {return};
+    for (row, col) in new_tiles{
+        paint_tile(image, &maze, row, col, true);
+    }
+}
+
+/// a new image when the maze changes, the explored tiles are painted in it by explore_tiles
+fn update_map_image(
+    map_image: Option<Res<MapImage>>,
+    mut images: ResMut<Assets<Image>>,
+    explored: Res<ExploredTiles>,
+    maze: Res<Maze>,
+){
+    let Some(map_image) = map_image else {return};
+    if !maze.is_changed() {return}
+    images.set_untracked(map_image.0.id(), rasterize_maze(&maze, &explored));
+}
+
+/// not a bevy system. Position of a point of the world in the map, between 0 and 1
+fn map_position(maze: &Maze, position: Vec3) -> Vec2{
+    let rows = maze.map.len() as f32;
+    let cols = maze.map[0].len() as f32;
+    Vec2::new(
+        (position.x / maze.tile_size + 0.5) / cols,
+        (position.z / maze.tile_size + 0.5) / rows,
+    )
+}
+
+/// the position and the direction of the current player
+type CurrentPlayerQuery<'w, 's, F> = Query<'w, 's, (&'static Transform, &'static LookAngles), (With<CurrentPlayer>, F)>;
+
+/// radar or full screen map, rotation of the map
+#[allow(clippy::too_many_arguments)]
+fn update_minimap_layout(
+    mut minimap_q: Query<&mut Style, (With<Minimap>, Without<MapLayer>)>,
+    mut layer_q: Query<&mut Style, (With<MapLayer>, Without<Minimap>)>,
+    mut pivot_q: Query<&mut Transform, With<MapPivot>>,
+    player_q: CurrentPlayerQuery<Without<MapPivot>>,
+    windows: Query<&Window>,
+    player_input: Res<PlayerInput>,
+    settings: Res<Settings>,
+    maze: Res<Maze>,
+){
+    let Ok(mut minimap_style) = minimap_q.get_single_mut() else {return};
+    let Ok(mut layer_style) = layer_q.get_single_mut() else {return};
+    let Ok(mut pivot) = pivot_q.get_single_mut() else {return};
+    let Ok(window) = windows.get_single() else {return};
+
+    let rows = maze.map.len() as f32;
+    let cols = maze.map[0].len() as f32;
+    let player = player_q.get_single().ok();
+
+    let full_screen = player_input.show_map;
+    let (tile_size, map_offset, rotation) = if full_screen{
+        let tile_size = ((window.width() - 2. * FULL_SCREEN_MARGIN) / cols)
+            .min((window.height() - 2. * FULL_SCREEN_MARGIN) / rows);
+        // the whole map centered on the screen
+        (tile_size, Vec2::new(cols, rows) * tile_size / 2., 0.)
+    }else{
+        let (position, yaw) = player.map_or((Vec2::splat(0.5), 0.), |(transform, look)| {
+            (map_position(&maze, transform.translation), look.yaw)
+        });
+        // the direction the player is facing points up
+        let rotation = if settings.minimap_rotation {yaw - PI} else {0.};
+        (RADAR_TILE_SIZE, position * Vec2::new(cols, rows) * RADAR_TILE_SIZE, rotation)
     };
-}
\ No newline at end of file
+
+    let (size, top, right) = if full_screen{
+        (Val::Percent(100.), Val::Px(0.), Val::Px(0.))
+    }else{
+        (Val::Px(RADAR_SIZE), Val::Px(10.), Val::Px(10.))
+    };
+    if minimap_style.width != size{
+        minimap_style.width = size;
+        minimap_style.height = size;
+        minimap_style.top = top;
+        minimap_style.right = right;
+    }
+
+    layer_style.width = Val::Px(cols * tile_size);
+    layer_style.height = Val::Px(rows * tile_size);
+    layer_style.left = Val::Px(-map_offset.x);
+    layer_style.top = Val::Px(-map_offset.y);
+
+    pivot.rotation = Quat::from_rotation_z(rotation);
+}
+
+fn update_player_arrow(
+    mut arrow_q: Query<(&mut Style, &mut Transform), With<PlayerArrow>>,
+    player_q: CurrentPlayerQuery<Without<PlayerArrow>>,
+    maze: Res<Maze>,
+){
+    let Ok((mut style, mut arrow_transform)) = arrow_q.get_single_mut() else {return};
+    let Ok((transform, look)) = player_q.get_single() else {return};
+
+    let position = map_position(&maze, transform.translation);
+    style.left = Val::Percent(position.x * 100.);
+    style.top = Val::Percent(position.y * 100.);
+    // the player faces +Z (down on the map) when the yaw is 0
+    arrow_transform.rotation = Quat::from_rotation_z(PI - look.yaw);
+}
+
+/// markers of the teammates, and of the pickups on the explored tiles
+#[allow(clippy::too_many_arguments)]
+fn update_markers(
+    mut cmd: Commands,
+    layer_q: Query<Entity, With<MapLayer>>,
+    mut marker_q: Query<(Entity, &MinimapMarker, &mut Style, &mut BackgroundColor)>,
+    current_player_q: Query<(Entity, Option<&Team>), With<CurrentPlayer>>,
+    teammate_q: Query<(Entity, &Transform, &Team), With<PlayerId>>,
+    pickup_q: Query<(Entity, &Transform, &Pickup)>,
+    explored: Res<ExploredTiles>,
+    maze: Res<Maze>,
+){
+    let Ok(layer) = layer_q.get_single() else {return};
+    let current_player = current_player_q.get_single().ok();
+
+    let teammates = teammate_q.iter()
+        .filter(|(entity, _, team)| match current_player{
+            Some((current_entity, Some(current_team))) => *entity != current_entity && *team == current_team,
+            _ => false
+        })
+        .map(|(entity, transform, _)| (entity, transform.translation, TEAMMATE_COLOR));
+
+    let objectives = pickup_q.iter()
+        .filter(|(_, transform, pickup)| {
+            let explored = maze.world_to_tile(transform.translation)
+                .is_some_and(|(row, col)| explored.is_explored(row, col));
+            pickup.is_active() && explored
+        })
+        .map(|(entity, transform, pickup)| (entity, transform.translation, pickup.kind.color()));
+
+    let mut targets: Vec<(Entity, Vec3, Color)> = teammates.chain(objectives).collect();
+
+    for (marker_entity, marker, mut style, mut color) in marker_q.iter_mut(){
+        let Some(index) = targets.iter().position(|(target, _, _)| *target == marker.target) else{
+            cmd.entity(marker_entity).despawn_recursive();
+            continue;
+        };
+        let (_, position, target_color) = targets.swap_remove(index);
+        let position = map_position(&maze, position);
+        style.left = Val::Percent(position.x * 100.);
+        style.top = Val::Percent(position.y * 100.);
+        color.0 = target_color;
+    }
+
+    // new markers
+    for (target, position, color) in targets{
+        let position = map_position(&maze, position);
+        let marker = cmd.spawn((
+            MinimapMarker{ target },
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    left: Val::Percent(position.x * 100.),
+                    top: Val::Percent(position.y * 100.),
+                    width: Val::Px(MARKER_SIZE),
+                    height: Val::Px(MARKER_SIZE),
+                    margin: UiRect::new(Val::Px(-MARKER_SIZE / 2.), Val::Auto, Val::Px(-MARKER_SIZE / 2.), Val::Auto),
+                    ..default()
+                },
+                background_color: color.into(),
+                z_index: ZIndex::Local(1),
+                ..default()
+            }
+        )).id();
+        cmd.entity(layer).add_child(marker);
+    }
+}
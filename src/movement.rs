@@ -0,0 +1,163 @@
+//! Player movement, built on rapier's kinematic character controller.
+//! `step_movement` only works on plain data (inputs, player state, rotation), so that
+//! the exact same logic can be run by the server and replayed by a client to predict
+//! its own movement.
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+
+use crate::player::{Player, PlayerInput};
+
+//////////////////////////// CONSTS, COMPONENTS ///////////////////////////////
+
+pub const PLAYER_MOVE_SPEED: f32 = 7.0;
+pub const PLAYER_SLOW_MOVE_SPEED: f32 = 3.5;
+pub const PLAYER_SPRINT_SPEED: f32 = 10.0;
+pub const PLAYER_CROUCH_SPEED: f32 = 2.5;
+pub const JUMP_VELOCITY: f32 = 5.5;
+const GRAVITY: f32 = 9.81;
+const MAX_FALL_SPEED: f32 = 30.;
+
+pub const MAX_STAMINA: f32 = 100.;
+// stamina consumed/recovered per second
+const STAMINA_DRAIN: f32 = 25.;
+const STAMINA_REGEN: f32 = 15.;
+// once exhausted, the player has to recover some stamina before sprinting again
+const MIN_STAMINA_TO_SPRINT: f32 = 20.;
+
+// the capsule around the player (before the player's scale is applied)
+const CAPSULE_RADIUS: f32 = 0.1;
+const CAPSULE_TOP: f32 = 0.6;
+const CROUCHED_CAPSULE_TOP: f32 = 0.42;
+
+/// the capsule of the player only collides with the world,
+/// bullets go through it and hit the hitboxes
+pub const PLAYER_COLLISION_GROUP: Group = Group::GROUP_2;
+
+/// the vertical state of the player, the horizontal movement only depends on the inputs
+#[derive(Component, Default, Clone, Copy)]
+pub struct PlayerMotion{
+    pub vertical_velocity: f32,
+    pub grounded: bool,
+}
+
+//////////////////////////// BUNDLES ///////////////////////////////
+
+/// not a bevy system. The physics components of a player controlled by the server
+pub fn character_controller_bundle() -> impl Bundle{
+    (
+        RigidBody::KinematicPositionBased,
+        player_collider(false),
+        CollisionGroups::new(PLAYER_COLLISION_GROUP, Group::ALL),
+        KinematicCharacterController{
+            up: Vec3::Y,
+            offset: CharacterLength::Absolute(0.02),
+            slide: true,
+            autostep: Some(CharacterAutostep{
+                max_height: CharacterLength::Absolute(0.4),
+                min_width: CharacterLength::Absolute(0.2),
+                include_dynamic_bodies: false
+            }),
+            max_slope_climb_angle: 45_f32.to_radians(),
+            min_slope_slide_angle: 30_f32.to_radians(),
+            snap_to_ground: Some(CharacterLength::Absolute(0.3)),
+            apply_impulse_to_dynamic_bodies: false,
+            filter_flags: QueryFilterFlags::EXCLUDE_SENSORS,
+            ..default()
+        },
+        PlayerMotion::default(),
+    )
+}
+
+/// the capsule goes from the feet (origin of the player) to the top of the head
+pub fn player_collider(is_crouching: bool) -> Collider{
+    let top = if is_crouching {CROUCHED_CAPSULE_TOP} else {CAPSULE_TOP};
+    capsule(top, 1.)
+}
+
+/// not a bevy system
+fn capsule(top: f32, scale: f32) -> Collider{
+    Collider::capsule(
+        Vec3::new(0., CAPSULE_RADIUS, 0.) * scale,
+        Vec3::new(0., top - CAPSULE_RADIUS, 0.) * scale,
+        CAPSULE_RADIUS * scale
+    )
+}
+
+/// not a bevy system. A crouched player can stand up if nothing is above his head :
+/// the crouched capsule is cast upwards, up to the height of the standing one
+pub fn can_stand_up(rapier_context: &RapierContext, translation: Vec3, scale: f32) -> bool{
+    let height = (CAPSULE_TOP - CROUCHED_CAPSULE_TOP) * scale;
+    // only the world blocks the head, not the players nor the pickups
+    let filter = QueryFilter::only_fixed().exclude_sensors();
+    rapier_context.cast_shape(
+        translation, Quat::IDENTITY, Vec3::Y, &capsule(CROUCHED_CAPSULE_TOP, scale), height, filter
+    ).is_none()
+}
+
+//////////////////////////// MOVEMENT ///////////////////////////////
+
+/// compute the translation of a player for this frame and update its state (stamina, crouch ...).
+/// `rotation` is the rotation of the player (only its yaw matters),
+/// a crouched player stays crouched under a low ceiling unless `can_stand` (see `can_stand_up`)
+pub fn step_movement(
+    input: &PlayerInput,
+    player: &mut Player,
+    motion: &mut PlayerMotion,
+    rotation: Quat,
+    can_stand: bool,
+    delta_seconds: f32
+) -> Vec3{
+    let mut direction = Vec3::ZERO;
+
+    // the player model looks towards -forward
+    if input.left {direction += rotation * Vec3::X}
+    if input.right {direction += rotation * Vec3::NEG_X}
+    if input.up {direction += rotation * Vec3::Z}
+    if input.down {direction += rotation * Vec3::NEG_Z}
+
+    direction = direction.normalize_or_zero();
+
+    // crouch and sprint
+    player.is_crouching = input.crouch || (player.is_crouching && !can_stand);
+
+    let wants_to_sprint = input.sprint && !input.is_walking && !player.is_crouching && direction != Vec3::ZERO;
+    let can_sprint = player.stamina > 0. && (player.is_sprinting || player.stamina >= MIN_STAMINA_TO_SPRINT);
+    player.is_sprinting = wants_to_sprint && can_sprint;
+
+    player.stamina = if player.is_sprinting {
+        player.stamina - STAMINA_DRAIN * delta_seconds
+    } else {
+        player.stamina + STAMINA_REGEN * delta_seconds
+    }.clamp(0., MAX_STAMINA);
+
+    let speed = match &*player{
+        p if p.is_crouching => PLAYER_CROUCH_SPEED,
+        p if p.is_sprinting => PLAYER_SPRINT_SPEED,
+        _ if input.is_walking => PLAYER_SLOW_MOVE_SPEED,
+        _ => PLAYER_MOVE_SPEED
+    };
+
+    // jump and gravity
+    if input.jump && motion.grounded && !player.is_crouching{
+        motion.vertical_velocity = JUMP_VELOCITY;
+        motion.grounded = false;
+    }
+    motion.vertical_velocity = (motion.vertical_velocity - GRAVITY * delta_seconds).max(-MAX_FALL_SPEED);
+
+    (direction * speed + motion.vertical_velocity * Vec3::Y) * delta_seconds
+}
+
+/// read the result of the character controller after the physics step
+pub fn update_player_motion(
+    mut player_q: Query<(&mut PlayerMotion, &KinematicCharacterControllerOutput)>,
+){
+    for (mut motion, output) in player_q.iter_mut(){
+        motion.grounded = output.grounded;
+
+        // landed, or hit the ceiling
+        let blocked_vertically = output.effective_translation.y.abs() < output.desired_translation.y.abs() * 0.5;
+        if (motion.grounded && motion.vertical_velocity < 0.) || (motion.vertical_velocity > 0. && blocked_vertically){
+            motion.vertical_velocity = 0.;
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Network diagnostics : a net graph toggled in game (F3 by default) with the ping, the loss,
+//! the bandwidth of each channel, the snapshot rate and the frame time.
+//! The client has no interpolation nor prediction yet (the snapshots are applied as they come),
+//! so the graph shows what they would have to deal with : the snapshot jitter and the late snapshots,
+//! and the error of extrapolating the current player's position from the previous snapshots.
+//! The server only shows the ping, the loss and the total bandwidth of each client (renet's `NetworkInfo`),
+//! in its window and in the console `status`.
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetClient, RenetServer, NetworkInfo};
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+
+use crate::{
+    controls::{ActionInput, Action},
+    chat::chat_closed,
+    player::PlayerName,
+    network::mods::{Lobby, Spectators},
+};
+
+//////////////////////////// PLUGIN, RESOURCES, CONSTS ///////////////////////////////
+
+pub struct NetGraphPlugin;
+impl Plugin for NetGraphPlugin{
+
+    fn build(&self, app: &mut App) {
+        app.init_resource::<NetStats>();
+        app.add_systems(Startup, spawn_net_graph);
+        app.add_systems(Update, (
+            toggle_net_graph.run_if(chat_closed),
+            update_frame_stats,
+            update_net_graph.after(update_frame_stats),
+        ));
+    }
+}
+
+// a snapshot is late when it comes this many times after the average interval
+const LATE_SNAPSHOT_FACTOR: f32 = 1.5;
+// weight of the new value in the smoothed averages
+const SMOOTHING: f32 = 0.1;
+
+const CHANNELS: [(DefaultChannel, &str); 3] = [
+    (DefaultChannel::ReliableOrdered, "reliable ordered"),
+    (DefaultChannel::ReliableUnordered, "reliable unordered"),
+    (DefaultChannel::Unreliable, "unreliable"),
+];
+
+/// not a bevy system
+fn channel_index(channel: DefaultChannel) -> usize{
+    match channel{
+        DefaultChannel::ReliableOrdered => 0,
+        DefaultChannel::ReliableUnordered => 1,
+        DefaultChannel::Unreliable => 2,
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChannelBytes{
+    pub received: usize,
+    pub sent: usize,
+}
+
+/// counted by the client systems sending and receiving the messages.
+/// The counters are per second, the values of the last complete second are shown
+#[derive(Resource, Debug, Default)]
+pub struct NetStats{
+    // the current second
+    second_start: f32,
+    counting: [ChannelBytes; 3],
+    snapshots: u32,
+    late_snapshots: u32,
+    frames: u32,
+    max_frame_time: f32,
+
+    // the last second
+    pub bandwidth: [ChannelBytes; 3],
+    pub snapshot_rate: u32,
+    pub late_snapshot_rate: u32,
+    pub fps: u32,
+    pub max_frame_time_ms: f32,
+
+    // smoothed, in milliseconds
+    pub frame_time_ms: f32,
+    pub snapshot_interval_ms: f32,
+    pub snapshot_jitter_ms: f32,
+    last_snapshot: Option<f32>,
+
+    /// distance between the current player's position in a snapshot
+    /// and the position extrapolated from the two previous ones
+    pub extrapolation_error: f32,
+    last_positions: Option<[(f32, Vec3); 2]>,
+}
+
+impl NetStats{
+    pub fn record_received(&mut self, channel: DefaultChannel, bytes: usize){
+        self.counting[channel_index(channel)].received += bytes;
+    }
+
+    pub fn record_sent(&mut self, channel: DefaultChannel, bytes: usize){
+        self.counting[channel_index(channel)].sent += bytes;
+    }
+
+    /// a snapshot received at `now`, with the current player's position if he is in it
+    pub fn record_snapshot(&mut self, now: f32, current_player: Option<Vec3>){
+        self.snapshots += 1;
+
+        if let Some(last) = self.last_snapshot{
+            let interval = (now - last) * 1000.;
+            if self.snapshot_interval_ms > 0. && interval > self.snapshot_interval_ms * LATE_SNAPSHOT_FACTOR{
+                self.late_snapshots += 1;
+            }
+            let deviation = (interval - self.snapshot_interval_ms).abs();
+            self.snapshot_jitter_ms += (deviation - self.snapshot_jitter_ms) * SMOOTHING;
+            self.snapshot_interval_ms += (interval - self.snapshot_interval_ms) * SMOOTHING;
+        }
+        self.last_snapshot = Some(now);
+
+        let Some(position) = current_player else {
+            self.last_positions = None;
+            return;
+        };
+        // several snapshots can be read in the same frame, only the first one is used
+        if self.last_positions.is_some_and(|[_, (time, _)]| time == now) {return}
+
+        if let Some([(time0, position0), (time1, position1)]) = self.last_positions{
+            if time1 > time0{
+                let velocity = (position1 - position0) / (time1 - time0);
+                let extrapolated = position1 + velocity * (now - time1);
+                self.extrapolation_error += (extrapolated.distance(position) - self.extrapolation_error) * SMOOTHING;
+            }
+        }
+        let previous = self.last_positions.map_or((now, position), |[_, last]| last);
+        self.last_positions = Some([previous, (now, position)]);
+    }
+}
+
+#[derive(Component)]
+struct NetGraph;
+
+//////////////////////////// CLIENT ///////////////////////////////
+
+fn spawn_net_graph(mut cmd: Commands){
+    cmd.spawn((
+        TextBundle{
+            background_color: Color::rgba(0., 0., 0., 0.6).into(),
+            visibility: Visibility::Hidden,
+            ..TextBundle::from_section("", TextStyle{ font_size: 16., ..default() })
+                .with_style(Style{
+                    position_type: PositionType::Absolute,
+                    left: Val::Px(10.),
+                    top: Val::Px(10.),
+                    padding: UiRect::all(Val::Px(5.)),
+                    ..default()
+                })
+        },
+        NetGraph,
+        Name::new("Net Graph")
+    ));
+}
+
+fn toggle_net_graph(
+    actions: ActionInput,
+    mut graph_q: Query<&mut Visibility, With<NetGraph>>,
+){
+    if !actions.just_pressed(Action::NetGraph) {return}
+    for mut visibility in graph_q.iter_mut(){
+        *visibility = match *visibility{
+            Visibility::Hidden => Visibility::Inherited,
+            _ => Visibility::Hidden,
+        };
+    }
+}
+
+/// count the frames, and start a new second
+fn update_frame_stats(
+    mut stats: ResMut<NetStats>,
+    time: Res<Time>,
+){
+    let frame_time = time.delta_seconds();
+    stats.frames += 1;
+    stats.max_frame_time = stats.max_frame_time.max(frame_time);
+    stats.frame_time_ms += (frame_time * 1000. - stats.frame_time_ms) * SMOOTHING;
+
+    let now = time.elapsed_seconds();
+    if now - stats.second_start < 1. {return}
+
+    stats.bandwidth = stats.counting;
+    stats.snapshot_rate = stats.snapshots;
+    stats.late_snapshot_rate = stats.late_snapshots;
+    stats.fps = stats.frames;
+    stats.max_frame_time_ms = stats.max_frame_time * 1000.;
+
+    stats.counting = default();
+    stats.snapshots = 0;
+    stats.late_snapshots = 0;
+    stats.frames = 0;
+    stats.max_frame_time = 0.;
+    stats.second_start = now;
+}
+
+fn update_net_graph(
+    stats: Res<NetStats>,
+    client: Option<Res<RenetClient>>,
+    mut graph_q: Query<(&mut Text, &Visibility), With<NetGraph>>,
+){
+    let Ok((mut text, visibility)) = graph_q.get_single_mut() else {return};
+    if *visibility == Visibility::Hidden {return}
+
+    let mut lines = vec![format!(
+        "fps: {}  frame: {:.1} ms (max {:.1} ms)",
+        stats.fps, stats.frame_time_ms, stats.max_frame_time_ms
+    )];
+
+    match client{
+        Some(client) if !client.is_disconnected() => {
+            lines.push(describe_network(&client.network_info()));
+            for (channel, name) in CHANNELS{
+                let bytes = stats.bandwidth[channel_index(channel)];
+                lines.push(format!(
+                    "  {:18} in {:>6.2} KB/s  out {:>6.2} KB/s",
+                    name, bytes.received as f32 / 1000., bytes.sent as f32 / 1000.
+                ));
+            }
+            lines.push(format!(
+                "snapshots: {}/s  interval {:.0} ms  jitter {:.1} ms  late {}/s",
+                stats.snapshot_rate, stats.snapshot_interval_ms, stats.snapshot_jitter_ms, stats.late_snapshot_rate
+            ));
+            // the snapshots are applied as they come, nothing is interpolated yet
+            lines.push(format!("extrapolation error: {:.2} m (no interpolation yet)", stats.extrapolation_error));
+        }
+        _ => lines.push("not connected".to_string()),
+    }
+
+    text.sections[0].value = lines.join("\n");
+}
+
+//////////////////////////// SERVER ///////////////////////////////
+
+/// not a bevy system. Ping, loss and bandwidth of a connection, for the net graph and the console
+pub fn describe_network(info: &NetworkInfo) -> String{
+    // the rtt of renet is in seconds
+    format!(
+        "ping {:.0} ms  loss {:.1}%  in {:.2} KB/s  out {:.2} KB/s",
+        info.rtt * 1000., info.packet_loss * 100.,
+        info.bytes_received_per_second / 1000., info.bytes_sent_per_second / 1000.
+    )
+}
+
+/// the network of each client, and the server's own frame time
+pub fn server_net_overlay(
+    mut contexts: EguiContexts,
+    server: Res<RenetServer>,
+    lobby: Res<Lobby>,
+    spectators: Res<Spectators>,
+    names_q: Query<&PlayerName>,
+    time: Res<Time>,
+){
+    let frame_time = time.delta_seconds();
+
+    egui::Window::new("Network").show(contexts.ctx_mut(), |ui|{
+        ui.label(format!("tick: {:.0}/s  frame: {:.1} ms", 1. / frame_time.max(f32::EPSILON), frame_time * 1000.));
+        ui.separator();
+
+        let mut clients = server.clients_id();
+        clients.sort();
+        if clients.is_empty(){
+            ui.label("no client");
+        }
+        for client_id in clients{
+            let Ok(info) = server.network_info(client_id) else {continue};
+            let name = lobby.players.get(&client_id)
+                .and_then(|&entity| names_q.get(entity).ok())
+                .map(|PlayerName(name)| name.clone())
+                .or_else(|| spectators.names.get(&client_id).map(|name| format!("{} (spectator)", name)))
+                .unwrap_or_default();
+            ui.label(format!("{} {}", client_id, name));
+            ui.label(format!("  {}", describe_network(&info)));
+        }
+    });
+}
@@ -1,51 +1,99 @@
-use crate::{player::{PlayerInput, PlayerState, FLASHLIGHT_INTENSITY, PlayerId, HP, PLAYER_SCALE, CurrentPlayer, Player}, camera:: get_camera, flashlight::{get_flashlight_bundle, get_inner_flashlight}, weapons::WeaponAsset, game::AppState, sounds::{FootStepSound, play_sound_effect}, shoot::BULLET_VELOCITY, animation, bullet_tracer};
+use crate::{player::{PlayerInput, PlayerName, PlayerState, FLASHLIGHT_INTENSITY, PlayerId, HP, PLAYER_SCALE, CurrentPlayer, Player}, camera::{get_camera, camera_height, LookAngles}, flashlight::{get_flashlight_bundle, get_inner_flashlight}, weapons::WeaponAsset, game::AppState, sounds::{SoundCategory, SoundCatalog, play_sound_event}, shoot::BULLET_VELOCITY, animation, bullet_tracer, pickup::{Pickup, handle_pickup_update}, hud::{DamageTaken, HitMarker}, killfeed::{Kill, Notice}, death::{DeathInfo, SnapshotBuffer, PlayerSnapshot}, chat::ChatBroadcast, world::Maze, netgraph::NetStats};
 use super::mods::*;
-use std::env;
+use std::{net::SocketAddr, collections::VecDeque};
 use bevy_rapier3d::prelude::{GravityScale, Velocity, RigidBody, Collider};
-use local_ip_address::local_ip;
+use bevy::ecs::system::SystemParam;
 
 
-pub fn new_renet_client() -> (RenetClient,ClientId, NetcodeClientTransport) {
-    let server_ip_addr = match env::args().into_iter().nth(1){
-        Some(addr) => addr,
-        None => local_ip().unwrap().to_string()
-    };
-
-    let Ok(server_addr) = (server_ip_addr.clone() + ":5000").parse() else {
-        panic!("ERR: {} is not a valid ip adress", server_ip_addr);
-    };
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+/// create the client connecting to the server chosen in the main menu, as a player or as a spectator
+pub fn new_renet_client(server_addr: SocketAddr, player_name: &str, spectator: bool) -> Result<(RenetClient, ClientId, NetcodeClientTransport), String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| e.to_string())?;
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
-    let client_id = current_time.as_millis() as u64;
+    // several clients can start in the same millisecond (see loadtest.rs)
+    let client_id = current_time.as_millis() as u64 * 1000 + rand::random::<u64>() % 1000;
     let authentication = ClientAuthentication::Unsecure {
         client_id,
         protocol_id: PROTOCOL_ID,
         server_addr,
-        user_data: None,
+        user_data: Some(player_name_to_user_data(player_name, spectator)),
     };
 
-    let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
+    let transport = NetcodeClientTransport::new(current_time, authentication, socket).map_err(|e| e.to_string())?;
     let client = RenetClient::new(ConnectionConfig::default());
 
-    (client,ClientId(client_id), transport)
+    Ok((client, ClientId(client_id), transport))
+}
+
+/// the events sent from the server messages, for the hud, the kill feed and the chat
+#[derive(SystemParam)]
+pub struct ServerMessageEvents<'w>{
+    damage: EventWriter<'w, DamageTaken>,
+    hit_markers: EventWriter<'w, HitMarker>,
+    kills: EventWriter<'w, Kill>,
+    notices: EventWriter<'w, Notice>,
+    chat_messages: EventWriter<'w, ChatBroadcast>,
+}
+
+/// the messages received from the server, handled by the systems below.
+/// The replays fill it from the demo file instead (see demo.rs)
+#[derive(Resource, Default)]
+pub struct ServerInbox{
+    pub messages: VecDeque<ServerMessages>,
+    pub snapshots: Vec<HashMap<u64, PlayerState>>,
+}
+
+pub fn client_receive_messages(
+    mut client: ResMut<RenetClient>,
+    mut inbox: ResMut<ServerInbox>,
+    mut stats: ResMut<NetStats>,
+    current_player_q: Query<&CurrentPlayer>,
+    time: Res<Time>,
+){
+    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
+        stats.record_received(DefaultChannel::ReliableOrdered, message.len());
+        match bincode::deserialize(&message){
+            Ok(message) => inbox.messages.push_back(message),
+            Err(e) => eprintln!("ERR: invalid message from the server: {}", e),
+        }
+    }
+
+    // get every snapshot received in this frame
+    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
+        stats.record_received(DefaultChannel::Unreliable, message.len());
+        let Ok(players) = bincode::deserialize::<HashMap<u64, PlayerState>>(&message) else {
+            eprintln!("ERR: invalid snapshot from the server");
+            continue;
+        };
+
+        let current_player = current_player_q.iter()
+            .find_map(|current_player| players.get(&current_player.0))
+            .map(|player| Vec3::from(player.translation));
+        stats.record_snapshot(time.elapsed_seconds(), current_player);
+        inbox.snapshots.push(players);
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn client_connections_handler(
     mut commands: Commands,
-    mut client: ResMut<RenetClient>,
-    client_id: Res<ClientId>,
+    mut inbox: ResMut<ServerInbox>,
+    client_id: Option<Res<ClientId>>,
     mut lobby: ResMut<Lobby>,
     players_q: Query<&Transform, With<Player>>,
+    current_player_q: Query<&Transform, With<CurrentPlayer>>,
+    mut pickup_q: Query<(Entity, &mut Pickup)>,
+    catalog: Res<SoundCatalog>,
     assets: Res<AssetServer>,
+    mut meshes : ResMut<Assets<Mesh>>,
+    mut materials : ResMut<Assets<StandardMaterial>>,
+    mut events: ServerMessageEvents,
 ){
-    while let Some(message) = client.receive_message(DefaultChannel::ReliableOrdered) {
-
-        let server_message = bincode::deserialize(&message).unwrap();
+    while let Some(server_message) = inbox.messages.pop_front() {
         match server_message {
     
             // spawn player and insert it in the lobby hashmap
-            ServerMessages::PlayerConnected { id } => {
-                println!("Player {} connected.", id);
+            ServerMessages::PlayerConnected { id, name, team } => {
+                println!("Player {} ({}) connected.", id, name);
 
                 let player_transform = Transform::from_xyz(0.0, 1.5, 0.0).with_scale(PLAYER_SCALE*Vec3::ONE);
 
@@ -71,7 +119,7 @@ pub fn client_connections_handler(
                 let mut weapon_transform = Transform::from_xyz(-0.1, 0.5, 0.2).with_scale(0.1 * Vec3::ONE);
                 weapon_transform.rotate_y(3.3);
 
-                if client_id.0 == id {
+                if client_id.as_ref().is_some_and(|client_id| client_id.0 == id) {
                     player.insert(CurrentPlayer(id));
                     player.with_children(|parent| {
                         // add the player's camera
@@ -83,16 +131,20 @@ pub fn client_connections_handler(
                     let Ok(player_transform) = players_q.get(player_entity) else {continue;};
                     
                     player.with_children(|parent|{
-                        parent.spawn(SpatialAudioBundle{
-                            source: assets.load("sounds/music_test.ogg"),
-                            settings: PlaybackSettings::LOOP,
-                            spatial: SpatialSettings::new(Transform::from_xyz(0., 0.5, 0.), 1., player_transform.translation)
-                        });
+                        parent.spawn((
+                            SpatialAudioBundle{
+                                source: assets.load("sounds/music_test.ogg"),
+                                settings: PlaybackSettings::LOOP,
+                                spatial: SpatialSettings::new(Transform::from_xyz(0., 0.5, 0.), 1., player_transform.translation)
+                            },
+                            SoundCategory::Music,
+                        ));
                     });
                 }
 
 
                 player.insert(PlayerId{id});
+                player.insert((PlayerName(name), team));
                 player.insert(HP::default());
                 lobby.players.insert(id, player.id());
             }
@@ -104,29 +156,94 @@ pub fn client_connections_handler(
                     commands.entity(player_entity).despawn();
                 }
             }
+
+            ServerMessages::PickupUpdate { id, kind, translation, active } => {
+                handle_pickup_update(
+                    &mut commands, &mut pickup_q, &mut meshes, &mut materials,
+                    id, kind, translation, active
+                );
+            }
+
+            ServerMessages::Sound { event, translation, emitter } => {
+                let listener = current_player_q.get_single().ok().map(|transform| transform.translation);
+                play_sound_event(
+                    &mut commands, &catalog, &lobby,
+                    event, translation.into(), emitter, listener
+                );
+            }
+
+            ServerMessages::Damaged { origin, damage } => {
+                events.damage.send(DamageTaken{ origin: origin.into(), damage });
+            }
+
+            ServerMessages::HitConfirmed { headshot, kill } => {
+                events.hit_markers.send(HitMarker{ headshot, kill });
+            }
+
+            ServerMessages::Kill { killer, victim, weapon, headshot, wallbang } => {
+                events.kills.send(Kill{ killer, victim, weapon, headshot, wallbang });
+            }
+
+            ServerMessages::Notice(notice) => {
+                events.notices.send(notice);
+            }
+
+            ServerMessages::Chat(message) => {
+                events.chat_messages.send(message);
+            }
+
+            ServerMessages::Died { killer, weapon, headshot, killer_hp, respawn_in } => {
+                commands.insert_resource(DeathInfo{ killer, weapon, headshot, killer_hp, respawn_in });
+            }
+
+            // the walls and the minimap follow the new maze, the server sends the new pickups next
+            ServerMessages::Map { name, map, surfaces } => {
+                println!("Map: {}", name);
+                commands.insert_resource(Maze::new(&name, map, surfaces));
+                for (entity, _) in pickup_q.iter(){
+                    commands.entity(entity).despawn_recursive();
+                }
+                // the other messages are read next frame, once the old pickups are despawned
+                break;
+            }
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn client_sync_players(
     mut flashlight_q: Query<(&mut SpotLight, &PlayerId, &mut Transform), Without<Camera>>,
     mut camera_q: Query<&mut Transform, With<Camera>>,  
     player_q: Query<&Children, With<PlayerId>>,
     current_player_q: Query<&CurrentPlayer>,
-    footsteps_q: Query<(&FootStepSound, Entity)>,
 
-    assets: Res<AssetServer>,
     mut meshes : ResMut<Assets<Mesh>>,
     mut materials : ResMut<Assets<StandardMaterial>>,
 
     mut commands: Commands,
-    mut client: ResMut<RenetClient>,
+    mut inbox: ResMut<ServerInbox>,
     lobby: ResMut<Lobby>,
+    current_state: Res<State<AppState>>,
     mut next_state: ResMut<NextState<AppState>>,
+    mut snapshots: ResMut<SnapshotBuffer>,
+    time: Res<Time>,
 ) {
-    // get every message received in this frame
-    while let Some(message) = client.receive_message(DefaultChannel::Unreliable) {
-        let players: HashMap<u64, PlayerState> = bincode::deserialize(&message).unwrap();
+    // after a map change, the players connect in the next frame (see the Map message).
+    // Their snapshots wait in the inbox, or the players would stay at their spawn
+    let pending_messages = !inbox.messages.is_empty();
+    let ready = inbox.snapshots.iter()
+        .position(|players| pending_messages && players.keys().any(|id| !lobby.players.contains_key(id)))
+        .unwrap_or(inbox.snapshots.len());
+    let ready: Vec<_> = inbox.snapshots.drain(..ready).collect();
+
+    for players in ready {
+
+        // keep the last states for the kill cam
+        snapshots.record(time.elapsed_seconds(), players.iter().map(|(&id, player)| (id, PlayerSnapshot{
+            translation: player.translation.into(),
+            look: LookAngles{ yaw: player.yaw, pitch: player.pitch },
+            is_crouching: player.state.is_crouching,
+        })).collect());
 
         'players_loop: for (player_id, player) in players.iter() {
             
@@ -135,10 +252,16 @@ pub fn client_sync_players(
                 None => continue 'players_loop
             };
 
-            // check for dead players
+            // the current player dies, or respawns
             for current_player in current_player_q.iter(){
-                if current_player.0 == *player_id && player.state.hp <= 0.{
-                    next_state.set(AppState::DeathScreen);
+                if current_player.0 != *player_id {continue}
+
+                let is_dead = player.state.hp <= 0.;
+                match current_state.get(){
+                    AppState::InGame if is_dead => next_state.set(AppState::DeathScreen),
+                    // the dead players can spectate until they respawn
+                    AppState::DeathScreen | AppState::Spectating if !is_dead => next_state.set(AppState::InGame),
+                    _ => (),
                 }
             }
             
@@ -146,17 +269,16 @@ pub fn client_sync_players(
             commands
             .entity(*player_entity)
             .insert(Player::from(player.state.clone()));
-                
-            play_sound_effect(&mut commands, &player, &assets, &footsteps_q, player_id, player_entity);
 
-            //update player position
+            //update player position and orientation
+            let look = LookAngles{ yaw: player.yaw, pitch: player.pitch };
             let transform = Transform {
                 translation: (player.translation).into(),
-                rotation: Quat::from_array(player.rotation),
+                rotation: look.body_rotation(),
                 scale: PLAYER_SCALE * Vec3::ONE,
             };
             
-            commands.entity(*player_entity).insert(transform);
+            commands.entity(*player_entity).insert((transform, look));
 
             
             // update flashlight
@@ -167,7 +289,8 @@ pub fn client_sync_players(
             for (mut light, playerid, mut light_transform) in flashlight_q.iter_mut(){
                 if *player_id != playerid.id {continue}
 
-                light_transform.rotate_x(player.cam_vertical_motion);
+                light_transform.rotation = look.view_rotation();
+                light_transform.translation.y = camera_height(player.state.is_crouching);
 
                 if player.state.flashlight_on{
                     if first_flashlight_found{
@@ -182,11 +305,12 @@ pub fn client_sync_players(
                 }                    
             }
 
-            // update player's camera vertical motion
+            // update player's camera orientation
             if let Ok(children) = player_q.get(*player_entity){
                 for &child in children{
                     if let Ok(mut cam_transform) = camera_q.get_mut(child){
-                        cam_transform.rotate_x(player.cam_vertical_motion);           
+                        cam_transform.rotation = look.view_rotation();
+                        cam_transform.translation.y = camera_height(player.state.is_crouching);
                         break;
                     }
                 }
@@ -202,9 +326,10 @@ pub fn client_sync_players(
     }
 }
 
-pub fn client_send_input(player_input: Res<PlayerInput>, mut client: ResMut<RenetClient>) {
+pub fn client_send_input(player_input: Res<PlayerInput>, mut client: ResMut<RenetClient>, mut stats: ResMut<NetStats>) {
 
     let input_message = bincode::serialize(&*player_input).unwrap();
+    stats.record_sent(DefaultChannel::ReliableOrdered, input_message.len());
     client.send_message(DefaultChannel::ReliableOrdered, input_message);
 }
 
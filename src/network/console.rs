@@ -0,0 +1,467 @@
+//! Server console : the operator types commands in the terminal running the server.
+//! The same commands can be sent remotely with rcon (UDP datagrams `<password> <command>`
+//! on the rcon port), enabled when the `RCON_PASSWORD` environment variable is set.
+//! Rcon only listens on localhost, unless the server is started with `--rcon-public`.
+//! The wrong passwords get no answer, and the addresses sending too many of them are ignored for a while.
+//! The bans are saved in the config directory (see config.rs).
+use super::mods::*;
+use bevy::ecs::query::Has;
+use std::{
+    collections::HashSet,
+    env, fs,
+    io::BufRead,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{mpsc::{self, Receiver}, Mutex},
+    thread,
+};
+
+use crate::{
+    config,
+    game::{Cvars, GameMode, MatchState},
+    world::{Maze, DEFAULT_MAP},
+    player::{Player, PlayerName},
+    team::Team,
+    death::RespawnTimer,
+    killfeed::Notice,
+    chat::server_chat_message,
+    bots::{Bot, BotCommand},
+    netgraph::describe_network,
+    demo::{GameServer, DemoCommand, demo_path},
+};
+
+//////////////////////////// RESOURCES, EVENTS, CONSTS ///////////////////////////////
+
+pub const RCON_PORT: u16 = 5002;
+const RCON_PASSWORD_VAR: &str = "RCON_PASSWORD";
+/// the flag of the server opening rcon to the other machines
+pub const RCON_PUBLIC_FLAG: &str = "--rcon-public";
+// after this number of wrong passwords, the datagrams of the address are ignored for RCON_LOCKOUT seconds.
+// The wrong passwords are forgotten RCON_LOCKOUT seconds after the last one
+const RCON_MAX_FAILURES: u32 = 3;
+const RCON_LOCKOUT: f32 = 60.;
+// the source of a datagram can be spoofed, the number of addresses remembered is limited
+const RCON_MAX_ADDRESSES: usize = 1024;
+const BANS_FILE: &str = "bans.ron";
+
+const HELP: &str = "commands:
+  status                  list the connected clients
+  kick <id>               disconnect a client
+  ban <id|ip>             disconnect and ban a client, or an ip address
+  unban <id|ip>
+  map [name]              change the map (default, or assets/maps/<name>.ron)
+  mode [dm|tdm]           change the game mode, restarts the match
+  restart                 respawn everyone and reset the pickups
+  say <text>              send a chat message to everyone
+  bot add|remove [count]  add or remove bots
+  record [name]           record a demo in demos/<name>.demo (date and map by default)
+  stop                    stop recording the demo
+  set [cvar] [value]      show or change the cvars";
+
+/// a line typed in the console, the output is sent back to the rcon client (if any)
+#[derive(Event, Debug, Clone)]
+pub struct ConsoleCommand{
+    pub line: String,
+    pub reply_to: Option<SocketAddr>,
+}
+
+/// start the match again, on a new map if any. The notice is announced to every player
+#[derive(Event)]
+pub struct RestartMatch{
+    pub maze: Option<Maze>,
+    pub notice: Notice,
+}
+
+/// the lines typed on stdin, read in another thread because reading is blocking
+#[derive(Resource)]
+pub struct ConsoleInput(Mutex<Receiver<String>>);
+
+impl ConsoleInput{
+    pub fn start() -> Self{
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move ||{
+            for line in std::io::stdin().lock().lines(){
+                let Ok(line) = line else {break};
+                if sender.send(line).is_err() {break}
+            }
+        });
+        Self(Mutex::new(receiver))
+    }
+}
+
+#[derive(Resource)]
+pub struct Rcon{
+    socket: UdpSocket,
+    password: String,
+    /// the wrong passwords sent by each address, and when they are forgotten (the end of the lockout)
+    failures: HashMap<IpAddr, (u32, f32)>,
+}
+
+impl Rcon{
+    /// returns None if rcon is disabled (no password) or the port is already used.
+    /// Only the local machine can send commands, unless `public`
+    pub fn new(public: bool) -> Option<Self>{
+        let password = env::var(RCON_PASSWORD_VAR).ok().filter(|password| !password.is_empty())?;
+        let address = if public {Ipv4Addr::UNSPECIFIED} else {Ipv4Addr::LOCALHOST};
+        let socket = match UdpSocket::bind((address, RCON_PORT)){
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("ERR: cannot bind the rcon port {}: {}", RCON_PORT, e);
+                return None;
+            }
+        };
+        socket.set_nonblocking(true).ok()?;
+        println!("rcon enabled on {}:{}", address, RCON_PORT);
+        Some(Self{ socket, password, failures: HashMap::new() })
+    }
+
+    /// not a bevy system. true if the password is right and the sender is not locked out
+    fn authenticate(&mut self, sender: IpAddr, password: &str, now: f32) -> bool{
+        self.failures.retain(|_, (_, forget_at)| now < *forget_at);
+        if self.failures.get(&sender).is_some_and(|(failures, _)| *failures >= RCON_MAX_FAILURES) {return false}
+
+        if password == self.password{
+            self.failures.remove(&sender);
+            return true;
+        }
+
+        eprintln!("ERR: rcon command from {} with a wrong password", sender);
+        if self.failures.len() >= RCON_MAX_ADDRESSES && !self.failures.contains_key(&sender) {return false}
+        let (failures, forget_at) = self.failures.entry(sender).or_insert((0, 0.));
+        *failures += 1;
+        *forget_at = now + RCON_LOCKOUT;
+        if *failures >= RCON_MAX_FAILURES{
+            eprintln!("ERR: too many wrong rcon passwords from {}, ignored for {} s", sender, RCON_LOCKOUT);
+        }
+        false
+    }
+}
+
+/// the banned client ids and ip addresses, checked when a client connects (see server.rs)
+#[derive(Resource, Debug, Default, Serialize, Deserialize)]
+pub struct BanList{
+    pub ids: HashSet<u64>,
+    pub ips: HashSet<IpAddr>,
+}
+
+impl BanList{
+    pub fn load() -> Self{
+        config::load(BANS_FILE).unwrap_or_default()
+    }
+
+    fn save(&self){
+        config::save(BANS_FILE, self);
+    }
+
+    pub fn is_banned(&self, client_id: u64, ip: Option<IpAddr>) -> bool{
+        self.ids.contains(&client_id) || ip.is_some_and(|ip| self.ips.contains(&ip))
+    }
+}
+
+//////////////////////////// SYSTEMS ///////////////////////////////
+
+/// read the lines of stdin and the rcon datagrams
+pub fn read_console_commands(
+    input: Res<ConsoleInput>,
+    rcon: Option<ResMut<Rcon>>,
+    mut commands: EventWriter<ConsoleCommand>,
+    time: Res<Time>,
+){
+    if let Ok(receiver) = input.0.lock(){
+        for line in receiver.try_iter(){
+            commands.send(ConsoleCommand{ line, reply_to: None });
+        }
+    }
+
+    let Some(mut rcon) = rcon else {return};
+    let now = time.elapsed_seconds();
+    let mut buffer = [0; 512];
+    while let Ok((size, sender)) = rcon.socket.recv_from(&mut buffer){
+        let datagram = String::from_utf8_lossy(&buffer[..size]);
+        let datagram = datagram.trim();
+        let (password, line) = datagram.split_once(' ').unwrap_or((datagram, ""));
+
+        // no answer, a wrong password is not told apart from a closed port
+        if !rcon.authenticate(sender.ip(), password, now) {continue}
+        println!("rcon ({}): {}", sender, line);
+        commands.send(ConsoleCommand{ line: line.to_string(), reply_to: Some(sender) });
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run_console_commands(
+    mut commands: EventReader<ConsoleCommand>,
+    mut restarts: EventWriter<RestartMatch>,
+    mut bot_commands: EventWriter<BotCommand>,
+    mut demo_commands: EventWriter<DemoCommand>,
+    mut server: GameServer,
+    transport: Res<NetcodeServerTransport>,
+    netsim: Option<Res<NetSim>>,
+    lobby: Res<Lobby>,
+    spectators: Res<Spectators>,
+    players_q: Query<(&PlayerName, &Team, &Player, Has<Bot>)>,
+    mut bans: ResMut<BanList>,
+    mut cvars: ResMut<Cvars>,
+    maze: Res<Maze>,
+    rcon: Option<Res<Rcon>>,
+    time: Res<Time>,
+){
+    for command in commands.iter(){
+        let line = command.line.trim();
+        if line.is_empty() {continue}
+        let (name, args) = line.split_once(' ').map_or((line, ""), |(name, args)| (name, args.trim()));
+
+        let output = match (name, args){
+            ("help", _) => HELP.to_string(),
+
+            ("status", _) => {
+                let mut output = format!(
+                    "map: {}, mode: {}, players: {}, spectators: {}",
+                    maze.name, cvars.mode.name(), lobby.players.len(), spectators.names.len()
+                );
+                for (client_id, &entity) in lobby.players.iter(){
+                    let Ok((PlayerName(name), team, player, bot)) = players_q.get(entity) else {continue};
+                    let address = if bot {"bot".to_string()} else {address(&transport, netsim.as_deref(), *client_id)};
+                    output += &format!("\n  {} {:16} {:?} {:>4.0} hp  {}", client_id, name, team, player.hp.max(0.), address);
+                    output += &network(&server, *client_id);
+                }
+                for (client_id, name) in spectators.names.iter(){
+                    output += &format!("\n  {} {:16} spectator  {}", client_id, name, address(&transport, netsim.as_deref(), *client_id));
+                    output += &network(&server, *client_id);
+                }
+                output
+            }
+
+            ("kick", id) => match parse_client(id, &lobby, &spectators){
+                Some(client_id) if lobby.players.get(&client_id).is_some_and(|&entity| players_q.get(entity).is_ok_and(|(.., bot)| bot)) => {
+                    bot_commands.send(BotCommand::Kick(client_id));
+                    format!("kicked {}", client_id)
+                }
+                Some(client_id) => {
+                    server.disconnect(client_id);
+                    format!("kicked {}", client_id)
+                }
+                None => format!("no client with the id {}", id),
+            }
+
+            ("ban", target) => {
+                if let Ok(ip) = target.parse::<IpAddr>(){
+                    // disconnect everyone connected from this address
+                    for client_id in server.clients_id(){
+                        if client_addr(&transport, netsim.as_deref(), client_id).is_some_and(|addr| addr.ip() == ip){
+                            server.disconnect(client_id);
+                        }
+                    }
+                    bans.ips.insert(ip);
+                    bans.save();
+                    format!("banned {}", ip)
+                }else if let Some(client_id) = parse_client(target, &lobby, &spectators){
+                    // the client id changes at each connection, the address is banned too
+                    if let Some(addr) = client_addr(&transport, netsim.as_deref(), client_id){
+                        bans.ips.insert(addr.ip());
+                    }
+                    bans.ids.insert(client_id);
+                    bans.save();
+                    server.disconnect(client_id);
+                    format!("banned {}", client_id)
+                }else{
+                    format!("no client or ip address {}", target)
+                }
+            }
+
+            ("unban", target) => {
+                let removed = match (target.parse::<IpAddr>(), target.parse::<u64>()){
+                    (Ok(ip), _) => bans.ips.remove(&ip),
+                    (_, Ok(client_id)) => bans.ids.remove(&client_id),
+                    _ => false,
+                };
+                if removed{
+                    bans.save();
+                    format!("unbanned {}", target)
+                }else{
+                    format!("{} is not banned", target)
+                }
+            }
+
+            ("map", "") => format!("map: {}, available: {}", maze.name, available_maps().join(", ")),
+            ("map", name) => match Maze::load(name){
+                Ok(new_maze) => {
+                    restarts.send(RestartMatch{ maze: Some(new_maze), notice: Notice::MapChanged { name: name.to_string() } });
+                    format!("changing the map to {}", name)
+                }
+                Err(e) => e,
+            }
+
+            ("mode", "") => {
+                let modes: Vec<&str> = GameMode::ALL.iter().map(|mode| mode.name()).collect();
+                format!("mode: {}, available: {}", cvars.mode.name(), modes.join(", "))
+            }
+            ("mode", name) => match GameMode::from_name(name){
+                Some(mode) => {
+                    cvars.mode = mode;
+                    restarts.send(RestartMatch{ maze: None, notice: Notice::ModeChanged { mode } });
+                    format!("mode set to {}", name)
+                }
+                None => format!("unknown mode: {}", name),
+            }
+
+            ("restart", _) => {
+                restarts.send(RestartMatch{ maze: None, notice: Notice::MatchRestarted });
+                "restarting the match".to_string()
+            }
+
+            ("say", "") => "usage: say <text>".to_string(),
+            ("say", text) => {
+                server.broadcast_message(DefaultChannel::ReliableOrdered, server_chat_message(text.to_string(), time.elapsed_seconds()));
+                format!("[chat] server: {}", text)
+            }
+
+            ("bot", args) => {
+                let (action, count) = args.split_once(' ').unwrap_or((args, "1"));
+                match (action, count.trim().parse::<usize>()){
+                    ("add", Ok(count)) => {
+                        bot_commands.send(BotCommand::Add(count));
+                        format!("adding {} bot(s)", count)
+                    }
+                    ("remove", Ok(count)) => {
+                        bot_commands.send(BotCommand::Remove(count));
+                        format!("removing {} bot(s)", count)
+                    }
+                    _ => "usage: bot add|remove [count]".to_string(),
+                }
+            }
+
+            // the name is a file name, not a path
+            ("record", name) if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+                "usage: record [name], with letters, digits, - and _".to_string()
+            }
+            ("record", name) => {
+                let path = demo_path((!name.is_empty()).then_some(name), &maze.name);
+                demo_commands.send(DemoCommand::Record(path.clone()));
+                format!("recording {}", path.display())
+            }
+
+            ("stop", _) => {
+                demo_commands.send(DemoCommand::Stop);
+                "stopping the recording".to_string()
+            }
+
+            ("set", "") => Cvars::NAMES.iter()
+                .map(|name| format!("{} = {}", name, cvars.get(name).unwrap_or_default()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            ("set", args) => match args.split_once(' '){
+                Some((name, value)) => match cvars.set(name, value.trim()){
+                    Ok(()) => format!("{} = {}", name, value.trim()),
+                    Err(e) => e,
+                }
+                None => cvars.get(args).map_or_else(|| format!("unknown cvar: {}", args), |value| format!("{} = {}", args, value)),
+            }
+
+            _ => format!("unknown command: {}, type help for the list of commands", name),
+        };
+
+        println!("{}", output);
+        if let (Some(rcon), Some(addr)) = (&rcon, command.reply_to){
+            let _ = rcon.socket.send_to(output.as_bytes(), addr);
+        }
+    }
+}
+
+/// not a bevy system. A connected client (player or spectator) from its id
+fn parse_client(id: &str, lobby: &Lobby, spectators: &Spectators) -> Option<u64>{
+    let client_id = id.parse::<u64>().ok()?;
+    (lobby.players.contains_key(&client_id) || spectators.names.contains_key(&client_id)).then_some(client_id)
+}
+
+/// not a bevy system
+fn address(transport: &NetcodeServerTransport, netsim: Option<&NetSim>, client_id: u64) -> String{
+    client_addr(transport, netsim, client_id).map_or_else(|| "?".to_string(), |addr| addr.to_string())
+}
+
+/// not a bevy system. Ping, loss and bandwidth of a client for the status, nothing for the bots
+fn network(server: &RenetServer, client_id: u64) -> String{
+    server.network_info(client_id).map_or_else(|_| String::new(), |info| format!("  {}", describe_network(&info)))
+}
+
+/// not a bevy system. The default map and the map files
+fn available_maps() -> Vec<String>{
+    let mut maps = vec![DEFAULT_MAP.to_string()];
+    let Ok(entries) = fs::read_dir("assets/maps") else {return maps};
+    for entry in entries.flatten(){
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "ron"){
+            if let Some(name) = path.file_stem(){
+                maps.push(name.to_string_lossy().to_string());
+            }
+        }
+    }
+    maps
+}
+
+/// respawn every player, on the new map if it changed
+pub fn restart_match(
+    mut cmd: Commands,
+    mut restarts: EventReader<RestartMatch>,
+    mut server: GameServer,
+    maze: Res<Maze>,
+    lobby: Res<Lobby>,
+    mut match_state: ResMut<MatchState>,
+){
+    // several restarts in the same frame : the last one wins
+    let Some(restart) = restarts.iter().last() else {return};
+
+    let maze = restart.maze.clone().unwrap_or_else(|| maze.clone());
+    let message = bincode::serialize(&ServerMessages::map(&maze)).unwrap();
+    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+    // the walls and the pickups are spawned again (see world.rs and pickup.rs)
+    cmd.insert_resource(maze);
+
+    // the players respawn right away (see death.rs)
+    for &entity in lobby.players.values(){
+        cmd.entity(entity).insert(RespawnTimer(0.));
+    }
+    *match_state = MatchState::default();
+
+    let message = bincode::serialize(&ServerMessages::Notice(restart.notice.clone())).unwrap();
+    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn rcon() -> Rcon{
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        Rcon{ socket, password: "secret".to_string(), failures: HashMap::new() }
+    }
+
+    #[test]
+    fn lockout_after_wrong_passwords(){
+        let mut rcon = rcon();
+        let sender = IpAddr::from([10, 0, 0, 1]);
+        for _ in 0..RCON_MAX_FAILURES{
+            assert!(!rcon.authenticate(sender, "guess", 0.));
+        }
+        // even the right password is ignored until the end of the lockout
+        assert!(!rcon.authenticate(sender, "secret", 1.));
+        assert!(rcon.authenticate(IpAddr::from([10, 0, 0, 2]), "secret", 1.));
+        assert!(rcon.authenticate(sender, "secret", RCON_LOCKOUT + 1.));
+        assert!(rcon.failures.is_empty());
+    }
+
+    #[test]
+    fn forget_old_failures(){
+        let mut rcon = rcon();
+        for i in 0..=255{
+            rcon.authenticate(IpAddr::from([10, 0, 0, i]), "guess", 0.);
+        }
+        assert_eq!(rcon.failures.len(), 256);
+        rcon.authenticate(IpAddr::from([10, 0, 1, 0]), "guess", RCON_LOCKOUT);
+        assert_eq!(rcon.failures.len(), 1);
+
+        for i in 0..RCON_MAX_ADDRESSES as u32 * 2{
+            rcon.authenticate(IpAddr::from(i.to_be_bytes()), "guess", RCON_LOCKOUT);
+        }
+        assert_eq!(rcon.failures.len(), RCON_MAX_ADDRESSES);
+    }
+}
@@ -0,0 +1,124 @@
+//! LAN server discovery, used by the server browser of the main menu.
+//! The clients broadcast a request on the discovery port, every server of the
+//! local network answers with its game port and the number of players.
+use super::mods::*;
+use std::net::{SocketAddr, Ipv4Addr};
+
+pub const GAME_PORT: u16 = 5000;
+pub const DISCOVERY_PORT: u16 = 5001;
+// the request sent by the clients, anything else is ignored
+const DISCOVERY_REQUEST: &[u8] = b"multiplayer-fps discovery";
+// time (in seconds) between two requests while the server browser is open
+const DISCOVERY_INTERVAL: f32 = 2.;
+pub const MAX_PLAYERS: usize = 64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo{
+    pub game_port: u16,
+    pub players: usize,
+    pub max_players: usize,
+}
+
+//////////////////////////// SERVER ///////////////////////////////
+
+#[derive(Resource)]
+pub struct DiscoveryResponder(UdpSocket);
+
+impl DiscoveryResponder{
+    /// returns None if the port is already used (another server on the same machine ...)
+    pub fn new() -> Option<Self>{
+        let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)){
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("ERR: cannot bind the discovery port {}, the server won't be visible in the server browser: {}", DISCOVERY_PORT, e);
+                return None;
+            }
+        };
+        socket.set_nonblocking(true).ok()?;
+        Some(Self(socket))
+    }
+}
+
+pub fn answer_discovery_requests(
+    responder: Res<DiscoveryResponder>,
+    lobby: Res<Lobby>,
+){
+    let mut buffer = [0; 64];
+
+    while let Ok((size, sender)) = responder.0.recv_from(&mut buffer){
+        if &buffer[..size] != DISCOVERY_REQUEST {continue}
+
+        let info = ServerInfo{
+            game_port: GAME_PORT,
+            players: lobby.players.len(),
+            max_players: MAX_PLAYERS,
+        };
+        let message = bincode::serialize(&info).unwrap();
+        let _ = responder.0.send_to(&message, sender);
+    }
+}
+
+//////////////////////////// CLIENT ///////////////////////////////
+
+/// the servers found on the local network
+#[derive(Resource, Default)]
+pub struct ServerBrowser{
+    socket: Option<UdpSocket>,
+    // time since the last request
+    timer: f32,
+    /// game address of the server -> server info
+    pub servers: HashMap<SocketAddr, ServerInfo>,
+}
+
+impl ServerBrowser{
+    /// not a bevy system. Start (or restart) looking for servers
+    pub fn open(&mut self){
+        self.servers.clear();
+        // send a request right away
+        self.timer = DISCOVERY_INTERVAL;
+
+        if self.socket.is_some() {return}
+
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| {
+                socket.set_broadcast(true)?;
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            });
+
+        match socket{
+            Ok(socket) => self.socket = Some(socket),
+            Err(e) => eprintln!("ERR: cannot open the server browser socket: {}", e),
+        }
+    }
+
+    pub fn close(&mut self){
+        self.socket = None;
+    }
+
+    pub fn is_open(&self) -> bool{
+        self.socket.is_some()
+    }
+}
+
+pub fn discover_servers(
+    mut browser: ResMut<ServerBrowser>,
+    time: Res<Time>,
+){
+    let browser = &mut *browser;
+    let Some(socket) = &browser.socket else {return};
+
+    browser.timer += time.delta_seconds();
+    if browser.timer >= DISCOVERY_INTERVAL{
+        browser.timer = 0.;
+        if let Err(e) = socket.send_to(DISCOVERY_REQUEST, (Ipv4Addr::BROADCAST, DISCOVERY_PORT)){
+            eprintln!("ERR: cannot send the discovery request: {}", e);
+        }
+    }
+
+    let mut buffer = [0; 64];
+    while let Ok((size, sender)) = socket.recv_from(&mut buffer){
+        let Ok(info) = bincode::deserialize::<ServerInfo>(&buffer[..size]) else {continue};
+        browser.servers.insert(SocketAddr::new(sender.ip(), info.game_port), info);
+    }
+}
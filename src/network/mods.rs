@@ -1,6 +1,6 @@
 pub use bevy::prelude::*;
 pub use bevy::prelude::{shape::Plane, *};
-use bevy::window::{WindowMode, WindowLevel, WindowTheme};
+use bevy::window::{WindowLevel, WindowTheme};
 pub use bevy_renet::{
     renet::{
         transport::{ClientAuthentication, ServerAuthentication, ServerConfig},
@@ -10,7 +10,7 @@ pub use bevy_renet::{
     RenetClientPlugin, RenetServerPlugin,
 };
 pub use bevy_renet::renet::transport::
-    {NetcodeClientTransport, NetcodeServerTransport, NetcodeTransportError};
+    {NetcodeClientTransport, NetcodeServerTransport, NetcodeTransportError, NETCODE_USER_DATA_BYTES};
 
 pub use std::time::SystemTime;
 pub use std::{collections::HashMap, net::UdpSocket};
@@ -20,10 +20,21 @@ pub use serde::{Deserialize, Serialize};
 
 use crate::game::*;
 use crate::player::{player_input, PlayerPlugin};
+use crate::pickup::{spawn_pickups, pickup_system, respawn_pickups, animate_pickups};
+use crate::sounds::{emit_footsteps, despawn_silent_sources};
+use crate::death::{start_respawn_timers, respawn_players, play_kill_cam};
+use crate::chat::{chat_closed, receive_chat_messages, ChatFilters};
+use crate::world::Maze;
+use crate::bots::{BotCommand, spawn_configured_bots, handle_bot_commands, update_bots};
+use crate::netgraph::server_net_overlay;
+use crate::demo::{DemoCommand, handle_demo_commands};
 
 pub use super::{
     client::*,
     server::*,
+    discovery::*,
+    console::*,
+    netsim::*,
 };
 
 ////////////////////// RESOURCES, CONSTS, COMPONENTS ...
@@ -35,18 +46,51 @@ pub struct Lobby {
     pub players: HashMap<u64, Entity>,
 }
 
+/// the clients watching the game, they are connected but have no player in the lobby (server side)
+#[derive(Debug, Default, Resource)]
+pub struct Spectators {
+    pub names: HashMap<u64, String>,
+}
+
 #[derive(Resource)]
 pub struct ClientId(pub u64);
 
+pub const MAX_NAME_LENGTH: usize = 16;
+
+// the last byte of the user data is 1 when the client joins as a spectator
+const SPECTATOR_BYTE: usize = NETCODE_USER_DATA_BYTES - 1;
+
+/// not a bevy system. The player's name is sent to the server in the netcode user data :
+/// the first byte is the length of the name, followed by the name (utf8)
+pub fn player_name_to_user_data(name: &str, spectator: bool) -> [u8; NETCODE_USER_DATA_BYTES]{
+    let name: String = name.chars().take(MAX_NAME_LENGTH).collect();
+    let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+    user_data[0] = name.len() as u8;
+    user_data[1..=name.len()].copy_from_slice(name.as_bytes());
+    user_data[SPECTATOR_BYTE] = spectator as u8;
+    user_data
+}
+
+/// not a bevy system
+pub fn is_spectator_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> bool{
+    user_data[SPECTATOR_BYTE] == 1
+}
+
+/// not a bevy system
+pub fn player_name_from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> String{
+    let len = (user_data[0] as usize).min(NETCODE_USER_DATA_BYTES - 1);
+    String::from_utf8_lossy(&user_data[1..=len]).to_string()
+}
+
 ///////////////////////// PLUGIN 
 pub struct ClientPlugin;
 
 impl Plugin for ClientPlugin{
     fn build(&self, app: &mut App) {
-        // set window
+        // set window, the mode and resolution come from the user's settings (see settings.rs)
         app.add_plugins(DefaultPlugins.set(WindowPlugin{
             primary_window: Some(Window{
-                mode: WindowMode::BorderlessFullscreen,
+                title: "Shadow Showdown".to_owned(),
                 ..default()
             }),
             ..default()
@@ -57,28 +101,43 @@ impl Plugin for ClientPlugin{
         app.add_state::<AppState>();
 
         // client initialization
+        // the client itself is created when the player joins a server (see menu.rs)
         app.add_plugins(RenetClientPlugin);
         app.add_plugins(NetcodeClientPlugin);
-        let (client, client_id,transport) = new_renet_client();
-        app.insert_resource(client);
-        app.insert_resource(client_id);
-        app.insert_resource(transport);
+        app.init_resource::<ServerBrowser>();
 
         // client sender/listener systems
+        app.init_resource::<ServerInbox>();
         app.add_systems(Update,(
-            player_input.run_if(in_state(AppState::InGame)),
-            toggle_game_menu,
-            panic_on_error_system,
-            client_connections_handler,
-            client_sync_players,
+            player_input.run_if(in_state(AppState::InGame)).run_if(chat_closed),
+            toggle_game_menu.run_if(chat_closed),
+            client_receive_messages,
             client_send_input.run_if(in_state(AppState::InGame)),
-            dead_screen.run_if(in_state(AppState::DeathScreen))
         )
         .run_if(client_connected()));
+
+        // the messages come from the server, or from a demo (see demo.rs)
+        app.add_systems(Update,(
+            client_connections_handler.after(client_receive_messages),
+            // the players connected in this frame are in the lobby before their snapshots
+            client_sync_players.after(client_connections_handler),
+            play_kill_cam.after(client_sync_players).run_if(in_state(AppState::DeathScreen)),
+        ));
+
+        app.add_systems(Update, (
+            animate_pickups,
+            discover_servers,
+            despawn_silent_sources,
+        ));
     }
 }
 
-pub struct ServerPlugin;
+/// the network simulator is enabled by the command line flags (see netsim.rs)
+pub struct ServerPlugin{
+    pub netsim: Option<NetSimConfig>,
+    /// rcon listens on every interface, not only localhost (see console.rs)
+    pub rcon_public: bool,
+}
 
 
 impl Plugin for ServerPlugin{
@@ -95,21 +154,74 @@ impl Plugin for ServerPlugin{
             ..default()
         }));
         app.init_resource::<Lobby>();
+        app.init_resource::<Spectators>();
+        app.init_resource::<ChatFilters>();
 
         // server initialization
         app.add_plugins(RenetServerPlugin);
         app.add_plugins(NetcodeServerPlugin);
-        let (server, transport) = new_renet_server();
+        let (server, transport, netsim) = new_renet_server(self.netsim);
         app.insert_resource(server);
         app.insert_resource(transport);
+        if let Some(netsim) = netsim{
+            app.insert_resource(netsim);
+            app.add_systems(Update, netsim_overlay);
+        }
 
         // server listener/sender systems
         app.add_systems(Update,(
             server_receive_events,
             receive_user_input,
             server_sync_players,
+            pickup_system,
+            respawn_pickups,
+            emit_footsteps,
+            broadcast_sounds.after(pickup_system).after(emit_footsteps),
+            send_hit_feedback,
+            broadcast_kills,
+            start_respawn_timers,
+            respawn_players,
+            receive_chat_messages,
+            spawn_pickups.run_if(resource_changed::<Maze>()),
         ).run_if(resource_exists::<RenetServer>()),);
 
+        // ping, loss and bandwidth of each client (see netgraph.rs)
+        app.add_systems(Update, server_net_overlay.run_if(resource_exists::<RenetServer>()));
+
+        // server console, on stdin and optionally with rcon
+        app.init_resource::<Cvars>();
+        app.init_resource::<MatchState>();
+        app.insert_resource(BanList::load());
+        app.add_event::<ConsoleCommand>();
+        app.add_event::<RestartMatch>();
+        app.insert_resource(ConsoleInput::start());
+        if let Some(rcon) = Rcon::new(self.rcon_public){
+            app.insert_resource(rcon);
+        }
+        app.add_systems(Update, (
+            read_console_commands,
+            run_console_commands.after(read_console_commands),
+            restart_match.after(run_console_commands),
+        ).run_if(resource_exists::<RenetServer>()));
+
+        // demos, recorded from the console
+        app.add_event::<DemoCommand>();
+        app.add_systems(Update, handle_demo_commands.after(run_console_commands));
+
+        // bots, added from the config file or the console
+        app.add_event::<BotCommand>();
+        app.add_systems(Startup, spawn_configured_bots);
+        app.add_systems(Update, (
+            handle_bot_commands.after(run_console_commands),
+            update_bots,
+        ).run_if(resource_exists::<RenetServer>()));
+
+        // answer the server browsers of the local network
+        if let Some(responder) = DiscoveryResponder::new(){
+            app.insert_resource(responder);
+            app.add_systems(Update, answer_discovery_requests);
+        }
+
         // handle players
         app.add_plugins(PlayerPlugin);
         
@@ -0,0 +1,390 @@
+//! Network simulator : a UDP proxy in front of the server that delays, drops, duplicates
+//! and reorders the packets, to reproduce bad networks locally.
+//! The proxy listens on the game port and forwards to the real server socket (bound on
+//! another port), so the clients connect as usual. Enabled by the CLI flags of the server
+//! (`--latency 100 --jitter 20 --loss 0.05 ...`), tuned with the overlay of the server window.
+//! The server sees the proxy's sockets instead of the clients, `client_addr` gives their real address.
+use super::mods::*;
+use bevy_inspector_egui::bevy_egui::{EguiContexts, egui};
+use rand::Rng;
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+//////////////////////////// CONFIG ///////////////////////////////
+
+// the proxy forgets the clients silent for this time
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+// time the proxy thread sleeps when there is nothing to do
+const IDLE_SLEEP: Duration = Duration::from_micros(500);
+// max size of a netcode packet
+const MAX_PACKET_SIZE: usize = 1400;
+
+/// the conditions applied to every packet, in both directions
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetSimConfig{
+    /// round trip time added (in milliseconds), half of it on each direction
+    pub latency: f32,
+    /// random variation (in milliseconds) of the delay of each packet
+    pub jitter: f32,
+    /// probabilities, between 0 and 1
+    pub loss: f32,
+    pub duplicate: f32,
+    /// the reordered packets arrive after the next ones
+    pub reorder: f32,
+}
+
+impl NetSimConfig{
+    /// the flags after `server`, None if no flag is given (the simulator is disabled)
+    pub fn from_args(args: &[String]) -> Result<Option<Self>, String>{
+        if args.is_empty() {return Ok(None)}
+
+        let mut config = Self::default();
+        let mut args = args.iter();
+        while let Some(flag) = args.next(){
+            let value = args.next()
+                .and_then(|value| value.parse::<f32>().ok())
+                .filter(|value| *value >= 0.)
+                .ok_or_else(|| format!("{} needs a positive number", flag))?;
+            let probability = || if value <= 1. {Ok(value)} else {Err(format!("{} is a probability, between 0 and 1", flag))};
+
+            match flag.as_str(){
+                "--latency" => config.latency = value,
+                "--jitter" => config.jitter = value,
+                "--loss" => config.loss = probability()?,
+                "--duplicate" => config.duplicate = probability()?,
+                "--reorder" => config.reorder = probability()?,
+                _ => return Err(format!("unknown flag: {} (--latency, --jitter, --loss, --duplicate, --reorder)", flag)),
+            }
+        }
+        Ok(Some(config))
+    }
+
+    /// the one-way delay of a packet, None if it is lost
+    fn delay(&self, rng: &mut impl Rng) -> Option<Duration>{
+        if rng.gen::<f32>() < self.loss {return None}
+        let jitter = if self.jitter > 0. {rng.gen_range(-self.jitter..self.jitter) / 2.} else {0.};
+        let milliseconds = (self.latency / 2. + jitter).max(0.);
+        Some(Duration::from_secs_f32(milliseconds / 1000.))
+    }
+}
+
+/// what the proxy did since it started
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetSimStats{
+    pub forwarded: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub clients: usize,
+}
+
+/// shared with the proxy thread, the config can be changed while it runs
+#[derive(Resource, Clone)]
+pub struct NetSim{
+    pub config: Arc<Mutex<NetSimConfig>>,
+    pub stats: Arc<Mutex<NetSimStats>>,
+    /// the address the clients connect to
+    pub addr: SocketAddr,
+    /// the real address of each client, by the address of its socket to the server
+    clients: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+}
+
+//////////////////////////// PROXY ///////////////////////////////
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Direction{
+    ToServer,
+    ToClient,
+}
+
+/// a packet waiting for its delivery time
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct DelayedPacket{
+    deliver_at: Instant,
+    /// keeps the order of the packets delivered at the same time
+    sequence: u64,
+    client: SocketAddr,
+    direction: Direction,
+    data: Vec<u8>,
+}
+
+/// a client of the proxy, with its own socket to the server
+struct ProxyClient{
+    socket: UdpSocket,
+    last_seen: Instant,
+    /// the packets are delivered in order (unless reordered), even with jitter
+    last_delivery: [Instant; 2],
+}
+
+struct Proxy{
+    public_socket: UdpSocket,
+    server_addr: SocketAddr,
+    clients: HashMap<SocketAddr, ProxyClient>,
+    queue: BinaryHeap<Reverse<DelayedPacket>>,
+    sequence: u64,
+    netsim: NetSim,
+}
+
+impl NetSim{
+    /// listen on `public_addr` and forward to the server socket at `server_addr`, in another thread.
+    /// With the port 0, the proxy listens on any free port (see `addr`)
+    pub fn start(public_addr: SocketAddr, server_addr: SocketAddr, config: NetSimConfig) -> std::io::Result<Self>{
+        let public_socket = UdpSocket::bind(public_addr)?;
+        public_socket.set_nonblocking(true)?;
+
+        let netsim = Self{
+            config: Arc::new(Mutex::new(config)),
+            stats: Arc::new(Mutex::new(NetSimStats::default())),
+            addr: public_socket.local_addr()?,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let mut proxy = Proxy{
+            public_socket,
+            server_addr,
+            clients: HashMap::new(),
+            queue: BinaryHeap::new(),
+            sequence: 0,
+            netsim: netsim.clone(),
+        };
+        thread::spawn(move || loop{
+            if !proxy.update() {thread::sleep(IDLE_SLEEP)}
+        });
+
+        println!("network simulator: {} -> {} {:?}", netsim.addr, server_addr, config);
+        Ok(netsim)
+    }
+
+    /// the real address of a client, from the address the server sees (a socket of the proxy)
+    pub fn client_addr(&self, proxy_addr: SocketAddr) -> Option<SocketAddr>{
+        self.clients.lock().ok()?.get(&proxy_addr).copied()
+    }
+}
+
+/// not a bevy system. The address of a client, None if unknown. With the network simulator,
+/// the address of the proxy's socket is never returned, so the server IP cannot be banned by mistake
+pub fn client_addr(transport: &NetcodeServerTransport, netsim: Option<&NetSim>, client_id: u64) -> Option<SocketAddr>{
+    let addr = transport.client_addr(client_id)?;
+    match netsim{
+        Some(netsim) => netsim.client_addr(addr),
+        None => Some(addr),
+    }
+}
+
+impl Proxy{
+    /// receive, delay and send the packets. Returns false if there was nothing to do
+    fn update(&mut self) -> bool{
+        let mut busy = false;
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let now = Instant::now();
+
+        // from the clients, until nothing is left to read (or the error of a client, connection reset ...)
+        while let Ok((size, client)) = self.public_socket.recv_from(&mut buffer){
+            if !self.clients.contains_key(&client){
+                let Some(socket) = self.connect_to_server() else {continue};
+                if let (Ok(proxy_addr), Ok(mut addrs)) = (socket.local_addr(), self.netsim.clients.lock()){
+                    addrs.insert(proxy_addr, client);
+                }
+                self.clients.insert(client, ProxyClient{ socket, last_seen: now, last_delivery: [now; 2] });
+            }
+            if let Some(proxy_client) = self.clients.get_mut(&client){
+                proxy_client.last_seen = now;
+            }
+            self.schedule(client, Direction::ToServer, &buffer[..size]);
+            busy = true;
+        }
+
+        // from the server
+        let clients: Vec<SocketAddr> = self.clients.keys().copied().collect();
+        for client in clients{
+            while let Some(Ok(size)) = self.clients.get(&client).map(|proxy_client| proxy_client.socket.recv(&mut buffer)){
+                self.schedule(client, Direction::ToClient, &buffer[..size]);
+                busy = true;
+            }
+        }
+
+        // deliver the packets on time
+        while self.queue.peek().is_some_and(|Reverse(packet)| packet.deliver_at <= now){
+            let Some(Reverse(packet)) = self.queue.pop() else {break};
+            let Some(proxy_client) = self.clients.get(&packet.client) else {continue};
+            let _ = match packet.direction{
+                Direction::ToServer => proxy_client.socket.send(&packet.data),
+                Direction::ToClient => self.public_socket.send_to(&packet.data, packet.client),
+            };
+            busy = true;
+        }
+
+        self.clients.retain(|_, proxy_client| now - proxy_client.last_seen < CLIENT_TIMEOUT);
+        if let Ok(mut addrs) = self.netsim.clients.lock(){
+            addrs.retain(|_, client| self.clients.contains_key(client));
+        }
+        if let Ok(mut stats) = self.netsim.stats.lock(){
+            stats.clients = self.clients.len();
+        }
+        busy
+    }
+
+    /// a socket for a new client, the server sees the proxy's socket as the client
+    fn connect_to_server(&self) -> Option<UdpSocket>{
+        let socket = UdpSocket::bind((self.server_addr.ip(), 0))
+            .and_then(|socket| socket.connect(self.server_addr).map(|_| socket))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket));
+        match socket{
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                eprintln!("ERR: network simulator, cannot connect to the server: {}", e);
+                None
+            }
+        }
+    }
+
+    /// add a packet (and its duplicate) to the queue, unless it is lost
+    fn schedule(&mut self, client: SocketAddr, direction: Direction, data: &[u8]){
+        let config = self.netsim.config.lock().map(|config| *config).unwrap_or_default();
+        let mut stats = NetSimStats::default();
+        let mut rng = rand::thread_rng();
+        let now = Instant::now();
+
+        let copies = if rng.gen::<f32>() < config.duplicate {2} else {1};
+        stats.duplicated += copies - 1;
+
+        for _ in 0..copies{
+            let Some(delay) = config.delay(&mut rng) else {
+                stats.lost += 1;
+                continue;
+            };
+            let Some(proxy_client) = self.clients.get_mut(&client) else {return};
+            let last_delivery = &mut proxy_client.last_delivery[direction as usize];
+
+            let deliver_at = if rng.gen::<f32>() < config.reorder{
+                // held back, the next packets overtake it
+                stats.reordered += 1;
+                now + delay + Duration::from_secs_f32((config.jitter.max(10.) * 2.) / 1000.)
+            }else{
+                // the jitter does not reorder the packets
+                let deliver_at = (now + delay).max(*last_delivery);
+                *last_delivery = deliver_at;
+                deliver_at
+            };
+
+            self.sequence += 1;
+            self.queue.push(Reverse(DelayedPacket{
+                deliver_at,
+                sequence: self.sequence,
+                client,
+                direction,
+                data: data.to_vec(),
+            }));
+            stats.forwarded += 1;
+        }
+
+        if let Ok(mut total) = self.netsim.stats.lock(){
+            total.forwarded += stats.forwarded;
+            total.lost += stats.lost;
+            total.duplicated += stats.duplicated;
+            total.reordered += stats.reordered;
+        }
+    }
+}
+
+//////////////////////////// OVERLAY ///////////////////////////////
+
+/// change the conditions while the server runs
+pub fn netsim_overlay(
+    mut contexts: EguiContexts,
+    netsim: Res<NetSim>,
+){
+    let Ok(mut config) = netsim.config.lock() else {return};
+    let stats = netsim.stats.lock().map(|stats| *stats).unwrap_or_default();
+
+    egui::Window::new("Network simulator").show(contexts.ctx_mut(), |ui|{
+        ui.add(egui::Slider::new(&mut config.latency, 0. ..=1000.).text("latency (ms)"));
+        ui.add(egui::Slider::new(&mut config.jitter, 0. ..=200.).text("jitter (ms)"));
+        ui.add(egui::Slider::new(&mut config.loss, 0. ..=1.).text("loss"));
+        ui.add(egui::Slider::new(&mut config.duplicate, 0. ..=1.).text("duplicate"));
+        ui.add(egui::Slider::new(&mut config.reorder, 0. ..=1.).text("reorder"));
+        if ui.button("Reset").clicked(){
+            *config = NetSimConfig::default();
+        }
+
+        ui.separator();
+        ui.label(format!("clients: {}", stats.clients));
+        ui.label(format!("forwarded: {}", stats.forwarded));
+        ui.label(format!("lost: {}, duplicated: {}, reordered: {}", stats.lost, stats.duplicated, stats.reordered));
+    });
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// a server socket behind the proxy, and a client socket sending to the proxy, on loopback
+    fn start(config: NetSimConfig) -> (NetSim, UdpSocket, UdpSocket){
+        let loopback = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let server = UdpSocket::bind(loopback).unwrap();
+        server.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let netsim = NetSim::start(loopback, server.local_addr().unwrap(), config).unwrap();
+        let client = UdpSocket::bind(loopback).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        client.connect(netsim.addr).unwrap();
+        (netsim, server, client)
+    }
+
+    #[test]
+    fn delay_in_order(){
+        let config = NetSimConfig{ latency: 200., jitter: 40., ..default() };
+        let (netsim, server, client) = start(config);
+        let mut buffer = [0; MAX_PACKET_SIZE];
+
+        let sent_at = Instant::now();
+        for i in 0..20u8{
+            client.send(&[i]).unwrap();
+        }
+        let mut proxy_addr = None;
+        for i in 0..20u8{
+            let (size, from) = server.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..size], &[i]);
+            proxy_addr = Some(from);
+        }
+        // half of the latency on each direction, minus half of the jitter
+        assert!(sent_at.elapsed() >= Duration::from_millis(80));
+
+        // the server sees a socket of the proxy, which knows the real client
+        let proxy_addr = proxy_addr.unwrap();
+        assert_ne!(proxy_addr, client.local_addr().unwrap());
+        assert_eq!(netsim.client_addr(proxy_addr), Some(client.local_addr().unwrap()));
+
+        let sent_at = Instant::now();
+        server.send_to(b"reply", proxy_addr).unwrap();
+        let size = client.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"reply");
+        assert!(sent_at.elapsed() >= Duration::from_millis(80));
+
+        let stats = *netsim.stats.lock().unwrap();
+        assert_eq!((stats.forwarded, stats.lost, stats.clients), (21, 0, 1));
+    }
+
+    #[test]
+    fn loss(){
+        let (netsim, server, client) = start(NetSimConfig{ loss: 1., ..default() });
+        let mut buffer = [0; MAX_PACKET_SIZE];
+
+        for i in 0..10u8{
+            client.send(&[i]).unwrap();
+        }
+        assert!(server.recv_from(&mut buffer).is_err());
+        assert_eq!(netsim.stats.lock().unwrap().lost, 10);
+
+        // the config changes while the proxy runs
+        netsim.config.lock().unwrap().loss = 0.;
+        client.send(&[10]).unwrap();
+        let (size, _) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..size], &[10]);
+    }
+}
@@ -1,26 +1,60 @@
-use crate::{player::{PlayerInput, PlayerId, PlayerState, PLAYER_SCALE, Player, get_spawn}, camera::{get_camera, CameraVerticalMotion}, weapons::Weapon, hitbox::Hitbox, world::Maze};
-use bevy_rapier3d::prelude::*;
+use crate::{team::{Team, smallest_team}, player::{PlayerInput, PlayerId, PlayerName, PlayerState, PLAYER_SCALE, Player, get_spawn}, camera::{get_camera, LookAngles}, weapons::{Weapon, WeaponKind}, hitbox::Hitbox, world::{Maze, Surface}, game::MatchState, pickup::{Pickup, PickupKind}, movement::character_controller_bundle, sounds::{EmitSound, SoundEvent}, shoot::PlayerHit, killfeed::Notice, bots::Bot, chat::ChatBroadcast, demo::GameServer};
 use std::time::*;
 use local_ip_address::local_ip;
 
 
-use super::mods::*;
+use super::{mods::*, discovery::{GAME_PORT, MAX_PLAYERS}, console::BanList, netsim::{NetSim, NetSimConfig}};
+use std::net::SocketAddr;
 
 #[derive(Debug, Serialize, Deserialize, Component)]
 pub enum ServerMessages {
-    PlayerConnected { id: u64 },
+    PlayerConnected { id: u64, name: String, team: Team },
     PlayerDisconnected { id: u64 },
+    PickupUpdate { id: u32, kind: PickupKind, translation: [f32;3], active: bool },
+    Sound { event: SoundEvent, translation: [f32;3], emitter: Option<u64> },
+    /// sent to a player hit by a bullet, `origin` is where the bullet came from
+    Damaged { origin: [f32;3], damage: f32 },
+    /// sent to the shooter when his bullet hits someone
+    HitConfirmed { headshot: bool, kill: bool },
+    /// broadcast when a player dies, shown in the kill feed
+    Kill { killer: u64, victim: u64, weapon: WeaponKind, headshot: bool, wallbang: bool },
+    /// connections and match events, shown to every player
+    Notice(Notice),
+    Chat(ChatBroadcast),
+    /// sent to a killed player, shown on the death screen
+    Died { killer: u64, weapon: WeaponKind, headshot: bool, killer_hp: f32, respawn_in: f32 },
+    /// the map played on the server, sent on connection and when it changes
+    Map { name: String, map: Vec<Vec<i32>>, surfaces: Vec<Vec<Surface>> },
 }
 
-// server setup
-pub fn new_renet_server() -> (RenetServer, NetcodeServerTransport) {
+impl ServerMessages{
+    /// not a bevy system
+    pub fn map(maze: &Maze) -> Self{
+        Self::Map { name: maze.name.clone(), map: maze.map.clone(), surfaces: maze.surfaces.clone() }
+    }
+}
+
+// server setup. With the network simulator, the clients connect to its proxy
+// on the game port, and the proxy forwards to the server's socket (see netsim.rs)
+pub fn new_renet_server(netsim: Option<NetSimConfig>) -> (RenetServer, NetcodeServerTransport, Option<NetSim>) {
     let server = RenetServer::new(ConnectionConfig::default());
-    let my_local_ip = local_ip().unwrap().to_string() + ":5000";
-    let public_addr = my_local_ip.parse().unwrap();
-    let socket = UdpSocket::bind(public_addr).unwrap();
+    let public_addr = SocketAddr::new(local_ip().unwrap(), GAME_PORT);
+    let (socket, netsim) = match netsim{
+        Some(config) => {
+            let socket = UdpSocket::bind((public_addr.ip(), 0)).unwrap();
+            match NetSim::start(public_addr, socket.local_addr().unwrap(), config){
+                Ok(netsim) => (socket, Some(netsim)),
+                Err(e) => {
+                    eprintln!("ERR: cannot start the network simulator on {}: {}", public_addr, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => (UdpSocket::bind(public_addr).unwrap(), None),
+    };
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
     let server_config = ServerConfig {
-        max_clients: 64,
+        max_clients: MAX_PLAYERS,
         protocol_id: PROTOCOL_ID,
         public_addr: public_addr,
         authentication: ServerAuthentication::Unsecure,
@@ -28,81 +62,106 @@ pub fn new_renet_server() -> (RenetServer, NetcodeServerTransport) {
 
     let transport = NetcodeServerTransport::new(current_time,server_config, socket).unwrap();
 
-    (server, transport)
+    (server, transport, netsim)
 }
 
 /// handle clients connection/disconnection
+#[allow(clippy::too_many_arguments)]
 pub fn server_receive_events(
     mut server_events: EventReader<ServerEvent>,
-    mut server: ResMut<RenetServer>,
+    mut server: GameServer,
     mut commands: Commands,
     mut lobby: ResMut<Lobby>,
+    mut spectators: ResMut<Spectators>,
     assets: Res<AssetServer>,
-    maze: Res<Maze>
+    maze: Res<Maze>,
+    pickup_q: Query<(&Pickup, &Transform)>,
+    names_q: Query<(&PlayerName, &Team)>,
+    bots_q: Query<(), With<Bot>>,
+    transport: Res<NetcodeServerTransport>,
+    netsim: Option<Res<NetSim>>,
+    bans: Res<BanList>,
 ) {
     for event in server_events.iter() {
         match event {
 
             ServerEvent::ClientConnected { client_id } => {
-                println!("Player {} connected.", client_id);
-
-                let spawn_position = get_spawn(lobby.players.len(), &maze);
-                let player_transform = 
-                    Transform::from_xyz(spawn_position.0, 1.5, spawn_position.1)
-                    .with_scale(PLAYER_SCALE*Vec3::ONE);
-
-                // Spawn player
-                let player_entity = commands.spawn((
-                    Name::new("Player1"),
-                    SceneBundle{
-                        scene: assets.load("character/player.glb#Scene0"),
-                        transform: player_transform,
-                        ..default()
-                    },
-                    PlayerInput::default(),
-
-                    CameraVerticalMotion(0.),
-                                        
-                    PlayerId { id: *client_id },
-                    Weapon::rifle(),
-                    Player::default(),
-
-                    RigidBody::Dynamic,
-                    Velocity{
-                        linvel: 0.1 * Vec3::ONE,
-                        ..default()
-                    },
-                    LockedAxes::ROTATION_LOCKED,
-                ))
-                // adding colliders and camera
-                .with_children(|parent|{
-                    parent.spawn(Hitbox::head());
-                    parent.spawn(Hitbox::body());
-                    parent.spawn(Hitbox::legs());
-                    parent.spawn(get_camera());
-                })
-                .id();
+                let addr = client_addr(&transport, netsim.as_deref(), *client_id);
+                if bans.is_banned(*client_id, addr.map(|addr| addr.ip())){
+                    println!("Player {} is banned, disconnecting.", client_id);
+                    server.disconnect(*client_id);
+                    continue;
+                }
+                // the player of a bot would be replaced, and its messages sent to the client
+                if lobby.players.get(client_id).is_some_and(|&entity| bots_q.contains(entity)){
+                    println!("Player {} has the id of a bot, disconnecting.", client_id);
+                    server.disconnect(*client_id);
+                    continue;
+                }
+
+                let name = transport.user_data(*client_id)
+                    .map(|user_data| player_name_from_user_data(&user_data))
+                    .unwrap_or_else(|| client_id.to_string());
+                let spectator = transport.user_data(*client_id)
+                    .is_some_and(|user_data| is_spectator_from_user_data(&user_data));
+                println!("Player {} ({}) connected.", client_id, name);
+
+                let message = bincode::serialize(&ServerMessages::map(&maze)).unwrap();
+                server.send_message(*client_id, DefaultChannel::ReliableOrdered, message);
 
                 // use the PlayerConnected event to send the list of the players that
                 // are already connected
-                for &player_id in lobby.players.keys() {
-                    let message = bincode::serialize(&ServerMessages::PlayerConnected { id: player_id }).unwrap();
+                for (&player_id, &entity) in lobby.players.iter() {
+                    let Ok((PlayerName(name), team)) = names_q.get(entity) else {continue};
+                    let message = bincode::serialize(&ServerMessages::PlayerConnected { id: player_id, name: name.clone(), team: *team }).unwrap();
+                    server.send_message(*client_id, DefaultChannel::ReliableOrdered, message);
+                }
+
+                // send the state of the pickups
+                for (pickup, transform) in pickup_q.iter(){
+                    let message = bincode::serialize(&pickup.to_message(transform)).unwrap();
                     server.send_message(*client_id, DefaultChannel::ReliableOrdered, message);
                 }
 
+                // the spectators only receive the state of the game, they have no player
+                if spectator{
+                    println!("Player {} is spectating.", client_id);
+                    spectators.names.insert(*client_id, name.clone());
+                    let message = bincode::serialize(&ServerMessages::Notice(Notice::Spectating { name })).unwrap();
+                    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                    continue;
+                }
+
+                let team = smallest_team(names_q.iter().map(|(_, team)| team));
+                let player_entity = spawn_player(&mut commands, &assets, &maze, lobby.players.len(), *client_id, name.clone(), team);
+
                 lobby.players.insert(*client_id, player_entity);
 
+                let message = bincode::serialize(&ServerMessages::Notice(Notice::Joined { name: name.clone() })).unwrap();
+                server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+
                 // broadcast the new client's id
-                let message = bincode::serialize(&ServerMessages::PlayerConnected { id: *client_id }).unwrap();
+                let message = bincode::serialize(&ServerMessages::PlayerConnected { id: *client_id, name, team }).unwrap();
                 server.broadcast_message(DefaultChannel::ReliableOrdered, message);
             }
 
             // disconnection -> despawn player and remove it from the HashMap
             ServerEvent::ClientDisconnected { client_id, reason } => {
                 println!("Player {} disconnected: {}", client_id, reason);
+                // a client rejected because of its id, the bot stays
+                if lobby.players.get(client_id).is_some_and(|&entity| bots_q.contains(entity)) {continue}
                 if let Some(player_entity) = lobby.players.remove(client_id) {
+                    if let Ok((PlayerName(name), _)) = names_q.get(player_entity){
+                        let message = bincode::serialize(&ServerMessages::Notice(Notice::Left { name: name.clone() })).unwrap();
+                        server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                    }
                     commands.entity(player_entity).despawn();
                 }
+                if let Some(name) = spectators.names.remove(client_id){
+                    let message = bincode::serialize(&ServerMessages::Notice(Notice::Left { name })).unwrap();
+                    server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+                    continue;
+                }
 
                 let message = bincode::serialize(&ServerMessages::PlayerDisconnected { id: *client_id }).unwrap();
                 server.broadcast_message(DefaultChannel::ReliableOrdered, message);
@@ -111,6 +170,51 @@ pub fn server_receive_events(
     }
 }
 
+/// not a bevy system. Spawn the entity of a player (connected client or bot, see bots.rs)
+/// in a corner of the maze, with its hitboxes and camera
+pub fn spawn_player(
+    commands: &mut Commands,
+    assets: &AssetServer,
+    maze: &Res<Maze>,
+    spawn_index: usize,
+    id: u64,
+    name: String,
+    team: Team,
+) -> Entity{
+    let spawn_position = get_spawn(spawn_index, maze);
+    let player_transform = 
+        Transform::from_xyz(spawn_position.0, 1.5, spawn_position.1)
+        .with_scale(PLAYER_SCALE*Vec3::ONE);
+
+    commands.spawn((
+        Name::new("Player1"),
+        SceneBundle{
+            scene: assets.load("character/player.glb#Scene0"),
+            transform: player_transform,
+            ..default()
+        },
+        PlayerInput::default(),
+
+        LookAngles::default(),
+                            
+        PlayerId { id },
+        PlayerName(name),
+        team,
+        Weapon::rifle(),
+        Player::default(),
+
+        character_controller_bundle(),
+    ))
+    // adding colliders and camera
+    .with_children(|parent|{
+        parent.spawn(Hitbox::head());
+        parent.spawn(Hitbox::body());
+        parent.spawn(Hitbox::legs());
+        parent.spawn(get_camera());
+    })
+    .id()
+}
+
 /// read user input and insert it in the player's entity
 pub fn receive_user_input(
     mut server: ResMut<RenetServer>,
@@ -118,26 +222,47 @@ pub fn receive_user_input(
     lobby: ResMut<Lobby>,
 ){
     for client_id in server.clients_id().into_iter() {
+        // several inputs can arrive in the same frame, keep the last one
+        // but don't lose the mouse motion nor the taps (click, jump, reload) of the others
+        let mut last_input: Option<PlayerInput> = None;
+        let mut mouse = Vec2::ZERO;
+        let (mut click, mut jump, mut reload) = (false, false, false);
+
         while let Some(message) = server.receive_message(client_id, DefaultChannel::ReliableOrdered) {
-            let player_input: PlayerInput = bincode::deserialize(&message)
-                .expect("[ERR]: player input deserialization failed");
-            
-            if let Some(player_entity) = lobby.players.get(&client_id) {
-                commands.entity(*player_entity).insert(player_input);
-            }
+            // an old or broken client, its input is ignored
+            let Ok(player_input) = bincode::deserialize::<PlayerInput>(&message) else {
+                eprintln!("ERR: invalid input from {}", client_id);
+                continue;
+            };
+
+            mouse += player_input.mouse;
+            click |= player_input.left_click_just_pressed;
+            jump |= player_input.jump;
+            reload |= player_input.reload;
+            last_input = Some(player_input);
+        }
+
+        let Some(mut player_input) = last_input else {continue};
+        player_input.mouse = mouse;
+        player_input.left_click_just_pressed = click;
+        player_input.jump = jump;
+        player_input.reload = reload;
+
+        if let Some(player_entity) = lobby.players.get(&client_id) {
+            commands.entity(*player_entity).insert(player_input);
         }
     }
 }
 
 // broadcast game state
 pub fn server_sync_players(
-    mut server: ResMut<RenetServer>,
-    player_q: Query<(&Transform, &PlayerId, &Player, &CameraVerticalMotion, &Children, &Weapon), Without<Camera>>,
+    mut server: GameServer,
+    player_q: Query<(&Transform, &PlayerId, &Player, &LookAngles, &Children, &Weapon), Without<Camera>>,
     camera_q: Query<&GlobalTransform, With<Camera>>
 ){
     let mut players: HashMap<u64, PlayerState> = HashMap::new();
 
-    for (transform, player_id, state, camera_rotation, children, weapon) in player_q.iter() {
+    for (transform, player_id, state, look, children, weapon) in player_q.iter() {
         for &child in children{
             if let Ok(camera_transform) = camera_q.get(child){
 
@@ -146,13 +271,13 @@ pub fn server_sync_players(
                 bullet_direction.y += weapon.spray_pattern[weapon.consecutive_shots][1];
 
 
-        let player_state = PlayerState{
-            translation: transform.translation.into(),
-            rotation: transform.rotation.into(),
-                    cam_vertical_motion: camera_rotation.0,
+                let player_state = PlayerState{
+                    translation: transform.translation.into(),
+                    yaw: look.yaw,
+                    pitch: look.pitch,
                     look_to: camera_transform.forward().into(),
                     state: state.clone(),
-        };       
+                };       
 
                 players.insert(player_id.id, player_state);
                 break;
@@ -162,4 +287,69 @@ pub fn server_sync_players(
 
     let sync_message = bincode::serialize(&players).unwrap();
     server.broadcast_message(DefaultChannel::Unreliable, sync_message);
-}
\ No newline at end of file
+}
+
+/// send the sounds made during this frame, once, to every client
+pub fn broadcast_sounds(
+    mut sounds: EventReader<EmitSound>,
+    mut server: GameServer,
+){
+    for sound in sounds.iter(){
+        let message = bincode::serialize(&ServerMessages::Sound {
+            event: sound.event,
+            translation: sound.position.into(),
+            emitter: sound.emitter
+        }).unwrap();
+        server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+    }
+}
+
+/// tell the shooter he hit someone, and the victim where the shot came from
+pub fn send_hit_feedback(
+    mut hits: EventReader<PlayerHit>,
+    mut server: ResMut<RenetServer>,
+    lobby: Res<Lobby>,
+    bots_q: Query<(), With<Bot>>,
+){
+    let is_bot = |id| lobby.players.get(&id).is_some_and(|&entity| bots_q.contains(entity));
+    for hit in hits.iter(){
+        // the bots have no client
+        if !is_bot(hit.victim){
+            let message = bincode::serialize(&ServerMessages::Damaged { origin: hit.origin.into(), damage: hit.damage }).unwrap();
+            server.send_message(hit.victim, DefaultChannel::ReliableOrdered, message);
+        }
+        if !is_bot(hit.shooter){
+            let message = bincode::serialize(&ServerMessages::HitConfirmed { headshot: hit.headshot, kill: hit.killed }).unwrap();
+            server.send_message(hit.shooter, DefaultChannel::ReliableOrdered, message);
+        }
+    }
+}
+
+/// tell everyone who killed who, the first kill of the match is announced
+pub fn broadcast_kills(
+    mut hits: EventReader<PlayerHit>,
+    mut server: GameServer,
+    lobby: Res<Lobby>,
+    names_q: Query<&PlayerName>,
+    mut match_state: ResMut<MatchState>,
+){
+    for hit in hits.iter().filter(|hit| hit.killed){
+        let message = bincode::serialize(&ServerMessages::Kill {
+            killer: hit.shooter,
+            victim: hit.victim,
+            weapon: hit.weapon,
+            headshot: hit.headshot,
+            wallbang: hit.wallbang,
+        }).unwrap();
+        server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+
+        if match_state.first_blood_done {continue}
+        match_state.first_blood_done = true;
+
+        let name = lobby.players.get(&hit.shooter)
+            .and_then(|&entity| names_q.get(entity).ok())
+            .map_or_else(|| hit.shooter.to_string(), |PlayerName(name)| name.clone());
+        let message = bincode::serialize(&ServerMessages::Notice(Notice::FirstBlood { name })).unwrap();
+        server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+    }
+}
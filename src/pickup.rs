@@ -0,0 +1,275 @@
+//! Ammo, health and armor pickups lying in the maze.
+//! The server owns the pickups : it spawns them, grants them to the players
+//! walking through them and respawns them after a delay.
+//! The clients only receive their state and display a bobbing mesh.
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::*;
+use rand::seq::SliceRandom;
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    world::{Maze, AMMO_TILE, HEALTH_TILE, ARMOR_TILE},
+    player::{Player, PlayerId, HP, MAX_ARMOR},
+    hitbox::Hitbox,
+    weapons::Weapon,
+    sounds::{EmitSound, SoundEvent},
+    network::mods::{DefaultChannel, ServerMessages},
+    demo::GameServer,
+};
+
+//////////////////////////// COMPONENTS, CONSTS ///////////////////////////////
+
+// height of the pickups above the ground
+const PICKUP_HEIGHT: f32 = 0.8;
+const PICKUP_RADIUS: f32 = 0.6;
+// number of pickups spawned at random free tiles, on top of the ones placed on the map
+const RANDOM_PICKUPS: usize = 3;
+
+const HEALTH_AMOUNT: f32 = 50.;
+const ARMOR_AMOUNT: f32 = 50.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum PickupKind{
+    Ammo,
+    Health,
+    Armor
+}
+
+impl PickupKind{
+    fn from_tile(tile: i32) -> Option<Self>{
+        match tile{
+            AMMO_TILE => Some(Self::Ammo),
+            HEALTH_TILE => Some(Self::Health),
+            ARMOR_TILE => Some(Self::Armor),
+            _ => None
+        }
+    }
+
+    /// time (in seconds) before the pickup appears again once taken
+    fn respawn_delay(&self) -> f32{
+        match self{
+            Self::Ammo => 15.,
+            Self::Health => 20.,
+            Self::Armor => 30.,
+        }
+    }
+
+    pub fn color(&self) -> Color{
+        match self{
+            Self::Ammo => Color::rgb(1., 0.8, 0.),
+            Self::Health => Color::rgb(1., 0.1, 0.1),
+            Self::Armor => Color::rgb(0.1, 0.4, 1.),
+        }
+    }
+
+    /// give the pickup to the player.
+    /// returns false if the player does not need it (full health, full ammos ...)
+    fn grant(&self, player: &mut Player, weapon: &Weapon) -> bool{
+        match self{
+            Self::Ammo => {
+                if player.reserve_ammos >= weapon.max_reserve_ammos {return false}
+                player.reserve_ammos = (player.reserve_ammos + weapon.ammos as u16)
+                    .min(weapon.max_reserve_ammos);
+            },
+            Self::Health => {
+                let max_hp = HP::default().0;
+                if player.hp >= max_hp {return false}
+                player.hp = (player.hp + HEALTH_AMOUNT).min(max_hp);
+            },
+            Self::Armor => {
+                if player.armor >= MAX_ARMOR {return false}
+                player.armor = (player.armor + ARMOR_AMOUNT).min(MAX_ARMOR);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Component)]
+pub struct Pickup{
+    pub id: u32,
+    pub kind: PickupKind,
+    /// remaining time before the pickup respawns, the pickup is active when it reaches 0
+    pub respawn_timer: f32,
+}
+
+impl Pickup{
+    pub fn is_active(&self) -> bool{
+        self.respawn_timer <= 0.
+    }
+
+    /// not a bevy system. The message sent to the clients to synchronize this pickup
+    pub fn to_message(&self, transform: &Transform) -> ServerMessages{
+        ServerMessages::PickupUpdate {
+            id: self.id,
+            kind: self.kind,
+            translation: transform.translation.into(),
+            active: self.is_active(),
+        }
+    }
+}
+
+/// client side only, used to make the pickup mesh float
+#[derive(Component)]
+pub struct PickupVisual{
+    base_height: f32
+}
+
+//////////////////////////// SERVER ///////////////////////////////
+
+/// spawn the pickups placed on the map, and a few more at random free tiles.
+/// The pickups of the previous map are removed when the map changes
+pub fn spawn_pickups(
+    mut cmd: Commands,
+    maze: Res<Maze>,
+    old_pickups_q: Query<Entity, With<Pickup>>,
+    mut server: GameServer,
+){
+    for entity in old_pickups_q.iter(){
+        cmd.entity(entity).despawn();
+    }
+
+    let mut pickups = vec![];
+
+    for (row, line) in maze.map.iter().enumerate(){
+        for (col, tile) in line.iter().enumerate(){
+            if let Some(kind) = PickupKind::from_tile(*tile){
+                pickups.push((kind, maze.tile_to_world(row, col)));
+            }
+        }
+    }
+
+    let mut rng = rand::thread_rng();
+    let kinds = [PickupKind::Ammo, PickupKind::Health, PickupKind::Armor];
+    for &(row, col) in maze.free_tiles().choose_multiple(&mut rng, RANDOM_PICKUPS){
+        let kind = *kinds.choose(&mut rng).unwrap();
+        pickups.push((kind, maze.tile_to_world(row, col)));
+    }
+
+    for (id, (kind, position)) in pickups.into_iter().enumerate(){
+        let pickup = Pickup{ id: id as u32, kind, respawn_timer: 0. };
+        let transform = Transform::from_xyz(position.x, PICKUP_HEIGHT, position.z);
+        // the clients already connected (see client.rs)
+        let message = bincode::serialize(&pickup.to_message(&transform)).unwrap();
+        server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+
+        cmd.spawn((
+            Name::new("Pickup"),
+            pickup,
+            TransformBundle::from_transform(transform),
+            Collider::ball(PICKUP_RADIUS),
+            Sensor,
+            // the players are kinematic bodies
+            ActiveCollisionTypes::default() | ActiveCollisionTypes::KINEMATIC_STATIC,
+        ));
+    }
+}
+
+/// grant the pickups to the players walking through them
+pub fn pickup_system(
+    rapier_context: Res<RapierContext>,
+    mut pickup_q: Query<(Entity, &mut Pickup, &Transform)>,
+    hitbox_q: Query<&Parent, With<Hitbox>>,
+    mut player_q: Query<(&mut Player, &Weapon, &PlayerId)>,
+    mut server: GameServer,
+    mut sounds: EventWriter<EmitSound>,
+){
+    for (pickup_entity, mut pickup, transform) in pickup_q.iter_mut(){
+        if !pickup.is_active() {continue}
+
+        for (collider1, collider2, intersecting) in rapier_context.intersections_with(pickup_entity){
+            if !intersecting {continue}
+
+            let other = if collider1 == pickup_entity {collider2} else {collider1};
+            // the player's colliders are children of the player entity
+            let player_entity = hitbox_q.get(other).map(|parent| parent.get()).unwrap_or(other);
+
+            let Ok((mut player, weapon, player_id)) = player_q.get_mut(player_entity) else {continue};
+            if player.hp <= 0. || !pickup.kind.grant(&mut player, weapon) {continue}
+
+            pickup.respawn_timer = pickup.kind.respawn_delay();
+
+            let message = bincode::serialize(&pickup.to_message(transform)).unwrap();
+            server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+
+            sounds.send(EmitSound{
+                event: SoundEvent::Pickup{ kind: pickup.kind },
+                position: transform.translation,
+                emitter: Some(player_id.id),
+            });
+            break;
+        }
+    }
+}
+
+pub fn respawn_pickups(
+    mut pickup_q: Query<(&mut Pickup, &Transform)>,
+    mut server: GameServer,
+    time: Res<Time>,
+){
+    for (mut pickup, transform) in pickup_q.iter_mut(){
+        if pickup.is_active() {continue}
+
+        pickup.respawn_timer -= time.delta_seconds();
+
+        if pickup.is_active(){
+            let message = bincode::serialize(&pickup.to_message(transform)).unwrap();
+            server.broadcast_message(DefaultChannel::ReliableOrdered, message);
+        }
+    }
+}
+
+//////////////////////////// CLIENT ///////////////////////////////
+
+/// not a bevy system. Spawn or update a pickup received from the server
+#[allow(clippy::too_many_arguments)]
+pub fn handle_pickup_update(
+    cmd: &mut Commands,
+    pickup_q: &mut Query<(Entity, &mut Pickup)>,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    materials: &mut ResMut<Assets<StandardMaterial>>,
+    id: u32,
+    kind: PickupKind,
+    translation: [f32;3],
+    active: bool,
+){
+    let visibility = if active {Visibility::Visible} else {Visibility::Hidden};
+
+    match pickup_q.iter_mut().find(|(_, pickup)| pickup.id == id){
+        Some((entity, mut pickup)) => {
+            pickup.respawn_timer = if active {0.} else {kind.respawn_delay()};
+            cmd.entity(entity).insert(visibility);
+        },
+        None => {
+            let material = materials.add(StandardMaterial {
+                base_color: kind.color(),
+                emissive: kind.color(),
+                ..default()
+            });
+
+            cmd.spawn((
+                Name::new("Pickup"),
+                Pickup{ id, kind, respawn_timer: if active {0.} else {kind.respawn_delay()} },
+                PickupVisual{ base_height: translation[1] },
+                PbrBundle{
+                    mesh: meshes.add(shape::Cube{size: 0.4}.into()),
+                    material,
+                    transform: Transform::from_translation(translation.into()),
+                    visibility,
+                    ..default()
+                },
+            ));
+        }
+    }
+}
+
+/// make the pickups float and spin
+pub fn animate_pickups(
+    mut pickup_q: Query<(&mut Transform, &PickupVisual)>,
+    time: Res<Time>,
+){
+    for (mut transform, visual) in pickup_q.iter_mut(){
+        transform.translation.y = visual.base_height + 0.15 * (2. * time.elapsed_seconds()).sin();
+        transform.rotate_y(time.delta_seconds());
+    }
+}
@@ -2,9 +2,9 @@ use std::time::Instant;
 
 use bevy::{prelude::*, input::mouse::MouseMotion};
 use serde::{Serialize, Deserialize};
-use bevy_rapier3d::prelude::RapierContext;
+use bevy_rapier3d::prelude::{RapierContext, KinematicCharacterController, Collider};
 
-use crate::{camera::CameraVerticalMotion, flashlight::toggle_flashlight, shoot::{shoot, bullet_system, reload_system}, weapons::Weapon, bullet_tracer, world::Maze};
+use crate::{camera::{LookAngles, MAX_PITCH, camera_height, CROUCH_TRANSITION_SPEED}, flashlight::toggle_flashlight, shoot::{shoot, bullet_system, reload_system, PlayerHit}, weapons::{Weapon, WeaponKind}, bullet_tracer, world::Maze, hitbox::crouch_hitboxes, movement::{PlayerMotion, MAX_STAMINA, step_movement, update_player_motion, player_collider, can_stand_up}, controls::{ActionInput, Action, MOUSE_RADIANS_PER_DOT}, settings::Settings, sounds::EmitSound};
 
 pub struct PlayerPlugin;
 
@@ -13,25 +13,29 @@ impl Plugin for PlayerPlugin{
         app
         .init_resource::<PlayerInput>()
         .init_resource::<RapierContext>()
+        .add_event::<EmitSound>()
+        .add_event::<PlayerHit>()
 
         .add_systems(Update, (
+            update_player_motion.before(move_players),
             move_players,
             toggle_flashlight,
             shoot,
             bullet_system,
             bullet_tracer::handle_lifetime,
             reload_system,
+            crouch_hitboxes.after(move_players),
         ));
     }
 }
 
 ///////////// Events, Components, Resources ... ///////////////////
 
-pub const PLAYER_MOVE_SPEED: f32 = 7.0;
-pub const PLAYER_SLOW_MOVE_SPEED: f32 = 3.5;
-pub const MOUSE_SENSIBILITY:f32 = 0.3;
+// weight of the new mouse motion when the mouse is smoothed (raw input off)
+const MOUSE_SMOOTHING:f32 = 0.5;
 pub const FLASHLIGHT_INTENSITY:f32 = 4000.;
 pub const PLAYER_SCALE:f32 = 3.;
+pub const MAX_ARMOR:f32 = 100.;
 
 #[derive(Debug, Default, Serialize, Deserialize, Component, Resource)]
 pub struct PlayerInput {
@@ -45,7 +49,9 @@ pub struct PlayerInput {
     pub left_click_just_pressed: bool,
     pub left_click: bool,
     pub is_walking: bool,
-    pub mute: bool,
+    pub sprint: bool,
+    pub jump: bool,
+    pub crouch: bool,
     pub show_map: bool,
 }
 
@@ -53,8 +59,8 @@ pub struct PlayerInput {
 #[derive(Serialize, Deserialize,Clone)]
 pub struct PlayerState{
     pub translation:[f32;3],
-    pub rotation: [f32;4],
-    pub cam_vertical_motion: f32,
+    pub yaw: f32,
+    pub pitch: f32,
     pub look_to: [f32;3],
     pub state: Player
 }
@@ -63,15 +69,19 @@ pub struct PlayerState{
 pub struct Player {
     pub flashlight_on: bool,
     pub hp: f32,
+    pub armor: f32,
     pub ammos: u8,
+    pub reserve_ammos: u16,
+    /// the equipped weapon
+    pub weapon: WeaponKind,
     pub is_reloading: bool,
-    pub just_reloaded: bool,
     pub reload_timer: f32,
     pub is_shooting: bool,
     pub is_walking: bool,
     pub is_running: bool,
-    pub emmited_sound: Option<String>,
-    pub is_muted: bool,
+    pub is_sprinting: bool,
+    pub is_crouching: bool,
+    pub stamina: f32,
     
 }
 
@@ -80,15 +90,18 @@ impl Default for Player{
         Self {
             flashlight_on: false,
             hp: HP::default().0,
+            armor: 0.,
             ammos: Weapon::rifle().ammos ,
+            reserve_ammos: Weapon::rifle().max_reserve_ammos,
+            weapon: WeaponKind::Rifle,
             is_reloading: false,
-            just_reloaded: false,
             reload_timer: 0.,
             is_shooting: false,
             is_running: false,
             is_walking: false,
-            emmited_sound: None,
-            is_muted: false,
+            is_sprinting: false,
+            is_crouching: false,
+            stamina: MAX_STAMINA,
         }
     }
 }
@@ -98,6 +111,10 @@ pub struct PlayerId {
     pub id: u64,
 }
 
+/// the name chosen in the main menu
+#[derive(Component, Debug, Clone)]
+pub struct PlayerName(pub String);
+
 #[derive(Component)]
 pub struct CurrentPlayer(pub u64);
 
@@ -112,92 +129,99 @@ impl Default for HP{
 //////////////////// systems //////////////////////
 
 pub fn player_input(
-    keys: Res<Input<KeyCode>>,
+    actions: ActionInput,
+    settings: Res<Settings>,
     mut mouse_motion: EventReader<MouseMotion>,
-    mouse_inputs: Res<Input<MouseButton>>,
+    mut smoothed_look: Local<Vec2>,
     mut player_input: ResMut<PlayerInput>
 ) {
-    // keys
-    player_input.left = keys.pressed(KeyCode::Q);
-    player_input.right = keys.pressed(KeyCode::D);
-    player_input.up = keys.pressed(KeyCode::Z);
-    player_input.down = keys.pressed(KeyCode::S);
-    player_input.reload = keys.pressed(KeyCode::R);
-    player_input.is_walking = keys.pressed(KeyCode::ShiftLeft);
-    player_input.mute = keys.just_pressed(KeyCode::M);
-    if keys.just_pressed(KeyCode::F) {
+    // actions
+    player_input.left = actions.pressed(Action::StrafeLeft);
+    player_input.right = actions.pressed(Action::StrafeRight);
+    player_input.up = actions.pressed(Action::Forward);
+    player_input.down = actions.pressed(Action::Backward);
+    player_input.reload = actions.pressed(Action::Reload);
+    player_input.is_walking = actions.pressed(Action::Walk);
+    player_input.sprint = actions.pressed(Action::Sprint);
+    player_input.jump = actions.pressed(Action::Jump);
+    player_input.crouch = actions.pressed(Action::Crouch);
+    if actions.just_pressed(Action::ToggleFlashlight) {
         player_input.toggle_flashlight = !player_input.toggle_flashlight;
     }
-    if keys.just_pressed(KeyCode::Tab){
+    if actions.just_pressed(Action::ToggleMap){
         player_input.show_map = !player_input.show_map;
     }
     
-   
-    // mouse motion
-    if mouse_motion.is_empty(){
-        player_input.mouse = Vec2::ZERO;
+    // mouse motion, converted to the angles (radians) the view has to turn this frame.
+    // every motion of the frame counts, not only the last one
+    let mouse = &settings.mouse;
+    let delta: Vec2 = mouse_motion.iter().map(|motion| motion.delta).sum();
+    let mut look = -delta * mouse.sensitivity * MOUSE_RADIANS_PER_DOT;
+    if mouse.invert_y {
+        look.y = -look.y;
     }
-    for motion in mouse_motion.iter(){
-        player_input.mouse = -motion.delta;
+    if !mouse.raw_input {
+        *smoothed_look = smoothed_look.lerp(look, MOUSE_SMOOTHING);
+        look = *smoothed_look;
     }
-    // mouse click
-    player_input.left_click = mouse_inputs.pressed(MouseButton::Left);
-    player_input.left_click_just_pressed = mouse_inputs.just_pressed(MouseButton::Left);    
+    player_input.mouse = look;
+
+    // fire
+    player_input.left_click = actions.pressed(Action::Fire);
+    player_input.left_click_just_pressed = actions.just_pressed(Action::Fire);
 }
 
+/// the players moved by their input, their camera is a child
+type MovingPlayerQuery<'w, 's> = Query<'w, 's, (
+        &'static mut Transform,
+        &'static mut KinematicCharacterController,
+        &'static mut Collider,
+        &'static mut PlayerMotion,
+        &'static mut Player,
+        &'static mut PlayerInput,
+        &'static mut LookAngles,
+        &'static Children),
+        Without<Camera>>;
+
 pub fn move_players(
-    mut player_query: Query<(
-        &mut Transform,
-        &PlayerInput,
-        &mut CameraVerticalMotion,
-        &Children),
-        Without<Camera>>,
+    mut player_query: MovingPlayerQuery,
     mut camera_q: Query<&mut Transform, With<Camera>>,
+    rapier_context: Res<RapierContext>,
     time: Res<Time>,
 ) {
-    for (mut transform, input, mut camera_motion,  children) in player_query.iter_mut() {
-        // move player (translation)
-        let mut direction = Vec3::ZERO;
-
-        if input.left {direction += transform.left()}
-        if input.right {direction += transform.right()}
-        if input.up {direction += transform.forward()}
-        if input.down {direction += transform.back()}
-
-        direction = direction.normalize_or_zero();
-        let speed = if input.is_walking {PLAYER_SLOW_MOVE_SPEED} else {PLAYER_MOVE_SPEED};
+    for (mut transform, mut controller, mut collider, mut motion, mut player, mut input, mut look,  children) in player_query.iter_mut() {
+        // the dead players wait for their respawn (see death.rs)
+        if player.hp <= 0. {continue}
+
+        // move player (translation), the character controller takes care of the collisions
+        let was_crouching = player.is_crouching;
+        // the headroom is only checked when the player wants to stand up
+        let can_stand = !was_crouching || input.crouch
+            || can_stand_up(&rapier_context, transform.translation, transform.scale.y);
+        controller.translation = Some(step_movement(
+            &input, &mut player, &mut motion, transform.rotation, can_stand, time.delta_seconds()
+        ));
 
-        transform.translation -= direction * speed * time.delta_seconds();
-         
-        // move camera horizontally
-        // mouse micro-movement detection
-        if input.mouse.x.abs() == 1.{
-            transform.rotate_y(input.mouse.x * 0.00872664626); // 0.5 degrees
-        }else{
-            let horizontal_motion = input.mouse.x * MOUSE_SENSIBILITY * time.delta_seconds();        
-            transform.rotate_y(horizontal_motion);
+        if player.is_crouching != was_crouching{
+            *collider = player_collider(player.is_crouching);
         }
-    
-        // move camera vertically
-        let vertical_motion = if input.mouse.y.abs() == 1.{
-            -input.mouse.y * 0.00872664626 // 0.5 degrees
-        }else{
-            -input.mouse.y * MOUSE_SENSIBILITY * time.delta_seconds()
-        };
-
-        camera_motion.0 = vertical_motion;
+         
+        // rotate the view, the client already converted the mouse motion into angles.
+        // the view cannot go past the vertical
+        look.yaw += input.mouse.x;
+        look.pitch = (look.pitch + input.mouse.y).clamp(-MAX_PITCH, MAX_PITCH);
+        // the motion is consumed, so it is not applied again if no new input arrives next frame
+        input.mouse = Vec2::ZERO;
+        transform.rotation = look.body_rotation();
+
         for &child in children.iter(){
             if let Ok(mut cam_transform) = camera_q.get_mut(child){
-                // prevent player from rotating the camera more than 90 degrees 
-                // around the z axis (vertical motion)
-                // let can_rotate = 
-                //     (cam_transform.rotation.z + vertical_motion < 0.5)
-                //     && (cam_transform.rotation.z + vertical_motion > -0.5);
-
-                // if can_rotate{break;}
+                cam_transform.rotation = look.view_rotation();
 
-            
-                cam_transform.rotate_x(vertical_motion);
+                // lower the camera when crouching
+                let target_height = camera_height(player.is_crouching);
+                cam_transform.translation.y += (target_height - cam_transform.translation.y)
+                    * (CROUCH_TRANSITION_SPEED * time.delta_seconds()).min(1.);
                 break;
             }
         }
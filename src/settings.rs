@@ -0,0 +1,562 @@
+//! User preferences (video, audio, gameplay), saved in the user's config file (see config.rs).
+//! They are validated when loaded and applied as soon as they change.
+//! The settings panel is opened from the main menu or the pause menu.
+use bevy::{prelude::*, window::{PrimaryWindow, WindowMode, PresentMode}, pbr::DirectionalLightShadowMap};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use crate::{
+    config,
+    sounds::SoundCategory,
+    game::AppState,
+    chat::chat_closed,
+    controls::{ActionInput, Action, Controls, spawn_controls_tab},
+    menu::{row, button_bundle, menu_text},
+};
+
+pub struct SettingsPlugin;
+
+impl Plugin for SettingsPlugin{
+    fn build(&self, app: &mut App) {
+        app
+        .insert_resource(Settings::load())
+        .init_resource::<SettingsMenu>()
+        .init_resource::<SettingsTab>()
+
+        .add_systems(Update, (
+            apply_video_settings,
+            apply_shadow_settings,
+            apply_fov,
+            toggle_mute.run_if(in_state(AppState::InGame)).run_if(chat_closed),
+            toggle_settings_menu,
+            (
+                settings_menu_buttons,
+                update_settings_menu.after(settings_menu_buttons),
+            ).run_if(|menu: Res<SettingsMenu>| menu.0),
+        ));
+    }
+}
+
+//////////////////////////// CONSTS, RESOURCES ///////////////////////////////
+
+const SETTINGS_FILE: &str = "settings.ron";
+
+const RESOLUTIONS: [[u32; 2]; 5] = [
+    [1280, 720],
+    [1366, 768],
+    [1600, 900],
+    [1920, 1080],
+    [2560, 1440],
+];
+
+// vertical field of view, in degrees
+const MIN_FOV: f32 = 30.;
+const MAX_FOV: f32 = 90.;
+const FOV_STEP: f32 = 5.;
+const VOLUME_STEP: f32 = 0.1;
+pub const MIN_SENSITIVITY: f32 = 0.1;
+pub const MAX_SENSITIVITY: f32 = 10.;
+const SENSITIVITY_STEP: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WindowModeSetting{
+    Windowed,
+    Borderless,
+    Fullscreen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShadowQuality{
+    Off,
+    Low,
+    Medium,
+    High,
+}
+
+impl ShadowQuality{
+    /// size of the shadow maps of the spot lights (flashlights).
+    /// Bevy sizes them with `DirectionalLightShadowMap`, not `PointLightShadowMap`
+    fn shadow_map_size(&self) -> usize{
+        match self{
+            ShadowQuality::Off | ShadowQuality::Low => 512,
+            ShadowQuality::Medium => 1024,
+            ShadowQuality::High => 2048,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CrosshairStyle{
+    Image,
+    Dot,
+    Cross,
+    Hidden,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MouseSettings{
+    pub sensitivity: f32,
+    pub invert_y: bool,
+    /// if false, the mouse motion is smoothed over a few frames
+    pub raw_input: bool,
+}
+
+impl Default for MouseSettings{
+    fn default() -> Self {
+        Self {
+            sensitivity: 2.,
+            invert_y: false,
+            raw_input: true,
+        }
+    }
+}
+
+/// the fields missing from the file get their default value
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings{
+    // video
+    pub window_mode: WindowModeSetting,
+    pub resolution: [u32; 2],
+    pub vsync: bool,
+    pub shadows: ShadowQuality,
+    pub fov: f32,
+    // audio, volumes between 0 and 1
+    pub master_volume: f32,
+    pub bus_volumes: HashMap<SoundCategory, f32>,
+    pub muted: bool,
+    // gameplay
+    pub crosshair: CrosshairStyle,
+    pub mouse: MouseSettings,
+    /// the radar turns with the player, so the facing direction is always up
+    pub minimap_rotation: bool,
+    /// replay the last seconds from the killer's view on the death screen
+    pub kill_cam: bool,
+}
+
+impl Default for Settings{
+    fn default() -> Self {
+        Self {
+            window_mode: WindowModeSetting::Borderless,
+            resolution: [1920, 1080],
+            vsync: true,
+            shadows: ShadowQuality::Medium,
+            fov: 45.,
+            master_volume: 1.,
+            bus_volumes: SoundCategory::ALL.iter()
+                .map(|category| (*category, category.default_volume()))
+                .collect(),
+            muted: false,
+            crosshair: CrosshairStyle::Image,
+            mouse: MouseSettings::default(),
+            minimap_rotation: false,
+            kill_cam: true,
+        }
+    }
+}
+
+impl Settings{
+    pub fn load() -> Self{
+        config::load::<Settings>(SETTINGS_FILE)
+            .map(Settings::validated)
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self){
+        config::save(SETTINGS_FILE, self);
+    }
+
+    /// replace the invalid values (edited file ...) by the default ones
+    fn validated(mut self) -> Self{
+        let default = Settings::default();
+
+        if !RESOLUTIONS.contains(&self.resolution){
+            self.resolution = default.resolution;
+        }
+        if !self.fov.is_finite(){
+            self.fov = default.fov;
+        }
+        self.fov = self.fov.clamp(MIN_FOV, MAX_FOV);
+
+        for category in SoundCategory::ALL{
+            self.bus_volumes.entry(category).or_insert(category.default_volume());
+        }
+        for volume in self.bus_volumes.values_mut().chain([&mut self.master_volume]){
+            *volume = if volume.is_finite() {volume.clamp(0., 1.)} else {1.};
+        }
+
+        if !self.mouse.sensitivity.is_finite(){
+            self.mouse.sensitivity = default.mouse.sensitivity;
+        }
+        self.mouse.sensitivity = self.mouse.sensitivity.clamp(MIN_SENSITIVITY, MAX_SENSITIVITY);
+
+        self
+    }
+
+    /// volume of the sounds without category, after the mute
+    pub fn master_volume(&self) -> f32{
+        if self.muted {0.} else {self.master_volume}
+    }
+
+    /// volume of a mixer bus, after the master volume and the mute
+    pub fn volume(&self, category: SoundCategory) -> f32{
+        self.master_volume() * self.bus_volumes.get(&category).copied().unwrap_or(1.)
+    }
+
+    /// change a setting from the settings panel, `direction` is 1 or -1
+    fn step(&mut self, setting: SettingKind, direction: i8){
+        let sign = direction as f32;
+
+        match setting{
+            SettingKind::WindowMode => self.window_mode = cycle(
+                &[WindowModeSetting::Windowed, WindowModeSetting::Borderless, WindowModeSetting::Fullscreen],
+                self.window_mode, direction
+            ),
+            SettingKind::Resolution => self.resolution = cycle(&RESOLUTIONS, self.resolution, direction),
+            SettingKind::VSync => self.vsync = !self.vsync,
+            SettingKind::Shadows => self.shadows = cycle(
+                &[ShadowQuality::Off, ShadowQuality::Low, ShadowQuality::Medium, ShadowQuality::High],
+                self.shadows, direction
+            ),
+            SettingKind::Fov => self.fov = (self.fov + sign * FOV_STEP).clamp(MIN_FOV, MAX_FOV),
+            SettingKind::MasterVolume => self.master_volume = (self.master_volume + sign * VOLUME_STEP).clamp(0., 1.),
+            SettingKind::Volume(category) => {
+                let volume = self.bus_volumes.entry(category).or_insert(1.);
+                *volume = (*volume + sign * VOLUME_STEP).clamp(0., 1.);
+            },
+            SettingKind::Muted => self.muted = !self.muted,
+            SettingKind::Crosshair => self.crosshair = cycle(
+                &[CrosshairStyle::Image, CrosshairStyle::Dot, CrosshairStyle::Cross, CrosshairStyle::Hidden],
+                self.crosshair, direction
+            ),
+            SettingKind::Sensitivity => self.mouse.sensitivity = (self.mouse.sensitivity + sign * SENSITIVITY_STEP)
+                .clamp(MIN_SENSITIVITY, MAX_SENSITIVITY),
+            SettingKind::InvertY => self.mouse.invert_y = !self.mouse.invert_y,
+            SettingKind::RawInput => self.mouse.raw_input = !self.mouse.raw_input,
+            SettingKind::MinimapRotation => self.minimap_rotation = !self.minimap_rotation,
+            SettingKind::KillCam => self.kill_cam = !self.kill_cam,
+        }
+    }
+
+    fn value_label(&self, setting: SettingKind) -> String{
+        let on_off = |value: bool| if value {"ON".to_string()} else {"OFF".to_string()};
+        let percent = |volume: f32| format!("{:.0}%", volume * 100.);
+
+        match setting{
+            SettingKind::WindowMode => format!("{:?}", self.window_mode),
+            SettingKind::Resolution => format!("{}x{}", self.resolution[0], self.resolution[1]),
+            SettingKind::VSync => on_off(self.vsync),
+            SettingKind::Shadows => format!("{:?}", self.shadows),
+            SettingKind::Fov => format!("{:.0}", self.fov),
+            SettingKind::MasterVolume => percent(self.master_volume),
+            SettingKind::Volume(category) => percent(self.bus_volumes.get(&category).copied().unwrap_or(1.)),
+            SettingKind::Muted => on_off(self.muted),
+            SettingKind::Crosshair => format!("{:?}", self.crosshair),
+            SettingKind::Sensitivity => format!("{:.1}", self.mouse.sensitivity),
+            SettingKind::InvertY => on_off(self.mouse.invert_y),
+            SettingKind::RawInput => on_off(self.mouse.raw_input),
+            SettingKind::MinimapRotation => on_off(self.minimap_rotation),
+            SettingKind::KillCam => on_off(self.kill_cam),
+        }
+    }
+}
+
+/// not a bevy system. The next (or previous) value of the list
+fn cycle<T: Copy + PartialEq>(values: &[T], current: T, direction: i8) -> T{
+    let index = values.iter().position(|v| *v == current).unwrap_or(0) as i32;
+    let len = values.len() as i32;
+    values[(index + direction as i32).rem_euclid(len) as usize]
+}
+
+/// open the settings, from the main menu or the pause menu
+#[derive(Resource, Default)]
+pub struct SettingsMenu(pub bool);
+
+#[derive(Resource, Default, Clone, Copy, PartialEq)]
+enum SettingsTab{
+    #[default]
+    Video,
+    Audio,
+    Gameplay,
+    Controls,
+}
+
+//////////////////////////// APPLY ///////////////////////////////
+
+fn apply_video_settings(
+    settings: Res<Settings>,
+    mut windows: Query<&mut Window, With<PrimaryWindow>>,
+){
+    if !settings.is_changed() {return}
+    let Ok(mut window) = windows.get_single_mut() else {return};
+
+    let mode = match settings.window_mode{
+        WindowModeSetting::Windowed => WindowMode::Windowed,
+        WindowModeSetting::Borderless => WindowMode::BorderlessFullscreen,
+        WindowModeSetting::Fullscreen => WindowMode::Fullscreen,
+    };
+    let present_mode = if settings.vsync {PresentMode::AutoVsync} else {PresentMode::AutoNoVsync};
+    let [width, height] = settings.resolution;
+
+    // only touch the window when needed, every change recreates the surface
+    if window.mode != mode{
+        window.mode = mode;
+    }
+    if window.present_mode != present_mode{
+        window.present_mode = present_mode;
+    }
+    if window.resolution.physical_width() != width || window.resolution.physical_height() != height{
+        window.resolution.set_physical_resolution(width, height);
+    }
+}
+
+fn apply_shadow_settings(
+    settings: Res<Settings>,
+    mut shadow_map: ResMut<DirectionalLightShadowMap>,
+    mut lights_q: Query<&mut SpotLight>,
+    new_lights_q: Query<(), Added<SpotLight>>,
+){
+    if !settings.is_changed() && new_lights_q.is_empty() {return}
+
+    let size = settings.shadows.shadow_map_size();
+    if shadow_map.size != size{
+        shadow_map.size = size;
+    }
+
+    for mut light in lights_q.iter_mut(){
+        light.shadows_enabled = settings.shadows != ShadowQuality::Off;
+    }
+}
+
+fn apply_fov(
+    settings: Res<Settings>,
+    mut projection_q: Query<&mut Projection, With<Camera3d>>,
+    new_cameras_q: Query<(), Added<Camera3d>>,
+){
+    if !settings.is_changed() && new_cameras_q.is_empty() {return}
+
+    for mut projection in projection_q.iter_mut(){
+        if let Projection::Perspective(perspective) = &mut *projection{
+            perspective.fov = settings.fov.to_radians();
+        }
+    }
+}
+
+fn toggle_mute(
+    actions: ActionInput,
+    mut settings: ResMut<Settings>,
+){
+    if actions.just_pressed(Action::Mute){
+        settings.muted = !settings.muted;
+        settings.save();
+    }
+}
+
+//////////////////////////// MENU ///////////////////////////////
+
+#[derive(Component)]
+struct SettingsPanel;
+
+/// the content of the current tab
+#[derive(Component)]
+struct SettingsContent;
+
+#[derive(Component, Clone, Copy)]
+enum SettingsButton{
+    Tab(SettingsTab),
+    Step(SettingKind, i8),
+    Back,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SettingKind{
+    WindowMode,
+    Resolution,
+    VSync,
+    Shadows,
+    Fov,
+    MasterVolume,
+    Volume(SoundCategory),
+    Muted,
+    Crosshair,
+    Sensitivity,
+    InvertY,
+    RawInput,
+    MinimapRotation,
+    KillCam,
+}
+
+#[derive(Component)]
+struct SettingText(SettingKind);
+
+fn toggle_settings_menu(
+    mut cmd: Commands,
+    settings_menu: Res<SettingsMenu>,
+    panel_q: Query<Entity, With<SettingsPanel>>,
+){
+    if !settings_menu.is_changed() {return}
+
+    let is_spawned = !panel_q.is_empty();
+    if settings_menu.0 && !is_spawned{
+        cmd.spawn((
+            SettingsPanel,
+            Name::new("Settings"),
+            NodeBundle{
+                style: Style{
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(40.),
+                    left: Val::Percent(32.),
+                    top: Val::Percent(10.),
+                    padding: UiRect::all(Val::Px(20.)),
+                    flex_direction: FlexDirection::Column,
+                    row_gap: Val::Px(4.),
+                    ..default()
+                },
+                background_color: BackgroundColor(Color::rgba(0., 0., 0., 0.8)),
+                ..default()
+            }
+        )).with_children(|panel|{
+            panel.spawn(menu_text("SETTINGS".to_string(), 40.));
+
+            panel.spawn(row()).with_children(|row|{
+                for (tab, label) in [
+                    (SettingsTab::Video, "Video"),
+                    (SettingsTab::Audio, "Audio"),
+                    (SettingsTab::Gameplay, "Gameplay"),
+                    (SettingsTab::Controls, "Controls"),
+                ]{
+                    row.spawn((SettingsButton::Tab(tab), button_bundle())).with_children(|button|{
+                        button.spawn(menu_text(label.to_string(), 20.));
+                    });
+                }
+            });
+
+            panel.spawn((
+                SettingsContent,
+                NodeBundle{
+                    style: Style{
+                        flex_direction: FlexDirection::Column,
+                        row_gap: Val::Px(4.),
+                        ..default()
+                    },
+                    ..default()
+                }
+            ));
+
+            panel.spawn((SettingsButton::Back, button_bundle())).with_children(|button|{
+                button.spawn(menu_text("Back".to_string(), 20.));
+            });
+        });
+    }
+
+    if !settings_menu.0{
+        for panel in panel_q.iter(){
+            cmd.entity(panel).despawn_recursive();
+        }
+    }
+}
+
+fn settings_menu_buttons(
+    button_q: Query<(&Interaction, &SettingsButton), Changed<Interaction>>,
+    mut settings: ResMut<Settings>,
+    mut tab: ResMut<SettingsTab>,
+    mut settings_menu: ResMut<SettingsMenu>,
+){
+    for (interaction, button) in button_q.iter(){
+        if *interaction != Interaction::Pressed {continue}
+
+        match *button{
+            SettingsButton::Tab(new_tab) => *tab = new_tab,
+            SettingsButton::Step(setting, direction) => {
+                settings.step(setting, direction);
+                settings.save();
+            },
+            SettingsButton::Back => settings_menu.0 = false,
+        }
+    }
+}
+
+/// fill the current tab, and keep the displayed values up to date
+fn update_settings_menu(
+    mut cmd: Commands,
+    settings: Res<Settings>,
+    controls: Res<Controls>,
+    tab: Res<SettingsTab>,
+    content_q: Query<Entity, Added<SettingsContent>>,
+    all_content_q: Query<Entity, With<SettingsContent>>,
+    mut text_q: Query<(&mut Text, &SettingText)>,
+){
+    let Ok(content) = all_content_q.get_single() else {return};
+
+    if tab.is_changed() || !content_q.is_empty(){
+        cmd.entity(content).despawn_descendants();
+        cmd.entity(content).with_children(|content|{
+            let settings_in_tab: &[(SettingKind, &str)] = match *tab{
+                SettingsTab::Video => &[
+                    (SettingKind::WindowMode, "Window mode"),
+                    (SettingKind::Resolution, "Resolution"),
+                    (SettingKind::VSync, "VSync"),
+                    (SettingKind::Shadows, "Shadows"),
+                    (SettingKind::Fov, "Field of view"),
+                ],
+                SettingsTab::Audio => &[
+                    (SettingKind::MasterVolume, "Master volume"),
+                    (SettingKind::Volume(SoundCategory::Music), "Music"),
+                    (SettingKind::Volume(SoundCategory::Ambience), "Ambience"),
+                    (SettingKind::Volume(SoundCategory::Weapons), "Weapons"),
+                    (SettingKind::Volume(SoundCategory::Footsteps), "Footsteps"),
+                    (SettingKind::Volume(SoundCategory::Voice), "Voice"),
+                    (SettingKind::Volume(SoundCategory::Ui), "Interface"),
+                    (SettingKind::Muted, "Mute"),
+                ],
+                SettingsTab::Gameplay => &[
+                    (SettingKind::Crosshair, "Crosshair"),
+                    (SettingKind::Sensitivity, "Mouse sensitivity"),
+                    (SettingKind::InvertY, "Invert Y"),
+                    (SettingKind::RawInput, "Raw input"),
+                    (SettingKind::MinimapRotation, "Rotate minimap"),
+                    (SettingKind::KillCam, "Kill cam"),
+                ],
+                SettingsTab::Controls => {
+                    spawn_controls_tab(content, &controls);
+                    &[]
+                },
+            };
+
+            for &(setting, label) in settings_in_tab{
+                spawn_setting_row(content, &settings, setting, label);
+            }
+        });
+        return;
+    }
+
+    if !settings.is_changed() {return}
+
+    for (mut text, setting_text) in text_q.iter_mut(){
+        text.sections[0].value = settings.value_label(setting_text.0);
+    }
+}
+
+/// not a bevy system. "label   < value >"
+fn spawn_setting_row(parent: &mut ChildBuilder, settings: &Settings, setting: SettingKind, label: &str){
+    parent.spawn(row()).with_children(|row|{
+        row.spawn(menu_text(label.to_string(), 20.));
+
+        row.spawn(NodeBundle{
+            style: Style{
+                align_items: AlignItems::Center,
+                column_gap: Val::Px(10.),
+                ..default()
+            },
+            ..default()
+        }).with_children(|value|{
+            value.spawn((SettingsButton::Step(setting, -1), button_bundle())).with_children(|button|{
+                button.spawn(menu_text("<".to_string(), 20.));
+            });
+            value.spawn((SettingText(setting), menu_text(settings.value_label(setting), 20.)));
+            value.spawn((SettingsButton::Step(setting, 1), button_bundle())).with_children(|button|{
+                button.spawn(menu_text(">".to_string(), 20.));
+            });
+        });
+    });
+}
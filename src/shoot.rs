@@ -1,6 +1,6 @@
 use bevy::{prelude::*, utils::Instant};
-use bevy_rapier3d::prelude::{RapierContext, Collider, RigidBody, Velocity, GravityScale};
-use crate::{player::*, weapons::{Weapon, FireMode}, hitbox::Hitbox};
+use bevy_rapier3d::prelude::{RapierContext, Collider, RigidBody, Velocity, CollisionGroups, Group, QueryFilter};
+use crate::{player::*, team::Team, weapons::{Weapon, FireMode, WeaponKind}, hitbox::{Hitbox, BodyPart}, movement::PLAYER_COLLISION_GROUP, world::Maze, sounds::{EmitSound, SoundEvent, ReloadPhase}, game::Cvars};
 
 #[derive(Component)]
 struct ShootSound;
@@ -8,16 +8,41 @@ struct ShootSound;
 #[derive(Component)]
 pub struct Bullet{
     damage: f32,
-    direction: Vec3
+    direction: Vec3,
+    /// id of the player who shot the bullet
+    shooter: u64,
+    /// team of the shooter, for the friendly fire
+    team: Option<Team>,
+    weapon: WeaponKind,
+    origin: Vec3,
+    /// position of the bullet in the previous frame, the hits are searched between it and the current position
+    last_position: Vec3,
 }
 
 impl Bullet{
-    pub fn new(damage: f32, direction: Vec3) -> Self{
-        Self{damage, direction}
+    pub fn new(damage: f32, direction: Vec3, shooter: u64, team: Option<Team>, weapon: WeaponKind, origin: Vec3) -> Self{
+        Self{damage, direction, shooter, team, weapon, origin, last_position: origin}
     }
 }
 
+/// a player hit by a bullet (server side). The shooter and the victim are told by the server (see server.rs)
+#[derive(Event, Debug, Clone, Copy)]
+pub struct PlayerHit{
+    pub shooter: u64,
+    pub victim: u64,
+    pub weapon: WeaponKind,
+    /// where the bullet was shot from
+    pub origin: Vec3,
+    pub damage: f32,
+    pub headshot: bool,
+    /// a wall was between the shooter and the victim when the victim was hit
+    pub wallbang: bool,
+    pub killed: bool,
+}
+
 pub const BULLET_VELOCITY:f32 = 200.;
+/// part of the damage taken by the armor instead of the health
+const ARMOR_ABSORPTION:f32 = 0.5;
 
 fn can_shoot(input: &PlayerInput, weapon: &Weapon, ammos:u8, is_reloading: bool) -> bool{
     let input_ok = match weapon.fire_mode{
@@ -32,16 +57,19 @@ fn can_shoot(input: &PlayerInput, weapon: &Weapon, ammos:u8, is_reloading: bool)
     && !is_reloading
 }
 
+/// the players, their camera (a child) gives the direction of the shots
+type ShooterQuery<'w, 's> = Query<'w, 's, (&'static PlayerInput, &'static mut Weapon, &'static mut Player, &'static Transform, &'static Children, &'static PlayerId, Option<&'static Team>), Without<Camera>>;
+
 pub fn shoot(
-    mut shooter_q: Query<(&PlayerInput, &mut Weapon, &mut Player,&Transform, &Children), Without<Camera>>,
+    mut shooter_q: ShooterQuery,
     camera_q: Query<&GlobalTransform, With<Camera>>,
     mut cmd: Commands,
     mut meshes : ResMut<Assets<Mesh>>,
     mut materials : ResMut<Assets<StandardMaterial>>,
-    
+    mut sounds: EventWriter<EmitSound>,
 ){
-    for (input,mut weapon,mut player, player_transform, children) in shooter_q.iter_mut(){
-        if !can_shoot(input, &weapon, player.ammos, player.is_reloading){
+    for (input,mut weapon,mut player, player_transform, children, player_id, team) in shooter_q.iter_mut(){
+        if player.hp <= 0. || !can_shoot(input, &weapon, player.ammos, player.is_reloading){
             player.is_shooting = false;
             continue;
         }
@@ -66,15 +94,22 @@ pub fn shoot(
         player.ammos -= 1;
         player.is_shooting = true;
 
-        // shoot a bullet
-        let bullet_origin = Vec3::new(
+        sounds.send(EmitSound{
+            event: SoundEvent::Gunshot{ weapon: weapon.kind },
+            position: player_transform.translation,
+            emitter: Some(player_id.id),
+        });
+
+        // shoot a bullet from the camera (it goes up and down when the player jumps or crouches)
+        let mut bullet_origin = Vec3::new(
             player_transform.translation.x,
-            2.134, // camera height
+            2.134, // default camera height
             player_transform.translation.z
         );
         
         for &child in children.iter(){
             if let Ok(transform) = camera_q.get(child){
+                bullet_origin = transform.translation();
                 bullet_direction += transform.forward().normalize();
             }
         }
@@ -87,78 +122,129 @@ pub fn shoot(
                 transform: Transform::from_translation(bullet_origin),
                 ..default()
             },
-            RigidBody::Dynamic,
-            Bullet::new(weapon.damage, bullet_direction),
-            Collider::segment(Vec3::ZERO, bullet_direction*10.),
+            // moved by its velocity, what it hits is found by a ray cast in bullet_system
+            RigidBody::KinematicVelocityBased,
+            Bullet::new(weapon.damage, bullet_direction, player_id.id, team.copied(), weapon.kind, bullet_origin),
             Velocity{
                 linvel:BULLET_VELOCITY * bullet_direction,
                 angvel: Vec3::ZERO
             },
-            GravityScale(0.)
         ));
     }
 }   
 
+#[allow(clippy::too_many_arguments)]
 pub fn bullet_system(
     rapier_context: Res<RapierContext>,
-    bullet_q: Query<(&Bullet, Entity)>,
-    player_collider_q: Query<(&Hitbox, &Parent), With<Collider>>,
-    mut player_q: Query<&mut Player, With<PlayerId>>,
+    mut bullet_q: Query<(&mut Bullet, &Transform, Entity)>,
+    player_collider_q: Query<(&Hitbox, &Parent, Option<&BodyPart>), With<Collider>>,
+    mut player_q: Query<(&mut Player, &PlayerId, &Transform, Option<&Team>), Without<Bullet>>,
+    maze: Res<Maze>,
+    cvars: Option<Res<Cvars>>,
+    mut sounds: EventWriter<EmitSound>,
+    mut hits: EventWriter<PlayerHit>,
     mut cmd: Commands
 ) {
     // check if a bullet hits something
-    for (bullet, bullet_entity) in bullet_q.iter(){
-        for contact in rapier_context.contacts_with(bullet_entity){
-
-            let hit_collider = if contact.collider1() == bullet_entity {
-                contact.collider2()
-            } else {
-                contact.collider1()
-            };
-            
-            let Ok((damage_multiplier, parent)) = player_collider_q.get(hit_collider) else{
-                // if the bullet hits something that is not a player (wall, ground ...), despawn the bullet
-                cmd.entity(bullet_entity).despawn();
-                continue;          
-            };
-            
-            // player got hit
-            let Ok(mut player) = player_q.get_mut(parent.get()) else {
-                continue;
-            };
-
-            player.hp -= bullet.damage * damage_multiplier.0;
-            
-            cmd.entity(bullet_entity).despawn()                
-        }
+    for (mut bullet, bullet_transform, bullet_entity) in bullet_q.iter_mut(){
+        let travelled = bullet_transform.translation - bullet.last_position;
+        let start = bullet.last_position;
+        bullet.last_position = bullet_transform.translation;
+
+        // the bullet starts in the shooter's head, and goes through the dead players
+        let can_hit = |collider: Entity| match player_collider_q.get(collider){
+            Ok((_, parent, _)) => player_q.get(parent.get())
+                .map_or(true, |(player, player_id, _, _)| player_id.id != bullet.shooter && player.hp > 0.),
+            Err(_) => true,
+        };
+        let filter = QueryFilter::new()
+            .exclude_sensors()
+            // bullets only hit the hitboxes of the players, not their capsule
+            .groups(CollisionGroups::new(Group::ALL, Group::ALL ^ PLAYER_COLLISION_GROUP))
+            .predicate(&can_hit);
+
+        // the first collider on the way of the bullet since the last frame
+        let Some((hit_collider, _)) = rapier_context.cast_ray(start, travelled, 1., true, filter) else {continue};
+        cmd.entity(bullet_entity).despawn();
+
+        // if the bullet hits something else (wall, ground ...), it stops there
+        let Ok((damage_multiplier, parent, body_part)) = player_collider_q.get(hit_collider) else {continue};
+
+        let shooter_position = player_q.iter()
+            .find(|(_, player_id, _, _)| player_id.id == bullet.shooter)
+            .map(|(_, _, transform, _)| transform.translation);
+
+        // player got hit
+        let Ok((mut player, player_id, transform, team)) = player_q.get_mut(parent.get()) else {continue};
+        // the teammates are not hurt (see game.rs), the bullet stops
+        if cvars.as_ref().is_some_and(|cvars| !cvars.can_damage(bullet.team, team.copied())) {continue}
+
+        // the armor absorbs part of the damage until it is depleted
+        let damage = bullet.damage * damage_multiplier.0;
+        let absorbed = (damage * ARMOR_ABSORPTION).min(player.armor);
+        player.armor -= absorbed;
+        player.hp -= damage - absorbed;
+
+        let killed = player.hp <= 0.;
+        let event = if killed {SoundEvent::Death} else {SoundEvent::Hit};
+        sounds.send(EmitSound{ event, position: transform.translation, emitter: Some(player_id.id) });
+
+        hits.send(PlayerHit{
+            shooter: bullet.shooter,
+            victim: player_id.id,
+            weapon: bullet.weapon,
+            origin: bullet.origin,
+            damage,
+            headshot: body_part == Some(&BodyPart::Head),
+            wallbang: shooter_position.is_some_and(|shooter| !maze.line_of_sight(shooter, transform.translation)),
+            killed,
+        });
     }
 }
 
 pub fn reload_system(
-    mut player_q: Query<(&mut Player, &Weapon, &PlayerInput)>,
+    mut player_q: Query<(&mut Player, &Weapon, &PlayerInput, &PlayerId, &Transform)>,
+    mut sounds: EventWriter<EmitSound>,
     time: Res<Time>
 ){
 
-    for (mut player, weapon, player_input) in player_q.iter_mut(){
+    for (mut player, weapon, player_input, player_id, transform) in player_q.iter_mut(){
+        let mut reload_sound = |phase: ReloadPhase| sounds.send(EmitSound{
+            event: SoundEvent::Reload{ weapon: weapon.kind, phase },
+            position: transform.translation,
+            emitter: Some(player_id.id),
+        });
+
         // handle reload timer
         if player.reload_timer > 0.{
+            // the part 2 of the reload sound starts at a given progress of the reload
+            let part2_timer = weapon.reload_duration * (1. - weapon.kind.sounds().reload_part2_at);
+            let was_before_part2 = player.reload_timer > part2_timer;
+
             player.reload_timer -= time.delta_seconds();
-            player.just_reloaded = false; // player is currently in the process of reloading, so he did not just reloaded
+
+            if player.is_reloading && was_before_part2 && player.reload_timer <= part2_timer{
+                reload_sound(ReloadPhase::Part2);
+            }
         }
-        // reloading timer finished
+        // reloading timer finished, the magazine is filled with the reserve ammos
         if player.is_reloading && player.reload_timer <= 0.{
             player.is_reloading = false;
-            if player.ammos == 0{
-                player.ammos = weapon.ammos;
-            }else{                
-                player.ammos = weapon.ammos + 1;
-            }
+            let magazine = if player.ammos == 0{
+                weapon.ammos
+            }else{
+                weapon.ammos + 1
+            };
+            let needed = magazine.saturating_sub(player.ammos) as u16;
+            let taken = needed.min(player.reserve_ammos);
+            player.reserve_ammos -= taken;
+            player.ammos += taken as u8;
         }    
         // player reloads
-        if !player.is_reloading && player_input.reload{
+        if !player.is_reloading && player_input.reload && player.reserve_ammos > 0{
             player.is_reloading = true;
-            player.just_reloaded = true;
             player.reload_timer = weapon.reload_duration;
+            reload_sound(ReloadPhase::Part1);
         }
     }
 }
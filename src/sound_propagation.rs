@@ -0,0 +1,117 @@
+//! How a sound travels through the maze from a source to a listener.
+//! The sound follows the open tiles (path distance), and is muffled when a wall
+//! blocks the line of sight. Used to play the sounds (sounds.rs), and usable by
+//! anything that needs to "hear" (AI ...).
+use bevy::{prelude::*, ecs::system::SystemParam};
+use std::collections::VecDeque;
+
+use crate::world::Maze;
+
+// the sound does not propagate after the MAX_SOUND_RANGE value (path distance)
+pub const MAX_SOUND_RANGE: f32 = 35.;
+// below this value, you'll hear the sound at maximum volume
+pub const MIN_SOUND_RANGE: f32 = 5.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PropagatedSound{
+    /// volume after the distance attenuation, between 0 and 1
+    pub volume: f32,
+    /// 1 when a wall is between the source and the listener, 0 otherwise
+    pub muffle: f32,
+    /// -1 when the sound comes from the left of the listener, 1 from the right
+    pub pan: f32,
+    /// distance travelled by the sound through the open tiles
+    pub distance: f32,
+}
+
+/// distance (in tiles) from the origin tile to every open tile, None when not reachable
+#[derive(Default)]
+pub struct DistanceField{
+    origin: Option<(usize, usize)>,
+    distances: Vec<Vec<Option<u32>>>,
+}
+
+#[derive(SystemParam)]
+pub struct SoundPropagation<'w, 's>{
+    maze: Res<'w, Maze>,
+    // the distance field of the last listener tile, most sounds are heard by the same listener
+    cache: Local<'s, DistanceField>,
+}
+
+impl<'w, 's> SoundPropagation<'w, 's>{
+    /// how a sound emitted at `source` is heard by a listener at `listener` looking towards `listener_yaw`.
+    /// None if the sound cannot reach the listener
+    pub fn propagate(&mut self, source: Vec3, listener: Vec3, listener_yaw: f32) -> Option<PropagatedSound>{
+        let line_of_sight = self.maze.line_of_sight(source, listener);
+        let straight_distance = source.distance(listener);
+
+        let distance = if line_of_sight{
+            straight_distance
+        }else{
+            // from the listener, so the cached distance field is reused for every sound
+            self.path_distance(listener, source)?.max(straight_distance)
+        };
+        if distance > MAX_SOUND_RANGE {return None}
+
+        // the right of the player is -X (the model faces -forward, see LookAngles)
+        let right = Quat::from_rotation_y(listener_yaw) * Vec3::NEG_X;
+        let direction = (source - listener) * Vec3::new(1., 0., 1.);
+        let pan = direction.normalize_or_zero().dot(right);
+
+        Some(PropagatedSound{
+            volume: distance_attenuation(distance),
+            muffle: if line_of_sight {0.} else {1.},
+            pan,
+            distance,
+        })
+    }
+
+    /// distance following the open tiles (4-connected), None if there is no path
+    pub fn path_distance(&mut self, from: Vec3, to: Vec3) -> Option<f32>{
+        // a position against a wall can round onto the wall tile
+        let from_tile = self.maze.nearest_walkable_tile(from)?;
+        let (row, col) = self.maze.nearest_walkable_tile(to)?;
+
+        if self.cache.origin != Some(from_tile) || self.maze.is_changed(){
+            *self.cache = self.distance_field(from_tile);
+        }
+
+        let tiles = self.cache.distances[row][col]?;
+        Some(tiles as f32 * self.maze.tile_size)
+    }
+
+    /// breadth first search from the origin tile
+    fn distance_field(&self, origin: (usize, usize)) -> DistanceField{
+        let mut distances: Vec<Vec<Option<u32>>> = self.maze.map.iter()
+            .map(|line| vec![None; line.len()])
+            .collect();
+
+        let mut queue = VecDeque::from([origin]);
+        distances[origin.0][origin.1] = Some(0);
+
+        while let Some((row, col)) = queue.pop_front(){
+            let distance = distances[row][col].unwrap_or(0);
+
+            for (d_row, d_col) in [(-1, 0), (1, 0), (0, -1), (0, 1)]{
+                let (next_row, next_col) = (row as i32 + d_row, col as i32 + d_col);
+                if self.maze.is_wall(next_row, next_col) {continue}
+
+                let next = &mut distances[next_row as usize][next_col as usize];
+                if next.is_some() {continue}
+
+                *next = Some(distance + 1);
+                queue.push_back((next_row as usize, next_col as usize));
+            }
+        }
+
+        DistanceField{ origin: Some(origin), distances }
+    }
+}
+
+/// not a bevy system. The shortest the distance between you and the emitter, the louder the sound
+pub fn distance_attenuation(distance: f32) -> f32{
+    match distance{
+        x if x < MIN_SOUND_RANGE => 1.,
+        x => (1. - x / MAX_SOUND_RANGE).max(0.)
+    }
+}
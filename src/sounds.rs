@@ -1,16 +1,252 @@
 
-use bevy::prelude::*;
+use bevy::{prelude::*, audio::{Decodable, Volume}, asset::HandleId, reflect::{TypeUuid, TypePath}, utils::HashMap};
+use rodio::{Decoder, Source, source::Empty};
+use serde::{Serialize, Deserialize};
+use rand::{Rng, seq::SliceRandom};
+use std::{io::Cursor, sync::Arc};
 
-use crate::player::{Player, PlayerInput, PlayerState, CurrentPlayer};
+use crate::{
+    player::{Player, PlayerId, CurrentPlayer},
+    movement::{PlayerMotion, PLAYER_SLOW_MOVE_SPEED},
+    world::{Maze, Surface},
+    weapons::WeaponKind,
+    pickup::PickupKind,
+    network::mods::Lobby,
+    settings::Settings,
+    camera::LookAngles,
+    sound_propagation::{SoundPropagation, PropagatedSound},
+};
 
 #[derive(Component)]
 pub struct Emitter;
 
+/// the mixer bus of a sound, every spawned sound is tagged with one.
+/// Its volume is the master volume times the volume of its category (see settings.rs)
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SoundCategory{
+    Music,
+    Ambience,
+    Weapons,
+    Footsteps,
+    /// the players' pain and death cries
+    Voice,
+    Ui,
+}
+
+impl SoundCategory{
+    pub const ALL: [SoundCategory; 6] = [
+        SoundCategory::Music,
+        SoundCategory::Ambience,
+        SoundCategory::Weapons,
+        SoundCategory::Footsteps,
+        SoundCategory::Voice,
+        SoundCategory::Ui,
+    ];
+
+    pub fn default_volume(&self) -> f32{
+        match self{
+            SoundCategory::Music => 0.6,
+            SoundCategory::Ambience => 0.8,
+            _ => 1.
+        }
+    }
+}
+
+
+//////////////////////////// EVENTS ///////////////////////////////
+
+/// the part of the reload the sound belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ReloadPhase{
+    Part1,
+    Part2
+}
+
+/// a sound made in the world. The server sends it once, when it happens,
+/// and each client picks the samples to play in its SoundCatalog
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum SoundEvent{
+    Gunshot{ weapon: WeaponKind },
+    Reload{ weapon: WeaponKind, phase: ReloadPhase },
+    /// quiet steps are made by walking players
+    Footstep{ surface: Surface, quiet: bool },
+    Hit,
+    Death,
+    Pickup{ kind: PickupKind },
+}
+
+// above this distance, the shots are heard with the distant sample of the weapon
+const DISTANT_SHOT_RANGE: f32 = 20.;
+
+impl SoundEvent{
+    /// every event that can be played
+    fn all() -> Vec<SoundEvent>{
+        let mut events = vec![SoundEvent::Hit, SoundEvent::Death];
+        for weapon in WeaponKind::ALL{
+            events.push(SoundEvent::Gunshot{ weapon });
+            events.push(SoundEvent::Reload{ weapon, phase: ReloadPhase::Part1 });
+            events.push(SoundEvent::Reload{ weapon, phase: ReloadPhase::Part2 });
+        }
+        for surface in Surface::ALL{
+            events.push(SoundEvent::Footstep{ surface, quiet: false });
+            events.push(SoundEvent::Footstep{ surface, quiet: true });
+        }
+        for kind in [PickupKind::Ammo, PickupKind::Health, PickupKind::Armor]{
+            events.push(SoundEvent::Pickup{ kind });
+        }
+        events
+    }
+
+    /// the samples (paths in the assets) played together for this event, heard from afar or not
+    fn samples(&self, distant: bool) -> Vec<&'static str>{
+        match self{
+            SoundEvent::Gunshot{ weapon } => {
+                let sounds = weapon.sounds();
+                if distant {vec![sounds.distant_shot]} else {vec![sounds.shot, sounds.tail]}
+            },
+            SoundEvent::Reload{ weapon, phase } => {
+                let [part1, part2] = weapon.sounds().reload;
+                match phase{
+                    ReloadPhase::Part1 => vec![part1],
+                    ReloadPhase::Part2 => vec![part2],
+                }
+            },
+
+            // the recordings, a single step of them is played (see slice_footsteps)
+            SoundEvent::Footstep{ surface: Surface::Grass, .. } => vec![FOREST_FOOTSTEPS],
+            SoundEvent::Footstep{ surface: Surface::Stone | Surface::Wood, .. } => vec![HALLWAY_FOOTSTEPS],
+
+            SoundEvent::Pickup{ .. } => vec!["sounds/gun/Reloads, Cycling & More/WAV/AR Bolt Release WAV.wav"],
+
+            // generated, see assets/sounds/generated/README.md
+            SoundEvent::Hit => vec!["sounds/generated/hit.wav"],
+            SoundEvent::Death => vec!["sounds/generated/death.wav"],
+        }
+    }
+
+    pub fn category(&self) -> SoundCategory{
+        match self{
+            SoundEvent::Gunshot{..} | SoundEvent::Reload{..} => SoundCategory::Weapons,
+            SoundEvent::Footstep{..} => SoundCategory::Footsteps,
+            SoundEvent::Hit | SoundEvent::Death => SoundCategory::Voice,
+            SoundEvent::Pickup{..} => SoundCategory::Ui,
+        }
+    }
+}
+
+/// a sound made in the world by the gameplay systems (server side).
+/// Broadcast to the clients by the server (see server.rs)
+#[derive(Event, Debug, Clone, Copy)]
+pub struct EmitSound{
+    pub event: SoundEvent,
+    pub position: Vec3,
+    /// the player making the sound, the sound follows him on the clients
+    pub emitter: Option<u64>,
+}
+
+/// the samples of every sound event, loaded once when the client starts
+#[derive(Resource)]
+pub struct SoundCatalog{
+    samples: HashMap<&'static str, Handle<AudioSource>>,
+    /// the single steps cut from each footsteps recording
+    steps: HashMap<&'static str, Vec<Handle<AudioSource>>>,
+}
+
+impl FromWorld for SoundCatalog{
+    fn from_world(world: &mut World) -> Self {
+        let assets = world.resource::<AssetServer>();
+        let samples = SoundEvent::all().into_iter()
+            .flat_map(|event| [event.samples(false), event.samples(true)])
+            .flatten()
+            .map(|path| (path, assets.load(path)))
+            .collect();
+        Self{ samples, steps: HashMap::default() }
+    }
+}
+
+impl SoundCatalog{
+    /// the samples to play for an event emitted `distance` away from the listener
+    pub fn get(&self, event: &SoundEvent, distance: f32) -> Vec<Handle<AudioSource>>{
+        let paths = event.samples(distance > DISTANT_SHOT_RANGE).into_iter();
+        match event{
+            // a random step of the recording, nothing until it is cut
+            SoundEvent::Footstep{..} => paths
+                .filter_map(|path| self.steps.get(path)?.choose(&mut rand::thread_rng()).cloned())
+                .collect(),
+            _ => paths.filter_map(|path| self.samples.get(path).cloned()).collect(),
+        }
+    }
+}
 
+//////////////////////////// SERVER ///////////////////////////////
+
+// horizontal distance travelled between two footsteps, the faster you move the more steps you make
+const STRIDE_LENGTH: f32 = 1.8;
+// below this speed the steps are quiet (walking)
+const QUIET_STEP_SPEED: f32 = PLAYER_SLOW_MOVE_SPEED + 0.5;
+// a bigger displacement in one frame is a teleportation (respawn ...), not a step
+const MAX_STEP_DISPLACEMENT: f32 = 2.;
+
+#[derive(Default)]
+pub struct Stride{
+    last_position: Option<Vec3>,
+    travelled: f32,
+}
+
+/// moving players make footsteps noise, at a cadence following their speed.
+/// Crouched players are silent
+pub fn emit_footsteps(
+    mut player_q: Query<(&mut Player, &PlayerMotion, &PlayerId, &Transform)>,
+    maze: Res<Maze>,
+    mut sounds: EventWriter<EmitSound>,
+    mut strides: Local<HashMap<u64, Stride>>,
+    time: Res<Time>,
+){
+    // forget the players that left
+    strides.retain(|id, _| player_q.iter().any(|(_, _, player_id, _)| player_id.id == *id));
+
+    for (mut player, motion, player_id, transform) in player_q.iter_mut(){
+        let stride = strides.entry(player_id.id).or_default();
+        let position = transform.translation;
+
+        let displacement = stride.last_position
+            .map_or(0., |last| ((position - last) * Vec3::new(1., 0., 1.)).length());
+        stride.last_position = Some(position);
+
+        let speed = displacement / time.delta_seconds().max(f32::EPSILON);
+        player.is_running = speed > QUIET_STEP_SPEED;
+
+        if displacement == 0. || displacement > MAX_STEP_DISPLACEMENT || !motion.grounded || player.is_crouching{
+            // the first step is made as soon as the player starts moving
+            stride.travelled = STRIDE_LENGTH;
+            continue;
+        }
+
+        stride.travelled += displacement;
+        if stride.travelled < STRIDE_LENGTH {continue}
+        stride.travelled = 0.;
+
+        sounds.send(EmitSound{
+            event: SoundEvent::Footstep{ surface: maze.surface_at(position), quiet: !player.is_running },
+            position,
+            emitter: Some(player_id.id),
+        });
+    }
+}
+
+//////////////////////////// CLIENT ///////////////////////////////
+
+// each step is played a bit higher or lower, so the steps don't all sound the same
+const STEP_PITCH_VARIATION: f32 = 0.1;
+const QUIET_STEP_GAIN: f32 = 0.35;
+
+/// multiplies the volume of an emitted sound
 #[derive(Component)]
-pub struct FootStepSound(pub u64);
+pub struct SoundGain(pub f32);
+
+/// a point in the world playing a sound that is not made by a player
 #[derive(Component)]
-struct Music;
+pub struct SoundSource;
 
 pub fn sound_player_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
     // ambiance music
@@ -20,7 +256,7 @@ pub fn sound_player_setup(mut commands: Commands, asset_server: Res<AssetServer>
             source: asset_server.load("sounds/night_ambiance_village.ogg"),
             settings: PlaybackSettings::LOOP
         },
-        Music,
+        SoundCategory::Ambience,
     ));
 
     // reload sound on spawn
@@ -30,131 +266,456 @@ pub fn sound_player_setup(mut commands: Commands, asset_server: Res<AssetServer>
             source: asset_server.load("sounds/ak-reload-with-rack.ogg"),
             settings: PlaybackSettings::DESPAWN
         },
+        SoundCategory::Weapons,
     ));
 }
 
-pub fn handle_sound_emmission(
-    mut player_states_q: Query<(&mut Player, &PlayerInput)>,
+/// not a bevy system. Play a sound event received from the server
+pub fn play_sound_event(
+    commands: &mut Commands,
+    catalog: &SoundCatalog,
+    lobby: &Lobby,
+    event: SoundEvent,
+    position: Vec3,
+    emitter: Option<u64>,
+    listener: Option<Vec3>,
+){
+    let distance = listener.map_or(0., |listener| listener.distance(position));
+    let sources = catalog.get(&event, distance);
+    if sources.is_empty() {return}
+    let player_entity = emitter.and_then(|id| lobby.players.get(&id).copied());
+
+    // the sound follows the player who made it
+    let parent = match player_entity{
+        Some(player_entity) => player_entity,
+        None => commands.spawn((
+            Name::new("Sound Source"),
+            SoundSource,
+            TransformBundle::from_transform(Transform::from_translation(position)),
+        )).id()
+    };
+
+    // one sample per step, quieter when walking
+    let (settings, gain) = match event{
+        SoundEvent::Footstep{ quiet, .. } => (
+            PlaybackSettings::DESPAWN.with_speed(1. + rand::thread_rng().gen_range(-STEP_PITCH_VARIATION..STEP_PITCH_VARIATION)),
+            if quiet {QUIET_STEP_GAIN} else {1.}
+        ),
+        _ => (PlaybackSettings::DESPAWN, 1.),
+    };
+
+    commands.entity(parent).with_children(|parent|{
+        for source in sources{
+            parent.spawn((
+                Name::new("Sound Effect"),
+                emitted_sound(source, settings, event.category()),
+                SoundGain(gain),
+            ));
+        }
+    });
+}
+
+/// the sounds played by a source, dry or muffled
+type SourceSoundQuery<'w, 's> = Query<'w, 's, (), Or<(With<Emitter>, With<MuffledLayer>)>>;
+
+/// the sound sources disappear with their sounds
+pub fn despawn_silent_sources(
+    mut commands: Commands,
+    source_q: Query<(Entity, Option<&Children>), With<SoundSource>>,
+    sound_q: SourceSoundQuery,
+){
+    for (entity, children) in source_q.iter(){
+        let is_playing = children.is_some_and(|children| children.iter().any(|&child| sound_q.contains(child)));
+        if !is_playing{
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+//////////////////////////// FOOTSTEPS ///////////////////////////////
+
+// the footsteps recordings, cut into single steps when they are loaded
+const FOREST_FOOTSTEPS: &str = "sounds/footsteps-forest.ogg";
+const HALLWAY_FOOTSTEPS: &str = "sounds/footsteps-hallway.ogg";
+// the loudness of the recordings is measured on windows of this duration (in seconds)
+const LOUDNESS_WINDOW: f32 = 0.05;
+// a step is found when the loudness goes above this fraction of the loudest window,
+// its loudest window is searched during STEP_PEAK_SEARCH seconds
+const STEP_THRESHOLD: f32 = 0.3;
+const STEP_PEAK_SEARCH: f32 = 0.15;
+// a step starts a bit before its loudest window, the next one can't start before its end
+const STEP_LEAD: f32 = 0.05;
+const STEP_DURATION: f32 = 0.35;
+// so the cut steps don't click
+const STEP_FADE_IN: f32 = 0.01;
+const STEP_FADE_OUT: f32 = 0.1;
+
+/// cut the footsteps recordings into single steps, once they are loaded
+pub fn slice_footsteps(
+    mut catalog: ResMut<SoundCatalog>,
+    mut audio_sources: ResMut<Assets<AudioSource>>,
 ){
-    for (mut player, input) in player_states_q.iter_mut(){
-        player.emmited_sound = match &player{
-            p if p.is_shooting => Some("sounds/gunshot.ogg".to_string()),
-            p if p.just_reloaded => Some("sounds/ak-reload-with-rack.ogg".to_string()),
-            _ => None
+    for path in [FOREST_FOOTSTEPS, HALLWAY_FOOTSTEPS]{
+        if catalog.steps.contains_key(path) {continue}
+        let Some(recording) = catalog.samples.get(path).and_then(|handle| audio_sources.get(handle)) else {continue};
+
+        let steps = match cut_steps(recording.bytes.clone()){
+            Ok(steps) => steps,
+            Err(e) => {
+                // the footsteps on this surface stay silent
+                eprintln!("ERR: can't cut the steps of {}: {}", path, e);
+                vec![]
+            }
         };
+        let steps = steps.into_iter().map(|bytes| audio_sources.add(AudioSource{ bytes: bytes.into() })).collect();
+        catalog.steps.insert(path, steps);
+    }
+}
+
+/// not a bevy system. The steps of a footsteps recording, as wav files
+fn cut_steps(recording: Arc<[u8]>) -> Result<Vec<Vec<u8>>, String>{
+    let decoder = Decoder::new(Cursor::new(recording)).map_err(|e| e.to_string())?;
+    let (channels, rate) = (decoder.channels() as usize, decoder.sample_rate());
+    let samples: Vec<i16> = decoder.collect();
+    let frames = samples.len() / channels;
+    let to_frames = |seconds: f32| (seconds * rate as f32) as usize;
+
+    let window = to_frames(LOUDNESS_WINDOW).max(1);
+    let loudness: Vec<f32> = samples.chunks(window * channels)
+        .map(|chunk| (chunk.iter().map(|&sample| (sample as f32).powi(2)).sum::<f32>() / chunk.len() as f32).sqrt())
+        .collect();
+    let threshold = loudness.iter().copied().fold(0., f32::max) * STEP_THRESHOLD;
+    if threshold <= 0. {return Ok(vec![])}
 
-        if player.emmited_sound.is_some() {continue;}
+    let mut steps = vec![];
+    let mut next_start = 0;
+    for (i, &value) in loudness.iter().enumerate(){
+        if value < threshold || i * window < next_start {continue}
 
-        player.is_running = !input.is_walking && 
-            (input.left || input.right || input.up || input.down);
+        let search_end = (i + to_frames(STEP_PEAK_SEARCH) / window + 1).min(loudness.len());
+        let peak = (i..search_end).max_by(|&a, &b| loudness[a].total_cmp(&loudness[b])).unwrap_or(i);
+        let start = (peak * window).saturating_sub(to_frames(STEP_LEAD));
+        let end = (start + to_frames(STEP_DURATION)).min(frames);
+        next_start = end;
+
+        let mut step = samples[start * channels..end * channels].to_vec();
+        let (length, fade_in, fade_out) = (end - start, to_frames(STEP_FADE_IN).max(1), to_frames(STEP_FADE_OUT).max(1));
+        for (frame, samples) in step.chunks_mut(channels).enumerate(){
+            let gain = (frame as f32 / fade_in as f32).min((length - frame) as f32 / fade_out as f32).min(1.);
+            for sample in samples{
+                *sample = (*sample as f32 * gain) as i16;
+            }
+        }
+        steps.push(wav_file(&step, channels as u16, rate));
     }
+    Ok(steps)
 }
 
-/// not a bevy system
-pub fn play_sound_effect(
-    commands: &mut Commands,
-    player: &PlayerState,
-    assets: &Res<AssetServer>,
-    footsteps_q: &Query<(&FootStepSound, Entity)>,
-    player_id: &u64,
-    player_entity: &Entity
+/// not a bevy system. A 16 bits PCM wav file
+fn wav_file(samples: &[i16], channels: u16, rate: u32) -> Vec<u8>{
+    let data_size = samples.len() as u32 * 2;
+    let mut bytes = Vec::with_capacity(44 + data_size as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_size).to_le_bytes());
+    bytes.extend_from_slice(b"WAVEfmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, channels, sample rate, bytes per second, bytes per frame, bits per sample
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&channels.to_le_bytes());
+    bytes.extend_from_slice(&rate.to_le_bytes());
+    bytes.extend_from_slice(&(rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend_from_slice(&(channels * 2).to_le_bytes());
+    bytes.extend_from_slice(&16u16.to_le_bytes());
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples{
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+    bytes
+}
+
+//////////////////////////// PROPAGATION ///////////////////////////////
+
+// the emitted sounds are played by spatial sinks with fixed ears,
+// the emitter is moved around the ears to pan the sound (see sound_propagation.rs)
+const EAR_GAP: f32 = 1.;
+// cutoff frequency (Hz) of the muffled layer
+const MUFFLED_CUTOFF: u32 = 700;
+// a muffled sound is also quieter
+const MUFFLED_GAIN: f32 = 0.6;
+// how fast (per second) a sound becomes muffled when a wall comes between you and the emitter
+const MUFFLE_SPEED: f32 = 8.;
+
+/// the same sound through a low-pass filter, heard when a wall is between the emitter and the listener
+#[derive(TypeUuid, TypePath, Clone)]
+#[uuid = "0f6b4c8e-5d1a-4f7e-9c3b-2a8d7e6f1b45"]
+pub struct MuffledAudio{
+    source: AudioSource
+}
+
+impl Decodable for MuffledAudio{
+    type DecoderItem = f32;
+    type Decoder = Box<dyn Source<Item = f32> + Send>;
+
+    fn decoder(&self) -> Self::Decoder {
+        match Decoder::new(Cursor::new(self.source.clone())){
+            Ok(decoder) => Box::new(decoder.convert_samples().low_pass(MUFFLED_CUTOFF)),
+            Err(err) => {
+                // the muffled layer stays silent, the dry sound is still heard
+                eprintln!("ERR: can't decode the muffled sound: {}", err);
+                Box::new(Empty::new())
+            }
+        }
+    }
+}
+
+/// muffled version of each audio source already played
+#[derive(Resource, Default)]
+pub struct MuffledSources(HashMap<HandleId, Handle<MuffledAudio>>);
+
+/// how an emitted sound is currently heard by the player
+#[derive(Component, Default)]
+pub struct Propagation{
+    // None until the sound is heard for the first time
+    muffle: Option<f32>,
+    volume: f32,
+    pan: f32,
+    has_muffled_layer: bool,
+    // None for the sounds that are never muffled
+    muffled_layer: Option<Entity>,
+    started: bool,
+}
+
+/// the low-passed twin of an emitted sound
+#[derive(Component)]
+pub struct MuffledLayer{
+    dry: Entity
+}
+
+/// not a bevy system. A sound emitted in the world by its parent entity (player, pickup ...).
+/// It starts silent and paused, the volume_system sets its volume and panning,
+/// and it is started with its muffled layer by start_emitted_sounds
+pub fn emitted_sound(source: Handle<AudioSource>, settings: PlaybackSettings, category: SoundCategory) -> impl Bundle{
+    (
+        SpatialAudioBundle{
+            source,
+            settings: settings.with_volume(Volume::new_relative(0.)).paused(),
+            spatial: ears(),
+        },
+        Emitter,
+        category,
+        Propagation::default(),
+    )
+}
+
+fn ears() -> SpatialSettings{
+    SpatialSettings::new(Transform::IDENTITY, EAR_GAP, Vec3::NEG_Z)
+}
+
+/// position of the emitter around the fixed ears, `pan` is -1 (left) to 1 (right)
+fn virtual_emitter_position(pan: f32) -> Vec3{
+    Vec3::new(pan, 0., -1.)
+}
+
+/// the emitted sounds, and their propagation
+type EmittedSoundQuery<'w, 's> = Query<'w, 's, (Entity, &'static Handle<AudioSource>, &'static PlaybackSettings, &'static SoundCategory, &'static Parent, &'static mut Propagation)>;
+
+/// spawn the muffled twin of the emitted sounds, once their source is loaded
+pub fn add_muffled_layers(
+    mut cmd: Commands,
+    mut sound_q: EmittedSoundQuery,
+    current_player_q: Query<(), With<CurrentPlayer>>,
+    audio_sources: Res<Assets<AudioSource>>,
+    mut muffled_audios: ResMut<Assets<MuffledAudio>>,
+    mut muffled_sources: ResMut<MuffledSources>,
 ){
+    for (entity, source, settings, category, parent, mut propagation) in sound_q.iter_mut(){
+        if propagation.has_muffled_layer {continue}
 
-    // play sound effect (other than footstep)
-    if let Some(audio_path) = &player.state.emmited_sound{
+        // the sounds made by the current player are never muffled
+        if current_player_q.contains(parent.get()){
+            propagation.has_muffled_layer = true;
+            continue;
+        }
 
-        let sound_bundle = (
-            Name::new("Sound Effect"),
-            AudioBundle {
-                source: assets.load(audio_path),
-                settings: PlaybackSettings::DESPAWN
-            },
-            Emitter
-        );
+        let Some(audio) = audio_sources.get(source) else {continue};
+        let muffled = muffled_sources.0.entry(source.id())
+            .or_insert_with(|| muffled_audios.add(MuffledAudio{ source: audio.clone() }))
+            .clone();
 
-        commands.entity(*player_entity).with_children(|parent|{
-            parent.spawn(sound_bundle);
+        cmd.entity(parent.get()).with_children(|parent|{
+            let layer = parent.spawn((
+                Name::new("Muffled Sound"),
+                SpatialAudioSourceBundle{
+                    source: muffled,
+                    // paused like its dry sound
+                    settings: *settings,
+                    spatial: ears(),
+                },
+                MuffledLayer{ dry: entity },
+                *category,
+            )).id();
+            propagation.muffled_layer = Some(layer);
         });
+        propagation.has_muffled_layer = true;
     }
+}
 
-    // make sure that a single player cannot make multiple footstep noise at once
-    let mut is_making_footsteps_noise = false;
-    for (footstep, entity) in footsteps_q.iter(){
-        if footstep.0 != *player_id {continue;}
-        // when the player stops running, stop making noise
-        if !player.state.is_running{
-            commands.entity(entity).despawn();
-            break;
-        }
-        
-        is_making_footsteps_noise = true;
-        break;
-    }
-
-    // footstep noise if running
-    if player.state.is_running && !is_making_footsteps_noise{
-        let entity = commands.spawn((
-            Name::new("Footstep Sound"),
-            FootStepSound(*player_id),
-            Emitter,
-            AudioBundle {
-                source: assets.load("sounds/footsteps-forest.ogg"),
-                settings: PlaybackSettings::DESPAWN
-            },
-        )).id();
+/// the emitted sounds and their muffled layer are started in the same frame, once both are loaded,
+/// so they stay in sync
+pub fn start_emitted_sounds(
+    mut sound_q: Query<(&SpatialAudioSink, &mut Propagation), With<Emitter>>,
+    layer_q: Query<Option<&SpatialAudioSink>, With<MuffledLayer>>,
+){
+    for (sink, mut propagation) in sound_q.iter_mut(){
+        if propagation.started || !propagation.has_muffled_layer {continue}
 
-        commands
-            .entity(*player_entity)
-            .add_child(entity);
+        if let Some(layer) = propagation.muffled_layer{
+            match layer_q.get(layer){
+                // the layer is still loading
+                Ok(None) => continue,
+                Ok(Some(layer_sink)) => layer_sink.play(),
+                // the layer is already gone (it couldn't be decoded), the dry sound plays alone
+                Err(_) => {}
+            }
+        }
+        sink.play();
+        propagation.started = true;
     }
 }
 
+/// the muffled layer stops with its sound (footsteps ...)
+pub fn despawn_orphan_layers(
+    mut cmd: Commands,
+    layer_q: Query<(Entity, &MuffledLayer)>,
+    sound_q: Query<(), With<Emitter>>,
+){
+    for (entity, layer) in layer_q.iter(){
+        if !sound_q.contains(layer.dry){
+            cmd.entity(entity).despawn();
+        }
+    }
+}
 
+/// the spatial sounds not emitted in the world (their volume is not propagated)
+type SpatialSinkQuery<'w, 's> = Query<'w, 's, (&'static SpatialAudioSink, Option<&'static SoundCategory>), (Without<Emitter>, Without<MuffledLayer>)>;
 
-// in the volume system, we define the sound propagation very simply : 
-// the shortest the distance between you and the emmiter, the louder the sound
-// The sound does not propagate after the MAX_SOUND_RANGE value
-const MAX_SOUND_RANGE: f32 = 35.;
-// below this value, you'll hear the sound at maximum volume
-const MIN_SOUND_RANGE: f32 = 5.;
+/// the sounds emitted in the world, with their propagation and gain
+type EmitterSinkQuery<'w, 's> = Query<'w, 's, (&'static SpatialAudioSink, &'static SoundCategory, &'static Parent, &'static mut Propagation, Option<&'static SoundGain>), With<Emitter>>;
 
+/// the volume of a sound is the volume of its bus (master * category),
+/// times the propagation through the maze for the sounds emitted in the world
+#[allow(clippy::too_many_arguments)]
 pub fn volume_system(
-    audio_q: Query<(&AudioSink, &Parent), With<Emitter>>,
-    emitter_q: Query<&Transform, Without<CurrentPlayer>>,
-    current_player_q: Query<&Transform, With<CurrentPlayer>>
-
+    audio_q: Query<(&AudioSink, Option<&SoundCategory>)>,
+    spatial_audio_q: SpatialSinkQuery,
+    mut emitted_q: EmitterSinkQuery,
+    layer_q: Query<(&SpatialAudioSink, &MuffledLayer)>,
+    emitter_q: Query<&Transform>,
+    listener_q: Query<(Entity, &Transform, &LookAngles), With<CurrentPlayer>>,
+    mut sound_propagation: SoundPropagation,
+    settings: Res<Settings>,
+    time: Res<Time>,
 ){
-    for (sink, parent) in audio_q.iter(){
-
-        // if the current player is the emitter of the sound, play the sound at maximum volume 
-        // otherwise, calculate the distance between the emmiter and the current player 
-        // and set the volume appropriatly.
-        if current_player_q.get(parent.get()).is_ok(){
-            sink.set_volume(1.);
-        }else{
-            let Ok(emitter) = emitter_q.get(parent.get()) else{
-                eprintln!("ERR: emitter of the sound not found");
-                continue;
-            };
-            let Ok(current_player) = current_player_q.get_single() else{
-                eprintln!("ERR: current player not found");
-                continue;
-            };
-            let dist = match (current_player.translation - emitter.translation).length(){
-                x if x<1. => 1.,
-                x => x
-            };
-
-            // x is distance, v is the volume
-            let volume = match dist{
-                x if x < MIN_SOUND_RANGE => 1.,
-                x => match 1. - (1./(MAX_SOUND_RANGE))*x {
-                    v if v < 0. => 0.,
-                    v => v
-                }
-            };
+    let bus_volume = |category: Option<&SoundCategory>| match category{
+        Some(category) => settings.volume(*category),
+        None => settings.master_volume(),
+    };
+
+    // sounds that are not emitted by someone (ui, music ...) don't depend on the maze
+    for (sink, category) in audio_q.iter(){
+        sink.set_volume(bus_volume(category));
+    }
+    for (sink, category) in spatial_audio_q.iter(){
+        sink.set_volume(bus_volume(category));
+    }
+
+    let listener = listener_q.get_single().ok();
+
+    for (sink, category, parent, mut propagation, gain) in emitted_q.iter_mut(){
+        let heard = match listener{
+            // if the current player is the emitter of the sound, play the sound at maximum volume
+            Some((listener_entity, _, _)) if listener_entity == parent.get() => Some(PropagatedSound{
+                volume: 1., muffle: 0., pan: 0., distance: 0.
+            }),
+            Some((_, listener_transform, look)) => {
+                let Ok(emitter) = emitter_q.get(parent.get()) else{
+                    eprintln!("ERR: emitter of the sound not found");
+                    continue;
+                };
+                sound_propagation.propagate(emitter.translation, listener_transform.translation, look.yaw)
+            },
+            None => None
+        };
 
-            sink.set_volume(volume);
+        // too far, or no path to the listener
+        let Some(heard) = heard else {
+            propagation.volume = 0.;
+            sink.set_volume(0.);
+            continue;
+        };
+
+        let muffle = match propagation.muffle{
+            Some(muffle) => muffle + (heard.muffle - muffle) * (MUFFLE_SPEED * time.delta_seconds()).min(1.),
+            None => heard.muffle
+        };
+        propagation.muffle = Some(muffle);
+        propagation.volume = heard.volume * settings.volume(*category) * gain.map_or(1., |gain| gain.0);
+        propagation.pan = heard.pan;
+
+        sink.set_volume(propagation.volume * (1. - muffle));
+        sink.set_emitter_position(virtual_emitter_position(heard.pan));
+    }
+
+    for (sink, layer) in layer_q.iter(){
+        let Ok((dry_sink, _, _, propagation, _)) = emitted_q.get(layer.dry) else {continue};
+        let muffle = propagation.muffle.unwrap_or(0.);
+
+        sink.set_speed(dry_sink.speed());
+        sink.set_volume(propagation.volume * muffle * MUFFLED_GAIN);
+        sink.set_emitter_position(virtual_emitter_position(propagation.pan));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// the duration (in seconds) of a wav file
+    fn duration(wav: Vec<u8>) -> f32{
+        let decoder = Decoder::new(Cursor::new(wav)).unwrap();
+        let (channels, rate) = (decoder.channels() as f32, decoder.sample_rate() as f32);
+        decoder.count() as f32 / channels / rate
+    }
+
+    #[test]
+    fn cut_a_step_per_click(){
+        let rate = 8000;
+        let mut samples = vec![0i16; rate * 3];
+        for click in [0.5, 1.2, 2.9]{
+            let start = (click * rate as f32) as usize;
+            for (i, sample) in samples[start..].iter_mut().take(rate / 50).enumerate(){
+                *sample = if i % 2 == 0 {8000} else {-8000};
+            }
         }
+        let steps = cut_steps(wav_file(&samples, 1, rate as u32).into()).unwrap();
+        assert_eq!(steps.len(), 3);
+        // the last one is cut by the end of the recording
+        let durations: Vec<f32> = steps.into_iter().map(duration).collect();
+        assert!((durations[0] - STEP_DURATION).abs() < 0.01 && (durations[1] - STEP_DURATION).abs() < 0.01);
+        assert!(durations[2] < STEP_DURATION);
+
+        let silence = wav_file(&[0; 8000], 1, 8000);
+        assert!(cut_steps(silence.into()).unwrap().is_empty());
+        assert!(cut_steps(Arc::from(&b"not a sound"[..])).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cut_the_bundled_recordings(){
+        for path in [FOREST_FOOTSTEPS, HALLWAY_FOOTSTEPS]{
+            let recording = std::fs::read(format!("assets/{}", path)).unwrap();
+            let steps = cut_steps(recording.into()).unwrap();
+            assert!(steps.len() >= 20, "{} steps in {}", steps.len(), path);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Spectator mode : a free-fly camera, or the first-person view of a living player.
+//! The clients joining as spectators have no player (see server.rs), the dead players
+//! can also spectate from the death screen until they respawn.
+use bevy::{prelude::*, input::mouse::MouseMotion};
+
+use crate::{
+    player::{Player, PlayerId, PlayerName, CurrentPlayer, PLAYER_SCALE},
+    camera::{LookAngles, MAX_PITCH, camera_height},
+    controls::{ActionInput, Action, MOUSE_RADIANS_PER_DOT},
+    settings::Settings,
+    world::Maze,
+    game::AppState,
+    chat::chat_closed,
+    network::mods::Lobby,
+};
+
+//////////////////////////// PLUGIN, COMPONENTS, CONSTS ///////////////////////////////
+
+pub struct SpectatorPlugin;
+impl Plugin for SpectatorPlugin{
+
+    fn build(&self, app: &mut App) {
+        app.add_systems(OnEnter(AppState::Spectating), spawn_spectator_camera);
+        app.add_systems(OnExit(AppState::Spectating), despawn_spectator_camera);
+        app.add_systems(Update, spectate_while_dead.run_if(in_state(AppState::DeathScreen)).run_if(chat_closed));
+        app.add_systems(Update,(
+            switch_spectator_mode.run_if(chat_closed),
+            move_free_camera.after(switch_spectator_mode).run_if(chat_closed),
+            follow_player.after(switch_spectator_mode),
+            update_spectator_text.after(switch_spectator_mode),
+        ).run_if(in_state(AppState::Spectating)));
+    }
+}
+
+// speed (units per second) of the free camera, multiplied when sprinting
+const FREE_CAMERA_SPEED: f32 = 10.;
+const FREE_CAMERA_SPRINT_MULTIPLIER: f32 = 3.;
+// height of the free camera when a spectator joins
+const SPECTATOR_START_HEIGHT: f32 = 6.;
+
+/// the client joined the server as a spectator, it has no player
+#[derive(Resource)]
+pub struct Spectator;
+
+#[derive(Clone, Copy, PartialEq)]
+enum SpectatorMode{
+    Free,
+    /// first-person view of the player with this id
+    Follow(u64),
+}
+
+#[derive(Component)]
+struct SpectatorCamera{
+    mode: SpectatorMode,
+    /// orientation of the free camera
+    look: LookAngles,
+    /// the followed player's model, hidden because the camera is inside it
+    hidden_player: Option<Entity>,
+    /// the cameras of the current player (if any), disabled while spectating
+    player_cameras: Vec<Entity>,
+}
+
+#[derive(Component)]
+struct SpectatorText;
+
+//////////////////////////// SETUP ///////////////////////////////
+
+fn spectate_while_dead(
+    actions: ActionInput,
+    mut next_state: ResMut<NextState<AppState>>,
+){
+    if actions.just_pressed(Action::Spectate){
+        next_state.set(AppState::Spectating);
+    }
+}
+
+fn spawn_spectator_camera(
+    mut cmd: Commands,
+    mut camera_q: Query<(Entity, &mut Camera, &Parent, &GlobalTransform)>,
+    current_player_q: Query<(Entity, &LookAngles), With<CurrentPlayer>>,
+    maze: Res<Maze>,
+){
+    // a spectator starts above the center of the maze, a dead player where he died
+    let mut transform = Transform::from_xyz(
+        maze.map[0].len() as f32 * maze.tile_size / 2.,
+        SPECTATOR_START_HEIGHT,
+        maze.map.len() as f32 * maze.tile_size / 2.,
+    );
+    let mut look = LookAngles::default();
+    let mut player_cameras = vec![];
+
+    if let Ok((current_player, player_look)) = current_player_q.get_single(){
+        look = *player_look;
+        for (entity, mut camera, parent, global_transform) in camera_q.iter_mut(){
+            if parent.get() != current_player {continue}
+            camera.is_active = false;
+            player_cameras.push(entity);
+            transform.translation = global_transform.translation();
+        }
+    }
+    transform.rotation = look.body_rotation() * look.view_rotation();
+
+    cmd.spawn((
+        Camera3dBundle{
+            transform,
+            camera: Camera{
+                order: isize::MAX,
+                ..default()
+            },
+            ..default()
+        },
+        SpectatorCamera{
+            mode: SpectatorMode::Free,
+            look,
+            hidden_player: None,
+            player_cameras,
+        },
+        Name::new("Spectator Camera")
+    ));
+
+    cmd.spawn((
+        TextBundle::from_section("", TextStyle{ font_size: 25., ..default() })
+            .with_style(Style{
+                position_type: PositionType::Absolute,
+                left: Val::Px(20.),
+                bottom: Val::Px(20.),
+                ..default()
+            }),
+        SpectatorText,
+        Name::new("Spectator Text")
+    ));
+}
+
+fn despawn_spectator_camera(
+    mut cmd: Commands,
+    spectator_q: Query<(Entity, &SpectatorCamera)>,
+    text_q: Query<Entity, With<SpectatorText>>,
+    mut camera_q: Query<&mut Camera>,
+    mut visibility_q: Query<&mut Visibility>,
+){
+    for (entity, spectator) in spectator_q.iter(){
+        for &camera in spectator.player_cameras.iter(){
+            if let Ok(mut camera) = camera_q.get_mut(camera){
+                camera.is_active = true;
+            }
+        }
+        show_player(spectator.hidden_player, &mut visibility_q);
+        cmd.entity(entity).despawn_recursive();
+    }
+
+    for entity in text_q.iter(){
+        cmd.entity(entity).despawn_recursive();
+    }
+}
+
+//////////////////////////// MODES ///////////////////////////////
+
+/// not a bevy system. The living players that can be followed, sorted by id
+fn living_players(player_q: &Query<(&PlayerId, &Player), Without<CurrentPlayer>>) -> Vec<u64>{
+    let mut players: Vec<u64> = player_q.iter()
+        .filter(|(_, player)| player.hp > 0.)
+        .map(|(player_id, _)| player_id.id)
+        .collect();
+    players.sort();
+    players
+}
+
+/// the spectate action switches between the free camera and the follow camera,
+/// fire goes to the next player
+fn switch_spectator_mode(
+    actions: ActionInput,
+    mut spectator_q: Query<&mut SpectatorCamera>,
+    player_q: Query<(&PlayerId, &Player), Without<CurrentPlayer>>,
+){
+    let Ok(mut spectator) = spectator_q.get_single_mut() else {return};
+    let players = living_players(&player_q);
+
+    let next_player = |current: Option<u64>| match current{
+        Some(current) => players.iter().find(|&&id| id > current).or(players.first()).copied(),
+        None => players.first().copied(),
+    };
+
+    spectator.mode = match spectator.mode{
+        SpectatorMode::Free if actions.just_pressed(Action::Spectate) => {
+            next_player(None).map_or(SpectatorMode::Free, SpectatorMode::Follow)
+        }
+        SpectatorMode::Follow(_) if actions.just_pressed(Action::Spectate) => SpectatorMode::Free,
+        // the followed player died or left
+        SpectatorMode::Follow(id) if actions.just_pressed(Action::Fire) || !players.contains(&id) => {
+            next_player(Some(id)).map_or(SpectatorMode::Free, SpectatorMode::Follow)
+        }
+        mode => mode,
+    };
+}
+
+fn move_free_camera(
+    actions: ActionInput,
+    settings: Res<Settings>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    mut spectator_q: Query<(&mut SpectatorCamera, &mut Transform)>,
+    time: Res<Time>,
+){
+    let delta: Vec2 = mouse_motion.iter().map(|motion| motion.delta).sum();
+    let Ok((mut spectator, mut transform)) = spectator_q.get_single_mut() else {return};
+    if spectator.mode != SpectatorMode::Free {return}
+
+    // same mouse settings as the player's view
+    let mut look = -delta * settings.mouse.sensitivity * MOUSE_RADIANS_PER_DOT;
+    if settings.mouse.invert_y {
+        look.y = -look.y;
+    }
+    spectator.look.yaw += look.x;
+    spectator.look.pitch = (spectator.look.pitch + look.y).clamp(-MAX_PITCH, MAX_PITCH);
+    transform.rotation = spectator.look.body_rotation() * spectator.look.view_rotation();
+
+    // fly where the camera looks, jump and crouch go up and down
+    let mut direction = Vec3::ZERO;
+    let axes = [
+        (Action::Forward, transform.forward()),
+        (Action::Backward, transform.back()),
+        (Action::StrafeLeft, transform.left()),
+        (Action::StrafeRight, transform.right()),
+        (Action::Jump, Vec3::Y),
+        (Action::Crouch, Vec3::NEG_Y),
+    ];
+    for (action, axis) in axes{
+        if actions.pressed(action) {direction += axis}
+    }
+
+    let speed = if actions.pressed(Action::Sprint) {FREE_CAMERA_SPEED * FREE_CAMERA_SPRINT_MULTIPLIER} else {FREE_CAMERA_SPEED};
+    transform.translation += direction.normalize_or_zero() * speed * time.delta_seconds();
+}
+
+/// put the camera in the followed player's head
+fn follow_player(
+    mut spectator_q: Query<(&mut SpectatorCamera, &mut Transform)>,
+    player_q: Query<(&Transform, &LookAngles, &Player), Without<SpectatorCamera>>,
+    mut visibility_q: Query<&mut Visibility>,
+    lobby: Res<Lobby>,
+){
+    let Ok((mut spectator, mut transform)) = spectator_q.get_single_mut() else {return};
+
+    let followed = match spectator.mode{
+        SpectatorMode::Follow(id) => lobby.players.get(&id).copied(),
+        SpectatorMode::Free => None,
+    };
+
+    // only the followed player is hidden
+    if spectator.hidden_player != followed{
+        show_player(spectator.hidden_player, &mut visibility_q);
+        if let Some(entity) = followed{
+            if let Ok(mut visibility) = visibility_q.get_mut(entity){
+                *visibility = Visibility::Hidden;
+            }
+        }
+        spectator.hidden_player = followed;
+    }
+
+    let Some(Ok((player_transform, look, player))) = followed.map(|entity| player_q.get(entity)) else {return};
+    transform.translation = player_transform.translation + Vec3::Y * camera_height(player.is_crouching) * PLAYER_SCALE;
+    transform.rotation = look.body_rotation() * look.view_rotation();
+    // the free camera starts from this view
+    spectator.look = *look;
+}
+
+/// not a bevy system
+fn show_player(player: Option<Entity>, visibility_q: &mut Query<&mut Visibility>){
+    let Some(entity) = player else {return};
+    if let Ok(mut visibility) = visibility_q.get_mut(entity){
+        *visibility = Visibility::Inherited;
+    }
+}
+
+fn update_spectator_text(
+    spectator_q: Query<&SpectatorCamera>,
+    mut text_q: Query<&mut Text, With<SpectatorText>>,
+    names_q: Query<&PlayerName>,
+    lobby: Res<Lobby>,
+){
+    let Ok(spectator) = spectator_q.get_single() else {return};
+    let Ok(mut text) = text_q.get_single_mut() else {return};
+
+    text.sections[0].value = match spectator.mode{
+        SpectatorMode::Free => "Free camera".to_string(),
+        SpectatorMode::Follow(id) => {
+            let name = lobby.players.get(&id)
+                .and_then(|&entity| names_q.get(entity).ok())
+                .map_or_else(|| id.to_string(), |PlayerName(name)| name.clone());
+            format!("Spectating {}", name)
+        }
+    };
+}
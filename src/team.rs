@@ -0,0 +1,31 @@
+//! The two sides of a match. The server puts each new player (client or bot) in the smallest team,
+//! the teammates are shown on the minimap, can talk in the team chat and can't hurt each other
+//! when the friendly fire is off (see game.rs).
+use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
+
+/// the side of a player, chosen by the server
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Team{
+    Red,
+    Blue
+}
+
+impl Team{
+    pub fn color(&self) -> Color{
+        match self{
+            Team::Red => Color::rgb(1., 0.35, 0.3),
+            Team::Blue => Color::rgb(0.35, 0.6, 1.),
+        }
+    }
+}
+
+/// not a bevy system. The new players join the smallest team
+pub fn smallest_team<'a>(teams: impl Iterator<Item = &'a Team>) -> Team{
+    let (mut players, mut red_players) = (0, 0);
+    for team in teams{
+        players += 1;
+        if *team == Team::Red {red_players += 1}
+    }
+    if red_players * 2 <= players {Team::Red} else {Team::Blue}
+}
@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
 use std::time::*;
 
 #[derive(Component)]
@@ -6,19 +7,97 @@ pub struct LastShot(pub Instant);
 
 #[derive(Component)]
 pub struct Weapon{
+    pub kind: WeaponKind,
     pub damage:f32,
     pub dispersion: f32,
     pub rate_of_fire: Duration,
     pub last_shot: Instant,
     pub ammos: u8,
+    pub max_reserve_ammos: u16,
     pub fire_mode: FireMode,
     pub reload_duration: f32,
     // recoil
     pub recoil_reset: Duration, // the time it takes for the weapon to reset the recoil
     pub consecutive_shots: usize,
-    pub spray_pattern: Vec<[f32;2]>
+    pub spray_pattern: Vec<[f32;2]>,
 }
 
+/// the samples (paths in the assets) played by a weapon, see sounds.rs
+#[derive(Debug)]
+pub struct WeaponSounds{
+    pub shot: &'static str,
+    /// played instead of the shot when the shooter is far from the listener
+    pub distant_shot: &'static str,
+    /// played with the shot (casing, pump ...)
+    pub tail: &'static str,
+    /// part 1 (magazine out) and part 2 (magazine in, rack) of the reload
+    pub reload: [&'static str; 2],
+    /// reload progress (between 0 and 1) when the part 2 starts
+    pub reload_part2_at: f32,
+}
+
+/// which weapon it is, sent to the clients (sounds ...)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WeaponKind{
+    Pistol,
+    Shotgun,
+    Rifle
+}
+
+impl WeaponKind{
+    pub const ALL: [WeaponKind; 3] = [WeaponKind::Pistol, WeaponKind::Shotgun, WeaponKind::Rifle];
+
+    pub fn sounds(&self) -> &'static WeaponSounds{
+        &WEAPON_SOUNDS[*self as usize]
+    }
+
+    pub fn reload_duration(&self) -> f32{
+        match self{
+            WeaponKind::Pistol => 2.,
+            WeaponKind::Shotgun => 2.,
+            WeaponKind::Rifle => 2.,
+        }
+    }
+}
+
+// in the order of WeaponKind
+// the distant shots and the casings are generated, see assets/sounds/generated/README.md
+const WEAPON_SOUNDS: [WeaponSounds; 3] = [
+    // pistol
+    WeaponSounds{
+        shot: "sounds/gun/Full Sound/.22LR/WAV/22LR Single WAV.wav",
+        distant_shot: "sounds/generated/distant_22lr.wav",
+        tail: "sounds/generated/small_casing_drop.wav",
+        reload: [
+            "sounds/gun/Reloads, Cycling & More/WAV/Semi 22LR Reload Part 1 WAV.wav",
+            "sounds/gun/Reloads, Cycling & More/WAV/Semi 22LR Reload Part 2 WAV.wav",
+        ],
+        reload_part2_at: 0.5,
+    },
+    // shotgun
+    WeaponSounds{
+        shot: "sounds/gun/Full Sound/7.62x54R/WAV/762x54r Single WAV.wav",
+        distant_shot: "sounds/generated/distant_762x54r.wav",
+        tail: "sounds/gun/Reloads, Cycling & More/WAV/Lever Cycle Fast WAV.wav",
+        reload: [
+            "sounds/gun/Reloads, Cycling & More/WAV/Pump Reload Part 1 WAV.wav",
+            "sounds/gun/Reloads, Cycling & More/WAV/Pump Reload Part 2 WAV.wav",
+        ],
+        reload_part2_at: 0.7,
+    },
+    // rifle
+    WeaponSounds{
+        shot: "sounds/gun/Full Sound/7.62x39/WAV/762x39 Single WAV.wav",
+        distant_shot: "sounds/generated/distant_762x39.wav",
+        tail: "sounds/generated/casing_drop.wav",
+        reload: [
+            "sounds/gun/Reloads, Cycling & More/WAV/AK Reload Part 1 WAV.wav",
+            "sounds/gun/Reloads, Cycling & More/WAV/AK Reload Part 2 WAV.wav",
+        ],
+        reload_part2_at: 0.6,
+    },
+];
+
 #[allow(dead_code)]
 pub enum FireMode{
     SemiAuto,
@@ -34,46 +113,52 @@ impl Weapon{
     
     pub fn pistol() -> Self{
         Self{
+            kind: WeaponKind::Pistol,
             damage: 150.,
             dispersion: 1.,
             rate_of_fire: Duration::from_millis(500),
             ammos: 12,
+            max_reserve_ammos: 36,
             fire_mode: FireMode::SemiAuto,
-            reload_duration: 2.,
+            reload_duration: WeaponKind::Pistol.reload_duration(),
             recoil_reset: Duration::from_millis(800),
             last_shot: Instant::now(),
             consecutive_shots: 0,
-            spray_pattern: simple_spray_pattern()
+            spray_pattern: simple_spray_pattern(),
         }
     }
 
     pub fn shotgun() -> Self{
         Self{
+            kind: WeaponKind::Shotgun,
             damage: 80.,
             dispersion: 10.,
             rate_of_fire: Duration::from_millis(1200),
             ammos: 7,
+            max_reserve_ammos: 28,
             fire_mode: FireMode::SemiAuto,
-            reload_duration: 2.,
+            reload_duration: WeaponKind::Shotgun.reload_duration(),
             recoil_reset: Duration::from_millis(800),
             last_shot: Instant::now(),
             consecutive_shots: 0,
-            spray_pattern: simple_spray_pattern()
+            spray_pattern: simple_spray_pattern(),
         }
     }
 
     pub fn rifle() -> Self{
         Self{
+            kind: WeaponKind::Rifle,
             damage: 33.,
             dispersion: 3.,
             rate_of_fire: Duration::from_millis(100),
             ammos: 30,
+            max_reserve_ammos: 90,
             fire_mode: FireMode::Auto,
-            reload_duration: 2.,
+            reload_duration: WeaponKind::Rifle.reload_duration(),
             recoil_reset: Duration::from_millis(800),
             last_shot: Instant::now(),
             consecutive_shots: 0,
-            spray_pattern: simple_spray_pattern()
+            spray_pattern: simple_spray_pattern(),
         }
     }
 }
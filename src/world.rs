@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
+use serde::{Serialize, Deserialize};
 
 use crate::weapons::WeaponAsset;
 
@@ -10,15 +11,54 @@ impl Plugin for WorldPlugin{
         app.init_resource::<Maze>();
         app.add_systems(Startup,( 
             spawn_world,
-            spawn_maze,
             set_light
         ));
+        // the server can change the map during the game (see network/console.rs)
+        app.add_systems(Update, spawn_maze.run_if(resource_changed::<Maze>()));
     }
 }
 
-#[derive(Resource)]
+// tiles of the maze map
+pub const PATH_TILE: i32 = 0;
+pub const WALL_TILE: i32 = 1;
+pub const AMMO_TILE: i32 = 2;
+pub const HEALTH_TILE: i32 = 3;
+pub const ARMOR_TILE: i32 = 4;
+
+/// the maps other than the default one, `<name>.ron` files
+const MAPS_DIRECTORY: &str = "assets/maps";
+pub const DEFAULT_MAP: &str = "default";
+
+/// what the ground of a tile is made of, changes the footsteps sound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Surface{
+    Grass,
+    Stone,
+    Wood
+}
+
+impl Surface{
+    pub const ALL: [Surface; 3] = [Surface::Grass, Surface::Stone, Surface::Wood];
+
+    pub fn from_char(c: char) -> Self{
+        match c{
+            's' => Surface::Stone,
+            'w' => Surface::Wood,
+            _ => Surface::Grass
+        }
+    }
+}
+
+/// parent of the walls of the maze, replaced when the map changes
+#[derive(Component)]
+pub struct MazeWalls;
+
+#[derive(Resource, Clone)]
 pub struct Maze{
+    pub name: String,
     pub map: Vec<Vec<i32>>,
+    /// surface of each tile, same size as the map
+    pub surfaces: Vec<Vec<Surface>>,
     pub tile_size: f32,
     pub wall_height: f32,
 }
@@ -29,23 +69,204 @@ impl Default for Maze{
         let wall_height = 4.;
         let map = vec![
             vec![1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],
-            vec![0,0,1,0,0,0,0,0,0,0,1,0,0,0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1],
+            vec![0,0,1,0,0,0,0,0,0,2,1,0,0,0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1],
             vec![1,0,1,0,1,0,1,0,1,0,1,0,1,1,0,1,0,1,1,0,1,1,1,0,1,1,1,1,0,1,0,1],
-            vec![1,0,0,0,1,0,1,0,1,0,1,0,1,0,0,1,0,1,0,0,0,1,0,0,0,1,0,0,0,1,0,1],
+            vec![1,0,0,0,1,0,1,0,1,0,1,0,1,3,0,1,0,1,0,0,0,1,0,0,0,1,0,0,0,1,0,1],
             vec![1,0,1,0,1,0,0,0,1,0,0,0,1,1,0,0,0,0,0,1,0,0,0,1,0,0,0,1,0,1,0,1],
             vec![1,0,1,0,1,1,1,1,1,1,1,1,1,1,0,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,0,1],
-            vec![1,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1],
+            vec![1,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,3,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1],
             vec![1,0,1,1,1,1,1,1,1,1,0,1,1,1,0,1,0,1,1,1,1,1,1,1,1,1,1,1,0,1,1,1],
-            vec![1,0,0,0,0,0,0,1,0,0,0,0,0,0,0,1,0,0,0,0,0,1,0,0,0,0,0,0,0,0,0,1],
+            vec![1,0,0,0,0,0,0,1,0,0,0,0,0,0,0,1,0,0,0,0,0,1,0,4,0,0,0,0,0,0,0,1],
             vec![1,0,1,1,0,1,1,1,0,1,1,1,1,1,0,1,0,1,1,1,0,1,0,1,1,1,1,1,1,1,0,1],
-            vec![1,0,1,0,0,0,0,0,0,1,0,0,0,1,0,1,0,0,0,0,0,1,0,0,0,0,0,0,0,0,0,1],
+            vec![1,0,1,0,0,0,0,0,0,1,0,0,0,1,0,1,2,0,0,0,0,1,0,0,0,0,0,0,0,0,0,1],
             vec![1,0,1,0,1,1,0,1,0,1,0,1,0,1,0,1,1,1,1,1,0,1,1,1,1,1,1,0,1,1,1,1],
-            vec![1,0,1,0,0,1,0,1,0,1,0,1,0,1,0,1,0,0,0,1,0,1,0,0,0,0,0,0,0,0,0,1],
+            vec![1,0,1,3,0,1,0,1,0,1,0,1,0,1,0,1,0,4,0,1,0,1,0,0,0,0,0,0,0,0,0,1],
             vec![1,0,1,0,1,1,0,1,0,1,0,1,0,1,0,1,0,1,0,1,0,1,0,1,1,1,1,1,1,1,0,1],
-            vec![1,0,0,0,0,0,0,1,0,0,0,0,0,0,0,0,0,1,0,0,0,0,0,0,0,0,0,0,0,0,0,1],
+            vec![1,0,0,0,0,0,0,1,0,0,0,0,0,0,0,0,0,1,0,0,0,0,0,0,0,2,0,0,0,0,0,1],
             vec![1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],        
         ];
-        Self{map, tile_size, wall_height}
+        // surface of each tile : g grass, s stone, w wood
+        let surfaces = [
+            "gggggggggggggggggggggggggggggggg",
+            "gggggggggggggggggggggggggggggggg",
+            "gggggggggggggggggggggggggggggggg",
+            "gggggggggggggggggggggggggggggggg",
+            "gggggggggggggggggggggggggggggggg",
+            "ggggggssssssssssssssssssssgggggg",
+            "ggggggssssssssssssssssssssgggggg",
+            "ggggggssssssssssssssssssssgggggg",
+            "ggggggssssssssssssssssssssgggggg",
+            "ggggggssssssssssssssssssssgggggg",
+            "ggggggssssssssssssssssssssgggggg",
+            "wwwwwwwwwwwwwwwwssssssssssssssss",
+            "wwwwwwwwwwwwwwwwssssssssssssssss",
+            "wwwwwwwwwwwwwwwwssssssssssssssss",
+            "wwwwwwwwwwwwwwwwssssssssssssssss",
+            "wwwwwwwwwwwwwwwwssssssssssssssss",
+        ].iter()
+            .map(|line| line.chars().map(Surface::from_char).collect())
+            .collect();
+        Self{name: DEFAULT_MAP.to_string(), map, surfaces, tile_size, wall_height}
+    }
+}
+
+/// a map file : one string per line of the maze, with the same tiles and surfaces as the default map
+#[derive(Deserialize)]
+struct MapFile{
+    tiles: Vec<String>,
+    /// grass everywhere if missing
+    #[serde(default)]
+    surfaces: Vec<String>,
+}
+
+impl Maze{
+    /// a maze with the default tile size and wall height
+    pub fn new(name: &str, map: Vec<Vec<i32>>, surfaces: Vec<Vec<Surface>>) -> Self{
+        Self{ name: name.to_string(), map, surfaces, ..default() }
+    }
+
+    /// the default map, or `assets/maps/<name>.ron`
+    pub fn load(name: &str) -> Result<Self, String>{
+        if name == DEFAULT_MAP {return Ok(Self::default())}
+        // the name is a file name, not a path
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'){
+            return Err(format!("invalid map name: {}", name));
+        }
+
+        let path = format!("{}/{}.ron", MAPS_DIRECTORY, name);
+        let content = std::fs::read_to_string(&path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        Self::from_ron(name, &content).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// the content of a map file
+    fn from_ron(name: &str, content: &str) -> Result<Self, String>{
+        let file: MapFile = ron::from_str(content).map_err(|e| format!("cannot parse: {}", e))?;
+
+        let tile = |c: char| match c.to_digit(10){
+            Some(tile) if tile as i32 <= ARMOR_TILE => Ok(tile as i32),
+            _ => Err(format!("unknown tile '{}' (0 path, 1 wall, 2 ammo, 3 health, 4 armor)", c)),
+        };
+        let map = file.tiles.iter()
+            .map(|line| line.chars().map(tile).collect::<Result<Vec<i32>, String>>())
+            .collect::<Result<Vec<Vec<i32>>, String>>()?;
+        Self::check_map(&map)?;
+
+        let surfaces = file.surfaces.iter()
+            .map(|line| line.chars().map(Surface::from_char).collect())
+            .collect();
+        Ok(Self::new(name, map, surfaces))
+    }
+
+    /// a map which is not read from a map file (demo header ...) must pass the same checks
+    pub fn check_map(map: &[Vec<i32>]) -> Result<(), String>{
+        // the players spawn in the corners, inside the border walls
+        if map.len() < 3 || map.iter().any(|line| line.len() != map[0].len() || line.len() < 3){
+            return Err("the lines of the map must have the same length, at least 3".to_string());
+        }
+        if let Some(tile) = map.iter().flatten().find(|tile| !(0..=ARMOR_TILE).contains(*tile)){
+            return Err(format!("unknown tile {} (0 path, 1 wall, 2 ammo, 3 health, 4 armor)", tile));
+        }
+        Ok(())
+    }
+
+    /// world position of the center of a tile (on the ground)
+    pub fn tile_to_world(&self, row: usize, col: usize) -> Vec3{
+        Vec3::new(col as f32 * self.tile_size, 0., row as f32 * self.tile_size)
+    }
+
+    /// tile (row, col) containing a world position, None if outside of the maze
+    pub fn world_to_tile(&self, position: Vec3) -> Option<(usize, usize)>{
+        let row = (position.z / self.tile_size).round();
+        let col = (position.x / self.tile_size).round();
+        if row < 0. || col < 0. {return None}
+
+        let (row, col) = (row as usize, col as usize);
+        if row >= self.map.len() || col >= self.map[row].len() {return None}
+        Some((row, col))
+    }
+
+    /// the tile of a world position if it is open, else the closest open tile around it.
+    /// A position close to a wall can round onto the wall tile. None if outside of the maze
+    pub fn nearest_walkable_tile(&self, position: Vec3) -> Option<(usize, usize)>{
+        let (row, col) = self.world_to_tile(position)?;
+        if !self.is_wall(row as i32, col as i32) {return Some((row, col))}
+
+        let mut neighbours = vec![];
+        for d_row in -1..=1{
+            for d_col in -1..=1{
+                let (next_row, next_col) = (row as i32 + d_row, col as i32 + d_col);
+                if self.is_wall(next_row, next_col) {continue}
+                neighbours.push((next_row as usize, next_col as usize));
+            }
+        }
+        let distance = |&(row, col): &(usize, usize)| self.tile_to_world(row, col).distance(position * Vec3::new(1., 0., 1.));
+        neighbours.into_iter().min_by(|a, b| distance(a).total_cmp(&distance(b)))
+    }
+
+    /// true if no wall is between the two positions (walk through the tiles of the segment)
+    pub fn line_of_sight(&self, from: Vec3, to: Vec3) -> bool{
+        // position in tiles, tile (row, col) covers [col, col+1[ x [row, row+1[
+        let to_grid = |position: Vec3| Vec2::new(position.x, position.z) / self.tile_size + 0.5;
+        let start = to_grid(from);
+        let end = to_grid(to);
+        let direction = end - start;
+
+        let mut tile = start.floor().as_ivec2();
+        let end_tile = end.floor().as_ivec2();
+        let step = IVec2::new(direction.x.signum() as i32, direction.y.signum() as i32);
+
+        // distance (fraction of the segment) to cross a whole tile, and to reach the next tile border
+        let t_delta = Vec2::new(1. / direction.x.abs(), 1. / direction.y.abs());
+        let first_border = |start: f32, tile: i32, direction: f32| match direction{
+            d if d > 0. => (tile as f32 + 1. - start) / d,
+            d if d < 0. => (start - tile as f32) / -d,
+            _ => f32::INFINITY
+        };
+        let mut t_max = Vec2::new(
+            first_border(start.x, tile.x, direction.x),
+            first_border(start.y, tile.y, direction.y)
+        );
+
+        let max_steps = (end_tile.x - tile.x).abs() + (end_tile.y - tile.y).abs() + 1;
+        for _ in 0..=max_steps{
+            if self.is_wall(tile.y, tile.x) {return false}
+            if tile == end_tile {return true}
+
+            if t_max.x < t_max.y{
+                tile.x += step.x;
+                t_max.x += t_delta.x;
+            }else{
+                tile.y += step.y;
+                t_max.y += t_delta.y;
+            }
+        }
+        true
+    }
+
+    /// the tiles outside of the maze are walls
+    pub fn is_wall(&self, row: i32, col: i32) -> bool{
+        if row < 0 || col < 0 {return true}
+        !matches!(self.map.get(row as usize).and_then(|line| line.get(col as usize)), Some(tile) if *tile != WALL_TILE)
+    }
+
+    /// surface of the ground at a world position, grass outside of the maze
+    pub fn surface_at(&self, position: Vec3) -> Surface{
+        self.world_to_tile(position)
+            .and_then(|(row, col)| self.surfaces.get(row)?.get(col).copied())
+            .unwrap_or(Surface::Grass)
+    }
+
+    /// every tile where nothing is placed yet
+    pub fn free_tiles(&self) -> Vec<(usize, usize)>{
+        let mut tiles = vec![];
+        for (row, line) in self.map.iter().enumerate(){
+            for (col, tile) in line.iter().enumerate(){
+                if *tile == PATH_TILE{
+                    tiles.push((row, col));
+                }
+            }
+        }
+        tiles
     }
 }
 
@@ -157,14 +378,26 @@ fn spawn_maze(
     mut cmd: Commands,
     maze: Res<Maze>,
     assets: Res<AssetServer>,
+    walls_q: Query<Entity, With<MazeWalls>>,
 
     mut meshes : ResMut<Assets<Mesh>>,
     mut materials : ResMut<Assets<StandardMaterial>> 
 ){
+    // remove the walls of the previous map
+    for wall in walls_q.iter(){
+        cmd.entity(wall).despawn_recursive();
+    }
+
     let tile_size = maze.tile_size;
     let wall_height = maze.wall_height;
     let maze_map = maze.map.clone();
 
+    let mut walls = cmd.spawn((
+        Name::new("Maze"),
+        MazeWalls,
+        SpatialBundle::default(),
+    ));
+
     // wall factory
     let mut create_wall = |x:f32, z:f32| {
         let wall = shape::Box::new(tile_size, wall_height, tile_size);
@@ -174,7 +407,7 @@ fn spawn_maze(
         });
 
 
-        cmd.spawn((
+        walls.with_children(|walls| {walls.spawn((
             Name::new("Wall"),
             PbrBundle{
                 mesh: meshes.add(wall.into()),
@@ -185,7 +418,7 @@ fn spawn_maze(
             },
             RigidBody::Fixed,
             Collider::cuboid(tile_size/2., wall_height/2., tile_size/2.),
-        ));
+        ));});
     };
 
     // generate the maze
@@ -194,7 +427,7 @@ fn spawn_maze(
 
     for line in maze_map.iter(){
         for tile in line.iter(){
-            if *tile == 1{
+            if *tile == WALL_TILE{
                 create_wall(coord_x, coord_z);
             }
             coord_x += tile_size;
@@ -205,4 +438,38 @@ fn spawn_maze(
 
      
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn load_map_file(){
+        let maze = Maze::from_ron("test", r#"(tiles: ["111", "123", "141"], surfaces: ["ggg", "gsw", "ggg"])"#).unwrap();
+        assert_eq!(maze.map, vec![vec![1, 1, 1], vec![1, AMMO_TILE, HEALTH_TILE], vec![1, ARMOR_TILE, 1]]);
+        assert_eq!(maze.surfaces[1], vec![Surface::Grass, Surface::Stone, Surface::Wood]);
+    }
+
+    #[test]
+    fn reject_unknown_tiles(){
+        for tile in ['5', '9', '#', 'x']{
+            let content = format!(r#"(tiles: ["111", "1{}1", "111"])"#, tile);
+            assert!(Maze::from_ron("test", &content).is_err(), "tile {} accepted", tile);
+        }
+    }
+
+    #[test]
+    fn reject_uneven_lines(){
+        assert!(Maze::from_ron("test", r#"(tiles: ["111", "10", "111"])"#).is_err());
+        assert!(Maze::from_ron("test", r#"(tiles: ["111", "101"])"#).is_err());
+    }
+
+    #[test]
+    fn check_decoded_maps(){
+        assert!(Maze::check_map(&Maze::default().map).is_ok());
+        assert!(Maze::check_map(&[]).is_err());
+        assert!(Maze::check_map(&[vec![1, 1, 1], vec![1, 0], vec![1, 1, 1]]).is_err());
+        assert!(Maze::check_map(&[vec![1, 1, 1], vec![1, -1, 1], vec![1, 1, 1]]).is_err());
+    }
+}
@@ -0,0 +1,39 @@
+import wave, struct, math, random
+RATE = 44100
+
+def write(path, samples):
+    peak = max(1e-9, max(abs(s) for s in samples))
+    scale = 0.8 / peak
+    with wave.open(path, 'wb') as w:
+        w.setnchannels(2); w.setsampwidth(2); w.setframerate(RATE)
+        frames = bytearray()
+        for s in samples:
+            v = int(max(-1, min(1, s * scale)) * 32767)
+            frames += struct.pack('<hh', v, v)
+        w.writeframes(bytes(frames))
+
+def read(path):
+    with wave.open(path, 'rb') as w:
+        ch, width, rate, n = w.getnchannels(), w.getsampwidth(), w.getframerate(), w.getnframes()
+        data = w.readframes(n)
+    assert width == 2, (path, width)
+    vals = struct.unpack('<%dh' % (len(data)//2), data)
+    mono = [sum(vals[i:i+ch]) / ch / 32768 for i in range(0, len(vals), ch)]
+    return mono, rate
+
+class Biquad:
+    def __init__(self, kind, f, q):
+        w0 = 2*math.pi*f/RATE; a = math.sin(w0)/(2*q); c = math.cos(w0)
+        if kind == 'bp':
+            b0, b1, b2 = a, 0, -a
+        elif kind == 'lp':
+            b0, b1, b2 = (1-c)/2, 1-c, (1-c)/2
+        elif kind == 'hp':
+            b0, b1, b2 = (1+c)/2, -(1+c), (1+c)/2
+        a0, a1, a2 = 1+a, -2*c, 1-a
+        self.b = (b0/a0, b1/a0, b2/a0); self.a = (a1/a0, a2/a0)
+        self.x1 = self.x2 = self.y1 = self.y2 = 0.
+    def __call__(self, x):
+        y = self.b[0]*x + self.b[1]*self.x1 + self.b[2]*self.x2 - self.a[0]*self.y1 - self.a[1]*self.y2
+        self.x2, self.x1, self.y2, self.y1 = self.x1, x, self.y1, y
+        return y
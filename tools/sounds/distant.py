@@ -0,0 +1,20 @@
+import os, sys; sys.path.insert(0, os.path.dirname(__file__))
+from common import *
+src, dst = sys.argv[1], sys.argv[2]
+dry, rate = read(src)
+assert rate == RATE
+# far away : the highs are absorbed, the attack is softened and the maze echoes the shot
+lp1, lp2, hp = Biquad('lp', 900, 0.7), Biquad('lp', 900, 0.7), Biquad('hp', 60, 0.7)
+muffled = [hp(lp2(lp1(x))) for x in dry]
+echoes = [(0., 1.), (0.11, 0.45), (0.24, 0.3), (0.41, 0.18), (0.63, 0.1)]
+n = len(muffled) + int(0.7 * RATE)
+out = [0.] * n
+for delay, gain in echoes:
+    d = int(delay * RATE)
+    for i, x in enumerate(muffled):
+        out[i + d] += x * gain
+# fade out the end
+fade = int(0.3 * RATE)
+for i in range(fade):
+    out[n - fade + i] *= 1 - i / fade
+write(dst, [x * 0.5 for x in out])
@@ -0,0 +1,14 @@
+import os, sys; sys.path.insert(0, os.path.dirname(__file__))
+from common import *
+# a brass casing hitting the ground and bouncing once
+random.seed(4)
+n = int(0.35 * RATE); out = [0.] * n
+partials = [(4700, 1.), (7300, 0.5), (11200, 0.25)]
+for start, gain in [(0., 1.), (0.09, 0.45), (0.16, 0.2)]:
+    s = int(start * RATE)
+    for i in range(s, n):
+        t = (i - s) / RATE
+        env = math.exp(-t * 55) * gain
+        click = random.uniform(-1, 1) * math.exp(-t * 900) * gain
+        out[i] += click + env * sum(g * math.sin(2*math.pi*f*t) for f, g in partials)
+write(sys.argv[1], [x * 0.35 for x in out])
@@ -0,0 +1,22 @@
+import os, sys; sys.path.insert(0, os.path.dirname(__file__))
+from common import *
+
+# a sawtooth (with some breath) through formant filters, its pitch falls
+def voice(duration, f_start, f_end, formants, attack, breath, seed):
+    random.seed(seed)
+    filters = [(Biquad('bp', f, q), g) for f, q, g in formants]
+    n = int(duration * RATE); phase = 0.; out = []
+    for i in range(n):
+        t = i / n
+        f0 = f_start * (f_end / f_start) ** t * (1 + 0.02 * math.sin(2*math.pi*5.5*i/RATE))
+        phase = (phase + f0 / RATE) % 1.
+        source = (2*phase - 1) * (1 - breath) + random.uniform(-1, 1) * breath
+        env = min(1., i / (attack * RATE)) * (1 - t) ** 1.5
+        out.append(sum(f(source) * g for f, g in filters) * env)
+    return out
+
+# "uh" formants for a short pain grunt, "ah" falling for the death cry
+hit = voice(0.28, 210, 150, [(650, 6, 1.), (1100, 8, 0.6), (2500, 10, 0.2)], 0.01, 0.25, 1)
+death = voice(0.9, 190, 85, [(750, 5, 1.), (1200, 7, 0.5), (2600, 10, 0.15)], 0.03, 0.35, 2)
+write(sys.argv[1] + '/hit.wav', hit)
+write(sys.argv[1] + '/death.wav', death)